@@ -0,0 +1,1007 @@
+use std::{
+    fs::File,
+    future::pending,
+    io::{self, BufReader, Write},
+    net::IpAddr,
+    pin::Pin,
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::{
+    signal,
+    task::{self, JoinSet},
+    time::{interval_at, sleep_until, Instant, Interval, MissedTickBehavior},
+};
+use tokio_stream::{Stream, StreamExt, StreamMap};
+
+use crate::{
+    cli::{CliArgs, Command, TraceArgs},
+    dscp,
+    error::PingError,
+    ip::{self, AddressFamily, BindTarget},
+    pinger::{Flow, PingConfig, PingEvent, PingHandle, PingReply, Pinger},
+    pmtu::{self, PmtuProbe},
+    rdns::{ReverseResolver, LOOKUP_TIMEOUT},
+    session::Session,
+    stats::PingStatistics,
+    sweep::{self, AddressRange, HostState, SweepConfig},
+    targets::{self, HostEntry},
+    trace::{self, Hop, HopProbe, HopStats, TraceConfig},
+    transport::{ProbeProtocol, TimestampSource},
+};
+
+/// The destination with its zone, if it has one.
+fn describe_destination(destination: IpAddr, scope_id: u32) -> String {
+    match scope_id {
+        0 => destination.to_string(),
+        scope_id => format!("{}%{}", destination, ip::scope_name(scope_id)),
+    }
+}
+
+/// The source address and, when it's one of ours, the interface it belongs to.
+fn describe_source(source: IpAddr, scope_id: u32) -> String {
+    match ip::interface_of(source) {
+        Some(interface) => format!("{} ({})", source, interface),
+        // link-local sources belong to the interface of the destination's zone
+        None if scope_id != 0 => format!("{} ({})", source, ip::scope_name(scope_id)),
+        None => source.to_string(),
+    }
+}
+
+/// Longest wait between flood probes when no reply comes sooner.
+const FLOOD_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How probes are sent, when they aren't plain echo requests.
+fn describe_protocol(protocol: ProbeProtocol) -> String {
+    match protocol {
+        ProbeProtocol::Icmp => String::new(),
+        protocol => format!(" with {}", protocol),
+    }
+}
+
+/// Runs the `ring` command line against `session`, writing its output to `out`.
+pub async fn run(
+    args: CliArgs,
+    session: Arc<Session>,
+    out: &mut (dyn Write + Send),
+) -> Result<PingStatistics, PingError> {
+    let family = if args.ipv4 {
+        AddressFamily::V4
+    } else if args.ipv6 {
+        AddressFamily::V6
+    } else {
+        AddressFamily::Any
+    };
+    if let Some(Command::Trace(trace)) = &args.command {
+        return run_trace(trace, session, out).await;
+    }
+    if let Some(range) = args.generate {
+        return run_sweep(&args, range, session, out).await;
+    }
+
+    // each host's addresses, pinged at once, with the targets they report into
+    let hosts = collect_hosts(&args, out)?;
+    let mut targets = Vec::new();
+    let mut configs = Vec::new();
+    // every name is looked up at once, off the runtime's threads
+    let lookups = hosts
+        .iter()
+        .map(|(entry, _)| {
+            let resolver = session.resolver();
+            let name = entry.name.clone();
+            task::spawn_blocking(move || ip::resolve_scoped_host(&*resolver, &name, family))
+        })
+        .collect::<Vec<_>>();
+    for ((host, (entry, origin)), lookup) in hosts.iter().enumerate().zip(lookups) {
+        let resolved = lookup.await.map_err(|e| PingError::new(e.to_string()))?;
+        let mut destinations = match (resolved, origin) {
+            (Ok(destinations), _) => destinations,
+            // a bad line in a targets file shouldn't keep the others from running
+            (Err(e), Some(origin)) => {
+                let _ = writeln!(out, "{}: couldn't resolve {}: {}", origin, entry.name, e);
+                continue;
+            }
+            (Err(e), None) => {
+                return Err(PingError::new(format!("Couldn't resolve host: {}", e)));
+            }
+        };
+        if !args.all_addresses {
+            destinations.truncate(1);
+        }
+        for (destination, scope_id) in destinations {
+            configs.push((
+                targets.len(),
+                build_config(&args, entry, destination, scope_id)?,
+            ));
+            targets.push(Target::new(host, entry, destination, args.tos));
+        }
+    }
+    if configs.is_empty() {
+        return Err(PingError::new("No hosts to ping".to_string()));
+    }
+
+    if args.pmtu {
+        if configs.len() > 1 {
+            return Err(PingError::new(
+                "Path MTU discovery takes a single host".to_string(),
+            ));
+        }
+        return run_pmtu(configs.remove(0).1, session, out).await;
+    }
+
+    // names would be looked up once per reply, throttling a flood to DNS speed
+    let resolver = (!args.numeric && !args.flood)
+        .then(|| ReverseResolver::new(session.resolver(), LOOKUP_TIMEOUT));
+    let mut runs = Runs::default();
+    for (target, config) in configs {
+        let pinger = start_pinger(out, config, &session)?;
+        runs.add(pinger, target, targets[target].host);
+    }
+
+    // only names can move to another address
+    let reresolve = args.reresolve.map(Duration::from_secs);
+    let mut reresolve_timer = reresolve.map(|period| {
+        let mut timer = interval_at(Instant::now() + period, period);
+        timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        timer
+    });
+    // re-resolutions running off the runtime's threads, each for one host
+    let mut lookups = JoinSet::new();
+
+    let ctrl_c = signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+    let mut interrupted = false;
+    loop {
+        tokio::select! {
+            event = runs.events.next() => match event {
+                Some((run, event)) => {
+                    let target = &mut targets[runs.targets[run]];
+                    target.stats.update(&event);
+                    // a name still on its way shows up on later replies, not holding this one up
+                    let from = match (event_source(&event), &resolver) {
+                        (Some(source), Some(resolver)) => resolver.describe_now(source),
+                        (Some(source), None) => source.to_string(),
+                        (None, _) => String::new(),
+                    };
+                    if args.flood {
+                        print_flood_event(out, &event);
+                    } else {
+                        print_event(out, &event, &from, args.audio, args.tos.is_some());
+                    }
+                    if let PingEvent::Reply(reply) = &event {
+                        // route change warnings would break up the dots
+                        let warnings: &mut (dyn Write + Send) =
+                            if args.flood { &mut io::sink() } else { out };
+                        target.replies.observe(warnings, reply);
+                    }
+                }
+                None => break,
+            },
+            // a slow resolver delays the next round rather than piling lookups up
+            _ = tick(&mut reresolve_timer), if !interrupted && lookups.is_empty() => {
+                for (host, (entry, _)) in hosts.iter().enumerate() {
+                    let name = entry.name.clone();
+                    if name.parse::<IpAddr>().is_ok() || name.contains('%') {
+                        continue;
+                    }
+                    let resolver = session.resolver();
+                    lookups.spawn_blocking(move || {
+                        (host, ip::resolve_scoped_host(&*resolver, &name, family))
+                    });
+                }
+            }
+            Some(lookup) = lookups.join_next() => {
+                let (host, resolved) = lookup.map_err(|e| PingError::new(e.to_string()))?;
+                let (entry, _) = &hosts[host];
+                let name = &entry.name;
+                // the latest run for a host is the one still sending
+                let Some(current) = runs.latest(host) else {
+                    continue;
+                };
+                let destination = runs.destination(current);
+                let (next, scope_id) = match resolved {
+                    Ok(addresses) if !addresses.iter().any(|(address, _)| *address == destination) => {
+                        addresses[0]
+                    }
+                    Ok(_) => continue,
+                    Err(e) => {
+                        let _ = writeln!(out, "Warning: couldn't re-resolve {}: {}", name, e);
+                        continue;
+                    }
+                };
+                if interrupted || !runs.is_running(current) {
+                    continue;
+                }
+                runs.finish(current);
+                // the new address gets whatever is left of -c
+                let remaining = args
+                    .count
+                    .map(|count| (count as u32).saturating_sub(runs.sent_to(host)) as u16);
+                if remaining == Some(0) {
+                    continue;
+                }
+                let _ = writeln!(
+                    out,
+                    "{} now resolves to {} instead of {}, switching",
+                    name, next, destination
+                );
+                let mut config = build_config(&args, entry, next, scope_id)?;
+                config.count = remaining;
+                let pinger = start_pinger(out, config, &session)?;
+                let target = match targets
+                    .iter()
+                    .position(|target| target.host == host && target.destination == next)
+                {
+                    Some(target) => target,
+                    None => {
+                        targets.push(Target::new(host, entry, next, args.tos));
+                        targets.len() - 1
+                    }
+                };
+                runs.add(pinger, target, host);
+            }
+            _ = &mut ctrl_c, if !interrupted => {
+                interrupted = true;
+                runs.stop_all();
+                let _ = writeln!(out, "\nInterrupted. Finishing current pings and collecting stats...");
+            }
+        }
+    }
+
+    if args.flood && !interrupted {
+        let _ = writeln!(out);
+    }
+    let mut total = PingStatistics::new();
+    for target in &targets {
+        if targets.len() > 1 {
+            let _ = writeln!(out, "--- {} ---", target.label);
+        }
+        target.replies.print_summary(out);
+        print_statistics(out, &target.stats);
+        total.merge(&target.stats);
+    }
+    if targets.len() > 1 {
+        let _ = writeln!(out, "--- all {} targets ---", targets.len());
+        print_statistics(out, &total);
+    }
+    Ok(total)
+}
+
+/// The hosts on the command line, then those read from `-F` or `-`, each with
+/// the file and line it came from. Lines that don't parse are reported and skipped.
+fn collect_hosts(
+    args: &CliArgs,
+    out: &mut (dyn Write + Send),
+) -> Result<Vec<(HostEntry, Option<String>)>, PingError> {
+    let mut hosts = Vec::new();
+    let files = args.file.iter().map(|file| (file, true));
+    for (file, is_file) in args
+        .hosts
+        .iter()
+        .map(|host| (host, host == "-"))
+        .chain(files)
+    {
+        if !is_file {
+            hosts.push((HostEntry::new(file), None));
+            continue;
+        }
+        let (name, entries) = if file == "-" {
+            ("stdin", targets::read_targets(io::stdin().lock()))
+        } else {
+            let entries = File::open(file).and_then(|f| targets::read_targets(BufReader::new(f)));
+            (file.as_str(), entries)
+        };
+        let entries =
+            entries.map_err(|e| PingError::new(format!("Couldn't read {}: {}", name, e)))?;
+        for (line, entry) in entries {
+            let origin = format!("{}:{}", name, line);
+            match entry {
+                Ok(entry) => hosts.push((entry, Some(origin))),
+                Err(e) => {
+                    let _ = writeln!(out, "{}: {}", origin, e);
+                }
+            }
+        }
+    }
+    Ok(hosts)
+}
+
+/// Waits for the next tick of `timer`, or forever without one.
+async fn tick(timer: &mut Option<Interval>) {
+    match timer {
+        Some(timer) => {
+            timer.tick().await;
+        }
+        None => pending().await,
+    }
+}
+
+/// Creates a pinger for `config` and announces it.
+fn start_pinger(
+    out: &mut (dyn Write + Send),
+    config: PingConfig,
+    session: &Arc<Session>,
+) -> Result<Pinger, PingError> {
+    let scope_id = config.scope_id;
+    let pinger = Pinger::with_session(config, session.clone())?;
+    let _ = writeln!(
+        out,
+        "Ringing {} from {}{}",
+        describe_destination(pinger.destination(), scope_id),
+        describe_source(pinger.source(), scope_id),
+        describe_protocol(session.protocol())
+    );
+    Ok(pinger)
+}
+
+/// One of a host's addresses, with its statistics and what its replies said
+/// about the path. Probes to the same address count together even across
+/// several pingers.
+struct Target {
+    // index of the host among those given on the command line or in files
+    host: usize,
+    label: String,
+    destination: IpAddr,
+    stats: PingStatistics,
+    replies: ReplyTracker,
+}
+
+impl Target {
+    fn new(host: usize, entry: &HostEntry, destination: IpAddr, sent_tos: Option<u8>) -> Target {
+        // names are worth repeating next to the address, literals aren't
+        let name = &entry.name;
+        let label = match (&entry.label, name.parse::<IpAddr>()) {
+            (Some(label), _) => format!("{} ({})", label, destination),
+            (None, Ok(_)) => destination.to_string(),
+            (None, Err(_)) if name.contains('%') => destination.to_string(),
+            (None, Err(_)) => format!("{} ({})", name, destination),
+        };
+        Target {
+            host,
+            label,
+            destination,
+            stats: PingStatistics::new(),
+            replies: ReplyTracker {
+                sent_tos,
+                ..ReplyTracker::default()
+            },
+        }
+    }
+}
+
+/// Running pingers, numbered in the order they started, with the target and
+/// host each reports into. Stopped pingers stay until their last probes are
+/// settled.
+#[derive(Default)]
+struct Runs {
+    events: StreamMap<usize, Pin<Box<dyn Stream<Item = PingEvent> + Send>>>,
+    destinations: Vec<IpAddr>,
+    handles: Vec<PingHandle>,
+    targets: Vec<usize>,
+    hosts: Vec<usize>,
+}
+
+impl Runs {
+    fn add(&mut self, pinger: Pinger, target: usize, host: usize) {
+        let run = self.handles.len();
+        self.destinations.push(pinger.destination());
+        self.handles.push(pinger.handle());
+        self.targets.push(target);
+        self.hosts.push(host);
+        self.events.insert(run, Box::pin(pinger.run()));
+    }
+
+    /// The run started last for `host`.
+    fn latest(&self, host: usize) -> Option<usize> {
+        self.hosts.iter().rposition(|run_host| *run_host == host)
+    }
+
+    fn destination(&self, run: usize) -> IpAddr {
+        self.destinations[run]
+    }
+
+    fn is_running(&self, run: usize) -> bool {
+        self.events.contains_key(&run) && self.handles[run].is_running()
+    }
+
+    /// Stops sending to the run's destination but still counts its last probes.
+    fn finish(&self, run: usize) {
+        self.handles[run].finish();
+    }
+
+    /// Probes sent to `host` so far, by every run for it.
+    fn sent_to(&self, host: usize) -> u32 {
+        self.handles
+            .iter()
+            .zip(&self.hosts)
+            .filter(|(_, run_host)| **run_host == host)
+            .map(|(handle, _)| handle.sent())
+            .sum()
+    }
+
+    fn stop_all(&self) {
+        for handle in &self.handles {
+            handle.stop();
+        }
+    }
+}
+
+/// The settings the command line, and the host's own overrides, ask for one destination.
+fn build_config(
+    args: &CliArgs,
+    entry: &HostEntry,
+    destination: IpAddr,
+    mut scope_id: u32,
+) -> Result<PingConfig, PingError> {
+    // -I names the link for a link-local destination written without a zone
+    if let (IpAddr::V6(destination), 0, Some(BindTarget::Interface(name))) =
+        (destination, scope_id, &args.interface)
+    {
+        if ip::is_link_local(&destination) {
+            scope_id = ip::interface_index(name).unwrap_or(0);
+        }
+    }
+
+    let mut config = PingConfig::new(destination);
+    config.scope_id = scope_id;
+    config.count = args.count;
+    config.interval = entry
+        .interval
+        .unwrap_or(Duration::from_millis(args.interval));
+    config.timeout = Duration::from_millis(args.timeout);
+    config.ttl = args.ttl;
+    config.hop_limit = args.hop_limit;
+    config.tos = args.tos.unwrap_or(0);
+    config.mtu_discovery = args.mtu_discovery;
+    config.payload_size = entry.size.unwrap_or(args.size);
+    config.fragment_size = args.fragment_size;
+    config.fragment_overlap = args.fragment_overlap;
+    config.id = args.id;
+    config.include_payload = args.include_payload;
+    if args.flood {
+        config.interval = config.interval.min(FLOOD_INTERVAL);
+        config.flood = true;
+    }
+    // the session binds its sockets, this keeps our IP header in line with them
+    config.source = match &args.interface {
+        Some(BindTarget::Address(address)) => Some(*address),
+        // the zone picks the interface's link-local address
+        Some(BindTarget::Interface(_)) if scope_id != 0 => None,
+        Some(BindTarget::Interface(name)) => Some(ip::interface_address(name, destination)?),
+        None => None,
+    };
+    Ok(config)
+}
+
+fn print_statistics(out: &mut (dyn Write + Send), stats: &PingStatistics) {
+    let _ = writeln!(
+        out,
+        "Success: {} Failure: {} - Avg Success Time: {}ms",
+        stats.received,
+        stats.lost(),
+        match stats.avg_rtt() {
+            Some(duration) => duration.as_millis().to_string(),
+            None => "N/A".to_string(),
+        }
+    );
+}
+
+async fn run_pmtu(
+    config: PingConfig,
+    session: Arc<Session>,
+    out: &mut (dyn Write + Send),
+) -> Result<PingStatistics, PingError> {
+    let destination = config.destination;
+    let scope_id = config.scope_id;
+    let source = Pinger::with_session(config.clone(), session.clone())?.source();
+    let _ = writeln!(
+        out,
+        "Discovering path MTU to {} from {}",
+        describe_destination(destination, scope_id),
+        describe_source(source, scope_id)
+    );
+
+    let mut stats = PingStatistics::new();
+    let mtu = pmtu::discover(config, session, |size, probe| {
+        stats.transmitted += 1;
+        let _ = match probe {
+            PmtuProbe::Fits { rtt } => {
+                stats.update_success(*rtt);
+                writeln!(out, "{} byte payload: reached {}", size, destination)
+            }
+            PmtuProbe::TooBig {
+                from: Some(from),
+                mtu: Some(mtu),
+            } => writeln!(
+                out,
+                "{} byte payload: too big for {} (mtu = {})",
+                size, from, mtu
+            ),
+            PmtuProbe::TooBig {
+                from: Some(from),
+                mtu: None,
+            } => writeln!(out, "{} byte payload: too big for {}", size, from),
+            PmtuProbe::TooBig { from: None, .. } => {
+                writeln!(
+                    out,
+                    "{} byte payload: too big for the local interface",
+                    size
+                )
+            }
+            PmtuProbe::Lost => writeln!(out, "{} byte payload: no reply", size),
+        };
+    })
+    .await?;
+
+    let _ = writeln!(
+        out,
+        "Path MTU to {}: {} bytes",
+        describe_destination(destination, scope_id),
+        mtu
+    );
+    Ok(stats)
+}
+
+async fn run_trace(
+    args: &TraceArgs,
+    session: Arc<Session>,
+    out: &mut (dyn Write + Send),
+) -> Result<PingStatistics, PingError> {
+    let family = if args.ipv4 {
+        AddressFamily::V4
+    } else if args.ipv6 {
+        AddressFamily::V6
+    } else {
+        AddressFamily::Any
+    };
+    let resolver = session.resolver();
+    let (destination, scope_id) = ip::resolve_scoped_host(&*resolver, &args.host, family)
+        .map_err(|e| PingError::new(format!("Couldn't resolve host: {}", e)))?[0];
+    if args.first_ttl > args.max_hops {
+        return Err(PingError::new(format!(
+            "The first TTL ({}) is beyond the last ({})",
+            args.first_ttl, args.max_hops
+        )));
+    }
+
+    let mut config = TraceConfig::new(destination);
+    config.probe.scope_id = scope_id;
+    config.probe.timeout = Duration::from_millis(args.timeout);
+    config.probe.payload_size = args.size;
+    config.first_ttl = args.first_ttl;
+    config.max_hops = args.max_hops;
+    config.queries = args.queries;
+    config.probe.flow = args.paris.then_some(Flow::Fixed(args.flow));
+    let source = Pinger::with_session(config.probe.clone(), session.clone())?.source();
+    let _ = writeln!(
+        out,
+        "Tracing route to {} from {}{}, {} hops max",
+        describe_destination(destination, scope_id),
+        describe_source(source, scope_id),
+        describe_protocol(session.protocol()),
+        args.max_hops
+    );
+
+    let resolver =
+        (!args.numeric).then(|| ReverseResolver::new(session.resolver(), LOOKUP_TIMEOUT));
+    let mut hops = Vec::new();
+    for ttl in args.first_ttl..=args.max_hops {
+        let (hop, line) = if args.mda {
+            let hop = trace::enumerate_hop(&config, ttl, &session).await?;
+            let line = describe_next_hops(&hop, resolver.as_ref()).await;
+            (hop, line)
+        } else {
+            let hop = trace::probe_hop(&config, ttl, &session).await?;
+            let line = describe_hop(&hop, resolver.as_ref()).await;
+            (hop, line)
+        };
+        let _ = writeln!(out, "{}", line);
+        let mut stats = HopStats::new(ttl);
+        stats.record(&hop);
+        hops.push(stats);
+        if hop.is_last() {
+            break;
+        }
+    }
+
+    if args.mtr || args.report {
+        let rounds = args.count.or(args.report.then_some(10));
+        run_mtr(
+            args,
+            config,
+            rounds,
+            &mut hops,
+            session,
+            resolver.as_ref(),
+            out,
+        )
+        .await?;
+    }
+    let mut total = PingStatistics::new();
+    for hop in &hops {
+        total.merge(&hop.stats);
+    }
+    Ok(total)
+}
+
+/// A multipath line: the TTL, then every address that answered with how many
+/// flows went through it.
+async fn describe_next_hops(hop: &Hop, resolver: Option<&ReverseResolver>) -> String {
+    let mut next_hops: Vec<(Option<IpAddr>, usize)> = Vec::new();
+    for from in hop.probes.iter().map(HopProbe::from) {
+        match next_hops.iter_mut().find(|(address, _)| *address == from) {
+            Some((_, flows)) => *flows += 1,
+            None => next_hops.push((from, 1)),
+        }
+    }
+    // unanswered flows go last
+    next_hops.sort_by_key(|(address, _)| address.is_none());
+    let mut line = format!("{:>2}", hop.ttl);
+    for (address, flows) in next_hops {
+        let name = match (address, resolver) {
+            (Some(address), Some(resolver)) => resolver.describe(address).await,
+            (Some(address), None) => address.to_string(),
+            (None, _) => "*".to_string(),
+        };
+        let plural = if flows == 1 { "flow" } else { "flows" };
+        line.push_str(&format!("  {} ({} {})", name, flows, plural));
+    }
+    line
+}
+
+/// Probes every hop of a finished pass once a round, for `rounds` rounds or until
+/// interrupted, then prints each hop's statistics. With `--mtr` the table is
+/// redrawn after every round.
+async fn run_mtr(
+    args: &TraceArgs,
+    mut config: TraceConfig,
+    rounds: Option<u32>,
+    hops: &mut [HopStats],
+    session: Arc<Session>,
+    resolver: Option<&ReverseResolver>,
+    out: &mut (dyn Write + Send),
+) -> Result<(), PingError> {
+    let (Some(first), Some(last)) = (hops.first(), hops.last()) else {
+        return Ok(());
+    };
+    let ttls = first.ttl..=last.ttl;
+    config.queries = 1;
+    let interval = Duration::from_millis(args.interval);
+    let mut next_round = Instant::now();
+    // lines of the table on screen, for --mtr to draw over
+    let mut drawn = 0;
+
+    let ctrl_c = signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+    let mut round = 0;
+    while rounds.is_none_or(|rounds| round < rounds) {
+        tokio::select! {
+            _ = sleep_until(next_round) => {}
+            _ = &mut ctrl_c => break,
+        }
+        next_round += interval;
+        round += 1;
+        let probed = tokio::select! {
+            probed = trace::probe_hops(&config, ttls.clone(), &session) => probed?,
+            _ = &mut ctrl_c => break,
+        };
+        for (stats, hop) in hops.iter_mut().zip(&probed) {
+            stats.record(hop);
+        }
+        if args.mtr {
+            let table = describe_hop_stats(hops, resolver).await;
+            if drawn > 0 {
+                let _ = write!(out, "\x1b[{}A", drawn);
+            }
+            for line in &table {
+                let _ = writeln!(out, "\x1b[2K{}", line);
+            }
+            drawn = table.len();
+        }
+    }
+
+    if !args.mtr || drawn == 0 {
+        for line in describe_hop_stats(hops, resolver).await {
+            let _ = writeln!(out, "{}", line);
+        }
+    }
+    Ok(())
+}
+
+/// An `mtr --report` style table: loss, then the last, average, best and worst
+/// round trip times and their standard deviation, in milliseconds. Hops that
+/// answered from several addresses list the others underneath.
+async fn describe_hop_stats(hops: &[HopStats], resolver: Option<&ReverseResolver>) -> Vec<String> {
+    let millis = |rtt: Option<Duration>| match rtt {
+        Some(rtt) => format!("{:.1}", rtt.as_secs_f64() * 1000.0),
+        None => "-".to_string(),
+    };
+    let mut lines = vec![format!(
+        "{:>3}  {:<40} {:>6} {:>5} {:>7} {:>7} {:>7} {:>7} {:>7}",
+        "Hop", "Address", "Loss%", "Snt", "Last", "Avg", "Best", "Wrst", "StDev"
+    )];
+    for hop in hops {
+        let mut names = Vec::new();
+        for address in &hop.addresses {
+            names.push(match resolver {
+                Some(resolver) => resolver.describe(*address).await,
+                None => address.to_string(),
+            });
+        }
+        let stats = &hop.stats;
+        lines.push(format!(
+            "{:>3}  {:<40} {:>5.1}% {:>5} {:>7} {:>7} {:>7} {:>7} {:>7}",
+            hop.ttl,
+            names.first().map_or("???", String::as_str),
+            stats.loss_percent(),
+            stats.transmitted,
+            millis(stats.last_rtt()),
+            millis(stats.avg_rtt()),
+            millis(stats.min_rtt()),
+            millis(stats.max_rtt()),
+            millis(stats.mdev_rtt()),
+        ));
+        lines.extend(names.iter().skip(1).map(|name| format!("     {}", name)));
+    }
+    lines
+}
+
+/// A traceroute line: the TTL, then each probe's time, preceded by the address
+/// that answered it whenever that changes.
+async fn describe_hop(hop: &Hop, resolver: Option<&ReverseResolver>) -> String {
+    let mut line = format!("{:>2}", hop.ttl);
+    let mut last_from = None;
+    for probe in &hop.probes {
+        if let Some(from) = probe.from().filter(|from| Some(*from) != last_from) {
+            let name = match resolver {
+                Some(resolver) => resolver.describe(from).await,
+                None => from.to_string(),
+            };
+            line.push_str(&format!("  {}", name));
+            last_from = Some(from);
+        }
+        match probe {
+            HopProbe::Lost => line.push_str("  *"),
+            HopProbe::Unreachable { rtt, message, .. } => {
+                line.push_str(&format!("  {} ms ({})", rtt.as_millis(), message))
+            }
+            HopProbe::TimeExceeded { rtt, .. } | HopProbe::Reply { rtt, .. } => {
+                line.push_str(&format!("  {} ms", rtt.as_millis()))
+            }
+        }
+    }
+    line
+}
+
+async fn run_sweep(
+    args: &CliArgs,
+    range: AddressRange,
+    session: Arc<Session>,
+    out: &mut (dyn Write + Send),
+) -> Result<PingStatistics, PingError> {
+    let mut config = SweepConfig::new();
+    config.rate = args.rate;
+    config.retries = args.retries;
+    config.timeout = Duration::from_millis(args.timeout);
+    config.ttl = args.ttl;
+    config.tos = args.tos.unwrap_or(0);
+    config.payload_size = if args.include_payload { args.size } else { 0 };
+    config.source = match &args.interface {
+        Some(BindTarget::Address(address)) => Some(*address),
+        Some(BindTarget::Interface(name)) => {
+            Some(ip::interface_address(name, IpAddr::V4(range.first))?)
+        }
+        None => None,
+    };
+    if let Some(source @ IpAddr::V6(_)) = config.source {
+        return Err(PingError::new(format!(
+            "Source {} can't sweep IPv4 addresses",
+            source
+        )));
+    }
+    let addresses = range.addresses();
+    let source = config
+        .source
+        .or_else(|| {
+            let transport = session.transport(IpAddr::V4(range.first)).ok()?;
+            transport.source_for(IpAddr::V4(range.first))
+        })
+        .or_else(|| ip::get_machine_ipv4(range.first).map(IpAddr::V4));
+    let _ = match source {
+        Some(source) => writeln!(
+            out,
+            "Sweeping {} addresses in {} from {}",
+            addresses.len(),
+            range,
+            describe_source(source, 0)
+        ),
+        None => writeln!(out, "Sweeping {} addresses in {}", addresses.len(), range),
+    };
+
+    let resolver =
+        (!args.numeric).then(|| ReverseResolver::new(session.resolver(), LOOKUP_TIMEOUT));
+    let report = sweep::sweep(&addresses, &config, session).await?;
+    if let Some(ref resolver) = resolver {
+        let answered = report
+            .hosts
+            .iter()
+            .flat_map(|(address, state)| match state {
+                HostState::Alive { .. } => vec![IpAddr::V4(*address)],
+                HostState::Unreachable { from, .. } => vec![IpAddr::V4(*address), *from],
+                HostState::Silent => vec![],
+            });
+        resolver.prefetch(answered).await;
+    }
+    let mut stats = PingStatistics::new();
+    stats.transmitted = addresses.len() as u32;
+    for (address, state) in &report.hosts {
+        let address = IpAddr::V4(*address);
+        match state {
+            HostState::Alive { rtt } => {
+                stats.update_success(*rtt);
+                let name = describe_address(resolver.as_ref(), address).await;
+                let _ = writeln!(out, "{} is alive: time={} ms", name, rtt.as_millis());
+            }
+            HostState::Unreachable { from, message } => {
+                stats.errors += 1;
+                let name = describe_address(resolver.as_ref(), address).await;
+                let from = describe_address(resolver.as_ref(), *from).await;
+                let _ = writeln!(out, "{} is unreachable: {} from {}", name, message, from);
+            }
+            HostState::Silent => {}
+        }
+    }
+    let unreachable = match stats.errors {
+        0 => String::new(),
+        errors => format!(", {} unreachable", errors),
+    };
+    let _ = writeln!(
+        out,
+        "{} of {} addresses alive{}, {} echo requests sent",
+        stats.received,
+        addresses.len(),
+        unreachable,
+        report.sent
+    );
+    Ok(stats)
+}
+
+/// `address` with its name, unless output is numeric.
+async fn describe_address(resolver: Option<&ReverseResolver>, address: IpAddr) -> String {
+    match resolver {
+        Some(resolver) => resolver.describe(address).await,
+        None => address.to_string(),
+    }
+}
+
+/// What the replies so far say about the path and how they were timed.
+#[derive(Debug, Default)]
+struct ReplyTracker {
+    // TTL of the latest reply, to notice the route changing under us
+    last_ttl: Option<u8>,
+    hops: Option<u8>,
+    // the least accurate send and receive timestamps any reply was timed with
+    timing: Option<(TimestampSource, TimestampSource)>,
+    // TOS we asked for, and the last differing DSCP replies came back with
+    sent_tos: Option<u8>,
+    remarked_to: Option<u8>,
+}
+
+impl ReplyTracker {
+    fn observe(&mut self, out: &mut (dyn Write + Send), reply: &PingReply) {
+        if let (Some(previous), Some(ttl)) = (self.last_ttl, reply.ttl) {
+            if previous != ttl {
+                let _ = writeln!(
+                    out,
+                    "Warning: TTL changed from {} to {} at icmp_seq={}, the route may have changed",
+                    previous, ttl, reply.seq
+                );
+            }
+        }
+        if let (Some(sent), Some(received)) = (self.sent_tos, reply.tos) {
+            let remarked = received >> 2 != sent >> 2;
+            if remarked && self.remarked_to.map(|tos| tos >> 2) != Some(received >> 2) {
+                let _ = writeln!(
+                    out,
+                    "Warning: replies carry DSCP {} instead of {}, the path may be remarking them",
+                    dscp::dscp_name(received),
+                    dscp::dscp_name(sent)
+                );
+            }
+            self.remarked_to = if remarked { Some(received) } else { None };
+        }
+        self.last_ttl = reply.ttl.or(self.last_ttl);
+        self.hops = reply.estimated_hops().or(self.hops);
+        self.timing = Some(match self.timing {
+            Some((send, receive)) => (
+                send.min(reply.send_timestamp),
+                receive.min(reply.receive_timestamp),
+            ),
+            None => (reply.send_timestamp, reply.receive_timestamp),
+        });
+    }
+
+    fn print_summary(&self, out: &mut (dyn Write + Send)) {
+        if let Some(hops) = self.hops {
+            let _ = writeln!(out, "Estimated hops: {}", hops);
+        }
+        if let Some((send, receive)) = self.timing {
+            let _ = writeln!(out, "Timestamps: {} send, {} receive", send, receive);
+        }
+    }
+}
+
+/// The address an event came from, for events that came from somewhere.
+fn event_source(event: &PingEvent) -> Option<IpAddr> {
+    match event {
+        PingEvent::Reply(reply) | PingEvent::Duplicate(reply) => Some(reply.source),
+        PingEvent::IcmpError { source, .. } => Some(*source),
+        _ => None,
+    }
+}
+
+/// Prints `event`, naming where it came from as `from`.
+fn print_event(
+    out: &mut (dyn Write + Send),
+    event: &PingEvent,
+    from: &str,
+    audio: bool,
+    show_tos: bool,
+) {
+    let _ = match event {
+        PingEvent::Reply(reply) => {
+            if audio {
+                let _ = writeln!(out, "\x07"); // Beep
+            }
+            writeln!(out, "{}", describe_reply(reply, from, show_tos))
+        }
+        PingEvent::Duplicate(reply) => {
+            writeln!(out, "{} (DUP!)", describe_reply(reply, from, show_tos))
+        }
+        PingEvent::IcmpError { seq, message, .. } => {
+            writeln!(out, "From {} icmp_seq={} {}", from, seq, message)
+        }
+        PingEvent::SendFailed { seq, error } => {
+            writeln!(out, "Failed to send packet icmp_seq={}: {}", seq, error)
+        }
+        PingEvent::ReceiveFailed { seq, error } => {
+            writeln!(out, "Failed to receive icmp_seq={}: {}", seq, error)
+        }
+        PingEvent::Sent { .. } | PingEvent::Timeout { .. } => Ok(()),
+    };
+}
+
+/// A dot for every probe sent, taken back when its reply arrives, so what's
+/// left on the line is the probes that went unanswered.
+fn print_flood_event(out: &mut (dyn Write + Send), event: &PingEvent) {
+    let _ = match event {
+        PingEvent::Sent { .. } => write!(out, "."),
+        PingEvent::Reply(_) => write!(out, "\x08 \x08"),
+        _ => return,
+    };
+    let _ = out.flush();
+}
+
+fn describe_reply(reply: &PingReply, from: &str, show_tos: bool) -> String {
+    let mut fields = String::new();
+    if let Some(ttl) = reply.ttl {
+        fields.push_str(&format!(" ttl={}", ttl));
+    }
+    if let (true, Some(tos)) = (show_tos, reply.tos) {
+        fields.push_str(&format!(" tos=0x{:02x}", tos));
+    }
+    // connected probes never see the answer itself
+    let received = match reply.bytes {
+        Some(bytes) => format!("Received {} bytes from", bytes),
+        None => "Answer from".to_string(),
+    };
+    format!(
+        "{} {}: icmp_seq={}{} time={} ms",
+        received,
+        from,
+        reply.seq,
+        fields,
+        reply.rtt.as_millis()
+    )
+}
@@ -1,11 +1,96 @@
 use anstyle::{AnsiColor, Color, Style};
-use clap::{crate_version, Parser};
+use clap::{builder::RangedU64ValueParser, crate_version, Args, Parser, Subcommand};
+
+use crate::{
+    dscp, icmp,
+    ip::{self, BindTarget},
+    pinger::Flow,
+    sweep::AddressRange,
+    transport::{MtuDiscovery, ProbeProtocol},
+};
+
+/// Most payload bytes a fragment carries after its 20-byte IPv4 header.
+const MAX_FRAGMENT_SIZE: u64 = u16::MAX as u64 - 20;
 
 #[derive(Debug, Parser)]
-#[command(name="ring", styles=colorize_app(), version=crate_version!(), about="ping in rust", long_about = "rust implementation of the classic util ping", arg_required_else_help(true))]
+#[command(name="ring", styles=colorize_app(), version=crate_version!(), about="ping in rust", long_about = "rust implementation of the classic util ping", arg_required_else_help(true), subcommand_negates_reqs = true, args_conflicts_with_subcommands = true)]
 pub struct CliArgs {
-    #[arg(help = "The ip address or hostname to ping")]
-    pub host: String,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    #[arg(
+        value_name = "HOST",
+        required_unless_present_any = ["generate", "file"],
+        help = "The ip addresses or hostnames to ping, all at once, or - to read them from stdin"
+    )]
+    pub hosts: Vec<String>,
+
+    #[arg(
+        short = 'F',
+        long,
+        value_name = "path",
+        help = "Read hosts from a file, one per line with optional interval=, size= and label= overrides"
+    )]
+    pub file: Option<String>,
+
+    #[arg(
+        short = 'g',
+        long,
+        value_name = "cidr|start-end",
+        conflicts_with_all = ["hosts", "file", "ipv6", "all_addresses", "reresolve", "pmtu"],
+        help = "Sweep an IPv4 block (10.0.0.0/24) or range (10.0.0.1-10.0.0.20) and list the hosts that answer"
+    )]
+    pub generate: Option<AddressRange>,
+
+    #[arg(
+        long,
+        value_name = "per second",
+        default_value = "100",
+        requires = "generate",
+        help = "Echo requests per second while sweeping"
+    )]
+    pub rate: u32,
+
+    #[arg(
+        long,
+        default_value = "1",
+        requires = "generate",
+        help = "Times to ask again the hosts that didn't answer a sweep"
+    )]
+    pub retries: u32,
+
+    #[arg(
+        short = '4',
+        conflicts_with = "ipv6",
+        help = "Only ping IPv4 addresses"
+    )]
+    pub ipv4: bool,
+
+    #[arg(short = '6', help = "Only ping IPv6 addresses")]
+    pub ipv6: bool,
+
+    #[arg(
+        long,
+        conflicts_with = "pmtu",
+        help = "Ping every address the host resolves to, with statistics for each"
+    )]
+    pub all_addresses: bool,
+
+    #[arg(
+        long,
+        value_name = "seconds",
+        value_parser = clap::value_parser!(u64).range(1..),
+        conflicts_with_all = ["all_addresses", "pmtu"],
+        help = "Resolve the host again this often and switch to its new address when it changes"
+    )]
+    pub reresolve: Option<u64>,
+
+    #[arg(
+        short = 'n',
+        long,
+        help = "Show addresses without looking up their names"
+    )]
+    pub numeric: bool,
 
     #[arg(
         short = 'a',
@@ -27,6 +112,14 @@ pub struct CliArgs {
     )]
     pub interval: u64,
 
+    #[arg(
+        short = 'f',
+        long,
+        conflicts_with_all = ["generate", "pmtu"],
+        help = "Flood ping, as -f in the classic ping (so --file is -F): print a dot for every probe and erase it on the reply, sending the next as soon as a reply arrives or at least 100 times a second; implies -n"
+    )]
+    pub flood: bool,
+
     #[arg(
         short = 't',
         long,
@@ -38,6 +131,63 @@ pub struct CliArgs {
     #[arg(short = 'T', long, default_value = "64", help = "TTL for IPV4 packets")]
     pub ttl: u8,
 
+    #[arg(
+        short = 'Q',
+        long,
+        value_parser = dscp::parse_tos,
+        help = "DSCP name (EF, AF41, CS6...) or raw TOS/traffic class byte to send with"
+    )]
+    pub tos: Option<u8>,
+
+    #[arg(
+        short = 'M',
+        value_name = "do|want|dont|probe",
+        help = "Path MTU discovery: set don't-fragment (do), let the kernel decide (want), allow fragmentation (dont), or ignore the known path MTU (probe)"
+    )]
+    pub mtu_discovery: Option<MtuDiscovery>,
+
+    #[arg(
+        long,
+        default_value = "false",
+        help = "Find the path MTU to the host instead of pinging it"
+    )]
+    pub pmtu: bool,
+
+    #[arg(
+        short = 's',
+        long,
+        default_value = "32",
+        value_parser = RangedU64ValueParser::<usize>::new().range(..=icmp::MAX_IPV6_PAYLOAD_SIZE as u64),
+        help = "Number of payload bytes to send, at most 65507 to IPv4 hosts and 65527 to IPv6 ones"
+    )]
+    pub size: usize,
+
+    #[arg(
+        long,
+        value_name = "bytes",
+        value_parser = RangedU64ValueParser::<usize>::new().range(8..=MAX_FRAGMENT_SIZE),
+        help = "Split IPv4 probes into fragments carrying this many payload bytes (rounded down to a multiple of 8)"
+    )]
+    pub fragment_size: Option<usize>,
+
+    #[arg(
+        long,
+        value_name = "bytes",
+        default_value = "0",
+        requires = "fragment_size",
+        help = "Bytes each fragment repeats from the one before it"
+    )]
+    pub fragment_overlap: usize,
+
+    #[arg(
+        short = 'I',
+        long = "interface",
+        value_name = "addr|ifname",
+        value_parser = ip::parse_bind_target,
+        help = "Send from this local address, or through this interface"
+    )]
+    pub interface: Option<BindTarget>,
+
     #[arg(long = "id", help = "Header ID for ICMP packets")]
     pub id: Option<u16>,
 
@@ -58,6 +208,172 @@ pub struct CliArgs {
 
     )]
     pub include_payload: bool,
+
+    #[arg(
+        long,
+        value_name = "port",
+        num_args = 0..=1,
+        require_equals = true,
+        conflicts_with_all = ["generate", "pmtu", "fragment_size", "id"],
+        help = "Send UDP datagrams to high ports, or to this port, and time the Port Unreachable, or datagram from an open port, that answers"
+    )]
+    pub udp: Option<Option<u16>>,
+
+    #[arg(
+        long,
+        value_name = "port",
+        conflicts_with_all = ["generate", "pmtu", "fragment_size", "id", "udp"],
+        help = "Send TCP SYNs to this port and time the SYN-ACK or RST that answers"
+    )]
+    pub tcp: Option<u16>,
+}
+
+impl CliArgs {
+    /// What the session running these arguments should send probes as.
+    pub fn protocol(&self) -> ProbeProtocol {
+        let (udp, tcp) = match self.command {
+            Some(Command::Trace(ref trace)) => (trace.udp, None),
+            None => (self.udp, self.tcp),
+        };
+        match (udp, tcp) {
+            (Some(port), _) => ProbeProtocol::Udp { port },
+            (None, Some(port)) => ProbeProtocol::Tcp { port },
+            (None, None) => ProbeProtocol::Icmp,
+        }
+    }
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Show the routers on the way to a host
+    Trace(TraceArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct TraceArgs {
+    #[arg(value_name = "HOST", help = "The ip address or hostname to trace")]
+    pub host: String,
+
+    #[arg(
+        short = '4',
+        conflicts_with = "ipv6",
+        help = "Only trace to an IPv4 address"
+    )]
+    pub ipv4: bool,
+
+    #[arg(short = '6', help = "Only trace to an IPv6 address")]
+    pub ipv6: bool,
+
+    #[arg(
+        short = 'n',
+        long,
+        help = "Show addresses without looking up their names"
+    )]
+    pub numeric: bool,
+
+    #[arg(
+        short = 'f',
+        long,
+        default_value = "1",
+        value_parser = clap::value_parser!(u8).range(1..),
+        help = "TTL of the first hop to probe"
+    )]
+    pub first_ttl: u8,
+
+    #[arg(
+        short = 'm',
+        long,
+        default_value = "30",
+        help = "Largest TTL to probe with"
+    )]
+    pub max_hops: u8,
+
+    #[arg(
+        short = 'q',
+        long,
+        default_value = "3",
+        value_parser = clap::value_parser!(u16).range(1..),
+        help = "Number of probes for each hop"
+    )]
+    pub queries: u16,
+
+    #[arg(
+        short = 't',
+        long,
+        default_value = "1000",
+        help = "Time to wait for a response in milliseconds"
+    )]
+    pub timeout: u64,
+
+    #[arg(
+        short = 's',
+        long,
+        default_value = "32",
+        value_parser = RangedU64ValueParser::<usize>::new().range(..=icmp::MAX_IPV6_PAYLOAD_SIZE as u64),
+        help = "Number of payload bytes to send, at most 65507 to IPv4 hosts and 65527 to IPv6 ones"
+    )]
+    pub size: usize,
+
+    #[arg(
+        long,
+        help = "Keep probing every hop after the first pass, with live per-hop statistics"
+    )]
+    pub mtr: bool,
+
+    #[arg(
+        long,
+        conflicts_with = "mtr",
+        help = "Like --mtr, but only print the per-hop statistics at the end"
+    )]
+    pub report: bool,
+
+    #[arg(
+        short = 'c',
+        long,
+        value_name = "rounds",
+        help = "Rounds of probes after the first pass, until interrupted by default (10 with --report)"
+    )]
+    pub count: Option<u32>,
+
+    #[arg(
+        short = 'i',
+        long,
+        default_value = "1000",
+        help = "Time between rounds in milliseconds"
+    )]
+    pub interval: u64,
+
+    #[arg(
+        long,
+        help = "Keep every probe on one path through load balancers, as Paris traceroute does"
+    )]
+    pub paris: bool,
+
+    #[arg(
+        long,
+        default_value = "0",
+        requires = "paris",
+        value_parser = clap::value_parser!(u16).range(..=Flow::MAX as i64),
+        help = "Flow to keep probes on with --paris; other flows may take other paths"
+    )]
+    pub flow: u16,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["paris", "mtr", "report"],
+        help = "List every next hop load balancers spread flows over at each TTL"
+    )]
+    pub mda: bool,
+
+    #[arg(
+        long,
+        value_name = "port",
+        num_args = 0..=1,
+        require_equals = true,
+        conflicts_with_all = ["paris", "mda"],
+        help = "Probe with UDP datagrams to high ports, or to this port, like classic traceroute"
+    )]
+    pub udp: Option<Option<u16>>,
 }
 
 pub fn colorize_app() -> clap::builder::Styles {
@@ -0,0 +1,83 @@
+//! DSCP names for the traffic class byte (IPv4 TOS, IPv6 traffic class).
+
+const NAMES: [(&str, u8); 22] = [
+    ("CS0", 0),
+    ("LE", 1),
+    ("CS1", 8),
+    ("AF11", 10),
+    ("AF12", 12),
+    ("AF13", 14),
+    ("CS2", 16),
+    ("AF21", 18),
+    ("AF22", 20),
+    ("AF23", 22),
+    ("CS3", 24),
+    ("AF31", 26),
+    ("AF32", 28),
+    ("AF33", 30),
+    ("CS4", 32),
+    ("AF41", 34),
+    ("AF42", 36),
+    ("AF43", 38),
+    ("CS5", 40),
+    ("EF", 46),
+    ("CS6", 48),
+    ("CS7", 56),
+];
+
+/// Parses a DSCP name such as `EF`, `AF41` or `CS6`, or a raw TOS byte in
+/// decimal or `0x` hex, into the TOS byte to send.
+pub fn parse_tos(value: &str) -> Result<u8, String> {
+    if let Some((_, dscp)) = NAMES
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(value))
+    {
+        return Ok(dscp << 2);
+    }
+    let parsed = match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => value.parse::<u8>(),
+    };
+    parsed.map_err(|_| {
+        format!(
+            "'{}' is not a DSCP name (EF, AF11-AF43, CS0-CS7, LE) or a TOS value from 0 to 255",
+            value
+        )
+    })
+}
+
+/// The DSCP name for the upper six bits of `tos`, or its number if it has none.
+pub fn dscp_name(tos: u8) -> String {
+    let dscp = tos >> 2;
+    match NAMES.iter().find(|(_, value)| *value == dscp) {
+        Some((name, _)) => name.to_string(),
+        None => dscp.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_names_and_raw_values() {
+        assert_eq!(parse_tos("EF"), Ok(0xb8));
+        assert_eq!(parse_tos("af41"), Ok(0x88));
+        assert_eq!(parse_tos("CS6"), Ok(0xc0));
+        assert_eq!(parse_tos("0x10"), Ok(0x10));
+        assert_eq!(parse_tos("184"), Ok(0xb8));
+        assert!(parse_tos("AF99").is_err());
+        assert!(parse_tos("256").is_err());
+    }
+
+    #[test]
+    fn it_names_the_dscp_and_ignores_ecn() {
+        assert_eq!(dscp_name(0xb8), "EF");
+        assert_eq!(dscp_name(0xb9), "EF");
+        assert_eq!(dscp_name(0x04), "LE");
+        assert_eq!(dscp_name(0x0c), "3");
+    }
+}
@@ -35,3 +35,27 @@ impl SocketError {
         SocketError { msg }
     }
 }
+
+#[derive(Debug, Clone, Error)]
+#[error("{msg}")]
+pub struct PingError {
+    pub msg: String,
+}
+
+impl PingError {
+    pub fn new(msg: String) -> Self {
+        PingError { msg }
+    }
+}
+
+impl From<IPError> for PingError {
+    fn from(err: IPError) -> Self {
+        PingError::new(err.msg)
+    }
+}
+
+impl From<SocketError> for PingError {
+    fn from(err: SocketError) -> Self {
+        PingError::new(err.msg)
+    }
+}
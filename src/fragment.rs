@@ -0,0 +1,247 @@
+use std::{collections::HashMap, net::Ipv4Addr, time::Duration};
+
+use tokio::time::Instant;
+
+use crate::icmp::internet_checksum;
+
+/// How long fragments wait for the rest of their datagram.
+pub const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(30);
+// datagrams being reassembled at once, so a fragment flood can't grow without bound
+const MAX_PENDING: usize = 64;
+
+const MORE_FRAGMENTS: u16 = 0x2000;
+const OFFSET_MASK: u16 = 0x1FFF;
+
+/// Whether an IPv4 datagram is a fragment rather than a whole datagram.
+pub fn is_fragment(datagram: &[u8]) -> bool {
+    match datagram.get(6..8) {
+        Some(field) => {
+            u16::from_be_bytes([field[0], field[1]]) & (MORE_FRAGMENTS | OFFSET_MASK) != 0
+        }
+        None => false,
+    }
+}
+
+/// Splits a serialized IPv4 datagram into fragments carrying `size` bytes of its
+/// payload each. Consecutive fragments share `overlap` bytes, which firewalls
+/// and reassembly code should cope with. Both are rounded to the 8-byte units
+/// fragment offsets count in.
+pub fn fragment(datagram: &[u8], size: usize, overlap: usize) -> Vec<Vec<u8>> {
+    let ihl = (datagram[0] & 0x0F) as usize * 4;
+    let (header, payload) = datagram.split_at(ihl);
+    let size = (size / 8 * 8).max(8);
+    let step = size.saturating_sub(overlap.div_ceil(8) * 8).max(8);
+
+    let mut fragments = Vec::new();
+    let mut start = 0;
+    loop {
+        let end = (start + size).min(payload.len());
+        let more = end < payload.len();
+        let mut fragment = header.to_vec();
+        fragment[2..4].copy_from_slice(&((ihl + end - start) as u16).to_be_bytes());
+        let flags = u16::from_be_bytes([header[6], header[7]]) & !(MORE_FRAGMENTS | OFFSET_MASK);
+        let field = flags | if more { MORE_FRAGMENTS } else { 0 } | (start / 8) as u16;
+        fragment[6..8].copy_from_slice(&field.to_be_bytes());
+        set_header_checksum(&mut fragment);
+        fragment.extend_from_slice(&payload[start..end]);
+        fragments.push(fragment);
+        if !more {
+            return fragments;
+        }
+        start += step;
+    }
+}
+
+/// Source, destination, IP id and protocol, which together identify the
+/// datagram a fragment belongs to.
+type FragmentKey = (Ipv4Addr, Ipv4Addr, u16, u8);
+
+#[derive(Debug)]
+struct PartialDatagram {
+    started: Instant,
+    // header of the first fragment, once it arrives
+    header: Option<Vec<u8>>,
+    payload: Vec<u8>,
+    // byte ranges of the payload received so far, sorted and merged
+    received: Vec<(usize, usize)>,
+    // payload length, known once the last fragment arrives
+    length: Option<usize>,
+}
+
+impl PartialDatagram {
+    fn insert(&mut self, start: usize, data: &[u8]) {
+        let end = start + data.len();
+        if self.payload.len() < end {
+            self.payload.resize(end, 0);
+        }
+        self.payload[start..end].copy_from_slice(data);
+
+        self.received.push((start, end));
+        self.received.sort_unstable();
+        let mut merged: Vec<(usize, usize)> = Vec::with_capacity(self.received.len());
+        for &(start, end) in &self.received {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        self.received = merged;
+    }
+
+    fn complete(&self) -> Option<Vec<u8>> {
+        let length = self.length?;
+        let header = self.header.as_ref()?;
+        if self.received.first() != Some(&(0, length)) {
+            return None;
+        }
+        let mut datagram = header.clone();
+        datagram[2..4].copy_from_slice(&((header.len() + length) as u16).to_be_bytes());
+        let flags = u16::from_be_bytes([header[6], header[7]]) & !(MORE_FRAGMENTS | OFFSET_MASK);
+        datagram[6..8].copy_from_slice(&flags.to_be_bytes());
+        set_header_checksum(&mut datagram);
+        datagram.extend_from_slice(&self.payload[..length]);
+        Some(datagram)
+    }
+}
+
+/// Collects IPv4 fragments until their datagram is whole. Fragments of datagrams
+/// that don't complete within the timeout are dropped.
+#[derive(Debug)]
+pub struct Reassembler {
+    timeout: Duration,
+    pending: HashMap<FragmentKey, PartialDatagram>,
+}
+
+impl Reassembler {
+    pub fn new(timeout: Duration) -> Reassembler {
+        Reassembler {
+            timeout,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Adds a fragment and returns the whole datagram once this fragment
+    /// completes it. Later fragments win where fragments overlap.
+    pub fn push(&mut self, fragment: &[u8], now: Instant) -> Option<Vec<u8>> {
+        let timeout = self.timeout;
+        self.pending
+            .retain(|_, partial| now.saturating_duration_since(partial.started) < timeout);
+
+        let ihl = (*fragment.first()? & 0x0F) as usize * 4;
+        if ihl < 20 || fragment.len() < ihl {
+            return None;
+        }
+        let total_length = u16::from_be_bytes([fragment[2], fragment[3]]) as usize;
+        let data = fragment.get(ihl..total_length.min(fragment.len()))?;
+        let field = u16::from_be_bytes([fragment[6], fragment[7]]);
+        let start = (field & OFFSET_MASK) as usize * 8;
+        if start + data.len() > u16::MAX as usize {
+            return None;
+        }
+        let key = (
+            Ipv4Addr::new(fragment[12], fragment[13], fragment[14], fragment[15]),
+            Ipv4Addr::new(fragment[16], fragment[17], fragment[18], fragment[19]),
+            u16::from_be_bytes([fragment[4], fragment[5]]),
+            fragment[9],
+        );
+
+        if !self.pending.contains_key(&key) && self.pending.len() >= MAX_PENDING {
+            return None;
+        }
+        let partial = self.pending.entry(key).or_insert_with(|| PartialDatagram {
+            started: now,
+            header: None,
+            payload: Vec::new(),
+            received: Vec::new(),
+            length: None,
+        });
+        partial.insert(start, data);
+        if start == 0 {
+            partial.header = Some(fragment[..ihl].to_vec());
+        }
+        if field & MORE_FRAGMENTS == 0 {
+            partial.length = Some(start + data.len());
+        }
+
+        let datagram = partial.complete()?;
+        self.pending.remove(&key);
+        Some(datagram)
+    }
+}
+
+impl Default for Reassembler {
+    fn default() -> Self {
+        Reassembler::new(REASSEMBLY_TIMEOUT)
+    }
+}
+
+fn set_header_checksum(datagram: &mut [u8]) {
+    let ihl = (datagram[0] & 0x0F) as usize * 4;
+    datagram[10..12].copy_from_slice(&[0, 0]);
+    let checksum = internet_checksum(&datagram[..ihl]);
+    datagram[10..12].copy_from_slice(&checksum.to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn datagram(payload_len: usize) -> Vec<u8> {
+        let mut datagram = vec![
+            0x45, 0, 0, 0, 0x12, 0x34, 0, 0, 64, 1, 0, 0, 10, 0, 0, 2, 10, 0, 0, 1,
+        ];
+        datagram[2..4].copy_from_slice(&((20 + payload_len) as u16).to_be_bytes());
+        datagram.extend((0..payload_len).map(|i| i as u8));
+        set_header_checksum(&mut datagram);
+        datagram
+    }
+
+    #[test]
+    fn it_reassembles_out_of_order_fragments() {
+        let original = datagram(100);
+        let mut fragments = fragment(&original, 32, 0);
+        assert_eq!(fragments.len(), 4);
+        assert!(fragments.iter().all(|fragment| is_fragment(fragment)));
+        fragments.reverse();
+
+        let mut reassembler = Reassembler::default();
+        let now = Instant::now();
+        let (last, rest) = fragments.split_last().unwrap();
+        for fragment in rest {
+            assert_eq!(reassembler.push(fragment, now), None);
+        }
+        assert_eq!(reassembler.push(last, now), Some(original));
+    }
+
+    #[test]
+    fn it_reassembles_overlapping_fragments() {
+        let original = datagram(100);
+        let fragments = fragment(&original, 40, 12);
+        // 40 byte fragments starting every 24 bytes
+        assert_eq!(fragments.len(), 4);
+        assert_eq!(
+            u16::from_be_bytes([fragments[1][6], fragments[1][7]]) & OFFSET_MASK,
+            3
+        );
+
+        let mut reassembler = Reassembler::default();
+        let now = Instant::now();
+        let whole = fragments
+            .iter()
+            .filter_map(|fragment| reassembler.push(fragment, now))
+            .collect::<Vec<_>>();
+        assert_eq!(whole, vec![original]);
+    }
+
+    #[test]
+    fn it_drops_fragments_that_time_out() {
+        let fragments = fragment(&datagram(100), 64, 0);
+        let mut reassembler = Reassembler::new(Duration::from_secs(1));
+        let now = Instant::now();
+        assert_eq!(reassembler.push(&fragments[0], now), None);
+        assert_eq!(
+            reassembler.push(&fragments[1], now + Duration::from_secs(2)),
+            None
+        );
+    }
+}
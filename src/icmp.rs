@@ -1,6 +1,33 @@
 use crate::error::ICMPError;
 use std::{net::IpAddr, process};
 
+pub const ICMPV4_ECHO_REPLY: u8 = 0;
+pub const ICMPV4_DESTINATION_UNREACHABLE: u8 = 3;
+pub const ICMPV4_ECHO_REQUEST: u8 = 8;
+pub const ICMPV4_TIME_EXCEEDED: u8 = 11;
+pub const ICMPV4_PARAMETER_PROBLEM: u8 = 12;
+
+pub const ICMPV6_DESTINATION_UNREACHABLE: u8 = 1;
+pub const ICMPV6_PACKET_TOO_BIG: u8 = 2;
+pub const ICMPV6_TIME_EXCEEDED: u8 = 3;
+pub const ICMPV6_PARAMETER_PROBLEM: u8 = 4;
+pub const ICMPV6_ECHO_REQUEST: u8 = 128;
+pub const ICMPV6_ECHO_REPLY: u8 = 129;
+
+/// Bytes the default echo request carries after its ICMP header.
+pub const DEFAULT_PAYLOAD_SIZE: usize = 32;
+/// IPv4 header without options plus the ICMP echo header.
+pub const IPV4_ECHO_OVERHEAD: usize = 28;
+/// IPv6 header plus the ICMPv6 echo header.
+pub const IPV6_ECHO_OVERHEAD: usize = 48;
+/// Largest echo payload the 16-bit IPv4 total length leaves room for.
+pub const MAX_IPV4_PAYLOAD_SIZE: usize = u16::MAX as usize - IPV4_ECHO_OVERHEAD;
+/// Largest echo payload the 16-bit IPv6 payload length, which leaves out the
+/// IPv6 header, leaves room for.
+pub const MAX_IPV6_PAYLOAD_SIZE: usize = u16::MAX as usize - 8;
+/// The "don't fragment" bit in `HeaderIPV4::flags`.
+pub const IPV4_FLAG_DONT_FRAGMENT: u8 = 0b010;
+
 #[derive(Debug)]
 pub struct HeaderIPV4 {
     pub version: u8,
@@ -40,7 +67,7 @@ pub struct ICMPHeader {
 
 #[derive(Debug)]
 pub struct ICMPPayload {
-    pub data: [u8; 32],
+    pub data: Vec<u8>,
 }
 
 #[derive(Debug)]
@@ -129,6 +156,68 @@ impl IPV4Packet {
         }
     }
 
+    /// Replaces the payload with `size` random bytes.
+    pub fn with_payload_size(mut self, size: usize) -> IPV4Packet {
+        self.icmp_payload = (size > 0).then(|| ICMPPayload::random(size));
+        self.icmp_header.checksum = 0;
+        self.icmp_header
+            .compute_icmp_checksum(self.icmp_payload.as_ref().map(|payload| &payload.data[..]));
+        if let Some(ref mut header) = self.header {
+            header.length = (IPV4_ECHO_OVERHEAD + size) as u16;
+            header.compute_checksum();
+        }
+        self
+    }
+
+    /// Picks the first two payload bytes so the ICMP checksum comes out as
+    /// `checksum`, growing the payload to two bytes if needed. Routers that
+    /// balance ICMP by its checksum then keep probes with different sequence
+    /// numbers on one path. `None` leaves the packet as it is.
+    pub fn with_checksum(mut self, checksum: Option<u16>) -> IPV4Packet {
+        let Some(checksum) = checksum else {
+            return self;
+        };
+        let payload = self
+            .icmp_payload
+            .get_or_insert(ICMPPayload { data: Vec::new() });
+        if payload.data.len() < 2 {
+            payload.data.resize(2, 0);
+        }
+        payload.data[..2].fill(0);
+        self.icmp_header.checksum = 0;
+        self.icmp_header.compute_icmp_checksum(Some(&payload.data));
+        let filler = checksum_filler(self.icmp_header.checksum, checksum);
+        payload.data[..2].copy_from_slice(&filler.to_be_bytes());
+        self.icmp_header.checksum = checksum;
+        if let Some(ref mut header) = self.header {
+            header.length = (IPV4_ECHO_OVERHEAD + payload.data.len()) as u16;
+            header.compute_checksum();
+        }
+        self
+    }
+
+    /// Sets or clears the "don't fragment" bit of our IP header, if the packet has one.
+    pub fn with_dont_fragment(mut self, dont_fragment: bool) -> IPV4Packet {
+        if let Some(ref mut header) = self.header {
+            header.flags = if dont_fragment {
+                header.flags | IPV4_FLAG_DONT_FRAGMENT
+            } else {
+                header.flags & !IPV4_FLAG_DONT_FRAGMENT
+            };
+            header.compute_checksum();
+        }
+        self
+    }
+
+    /// Sets the TOS byte of our IP header, if the packet has one.
+    pub fn with_tos(mut self, tos: u8) -> IPV4Packet {
+        if let Some(ref mut header) = self.header {
+            header.tos = tos;
+            header.compute_checksum();
+        }
+        self
+    }
+
     pub fn serialize(&self) -> Vec<u8> {
         let mut serialized_packet = Vec::new();
         if let Some(ref header) = self.header {
@@ -170,7 +259,7 @@ impl IPV4Packet {
             tos: data[1],
             length: u16::from_be_bytes([data[2], data[3]]),
             id: u16::from_be_bytes([data[4], data[5]]),
-            flags: (data[6] >> 5) as u8,
+            flags: data[6] >> 5,
             fragment_offset: u16::from_be_bytes([data[6] & 0x1F, data[7]]),
             ttl: data[8],
             protocol: data[9],
@@ -188,10 +277,9 @@ impl IPV4Packet {
         };
 
         let icmp_payload = if data.len() > 28 {
-            let mut payload_data = [0; 32];
-            let payload_len = std::cmp::min(data.len() - 28, 32);
-            payload_data[..payload_len].copy_from_slice(&data[28..28 + payload_len]);
-            Some(ICMPPayload { data: payload_data })
+            Some(ICMPPayload {
+                data: data[28..].to_vec(),
+            })
         } else {
             None
         };
@@ -205,6 +293,64 @@ impl IPV4Packet {
 }
 
 impl IPV6Packet {
+    /// Replaces the payload with `size` random bytes.
+    ///
+    /// The ICMPv6 checksum covers a pseudo-header, so the kernel computes it
+    /// for raw ICMPv6 sockets and this one is only a placeholder.
+    pub fn with_payload_size(mut self, size: usize) -> IPV6Packet {
+        self.icmp_payload = (size > 0).then(|| ICMPPayload::random(size));
+        self.icmp_header.checksum = 0;
+        self.icmp_header
+            .compute_icmp_checksum(self.icmp_payload.as_ref().map(|payload| &payload.data[..]));
+        if let Some(ref mut header) = self.header {
+            header.payload_length = (8 + size) as u16;
+        }
+        self
+    }
+
+    /// Like `IPV4Packet::with_checksum`, for the checksum the kernel will
+    /// compute over the message and the pseudo-header of `source` and `destination`.
+    pub fn with_checksum(
+        mut self,
+        checksum: Option<u16>,
+        source: IpAddr,
+        destination: IpAddr,
+    ) -> IPV6Packet {
+        let (Some(checksum), IpAddr::V6(source), IpAddr::V6(destination)) =
+            (checksum, source, destination)
+        else {
+            return self;
+        };
+        let payload = self
+            .icmp_payload
+            .get_or_insert(ICMPPayload { data: Vec::new() });
+        if payload.data.len() < 2 {
+            payload.data.resize(2, 0);
+        }
+        payload.data[..2].fill(0);
+        if let Some(ref mut header) = self.header {
+            header.payload_length = (8 + payload.data.len()) as u16;
+        }
+        self.icmp_header.checksum = 0;
+        let serialized = self.serialize();
+        let message = match self.header {
+            Some(_) => &serialized[40..],
+            None => &serialized[..],
+        };
+        let mut summed = Vec::with_capacity(40 + message.len());
+        summed.extend_from_slice(&source.octets());
+        summed.extend_from_slice(&destination.octets());
+        summed.extend_from_slice(&(message.len() as u32).to_be_bytes());
+        summed.extend_from_slice(&[0, 0, 0, 58]);
+        summed.extend_from_slice(message);
+        let filler = checksum_filler(internet_checksum(&summed), checksum);
+        if let Some(ref mut payload) = self.icmp_payload {
+            payload.data[..2].copy_from_slice(&filler.to_be_bytes());
+        }
+        self.icmp_header.checksum = checksum;
+        self
+    }
+
     pub fn new_echo_request(
         is_macos: bool,
         source_ip: IpAddr,
@@ -288,19 +434,12 @@ impl IPV6Packet {
     }
 
     pub fn deserialize(data: &[u8]) -> Result<IPV6Packet, ICMPError> {
-        let icmp_header = ICMPHeader {
-            msg_type: data[0],
-            code: data[1],
-            checksum: u16::from_be_bytes([data[2], data[3]]),
-            id: u16::from_be_bytes([data[4], data[5]]),
-            seq_num: u16::from_be_bytes([data[6], data[7]]),
-        };
+        let icmp_header = ICMPHeader::deserialize(data)?;
 
         let icmp_payload = if data.len() > 8 {
-            let mut payload_data = [0u8; 32];
-            let payload_len = std::cmp::min(data.len() - 8, 32);
-            payload_data[..payload_len].copy_from_slice(&data[8..8 + payload_len]);
-            Some(ICMPPayload { data: payload_data })
+            Some(ICMPPayload {
+                data: data[8..].to_vec(),
+            })
         } else {
             None
         };
@@ -340,7 +479,7 @@ impl HeaderIPV4 {
             tos: 0,
             // len(Header) + len(ICMPHeader) + 0 (no payload)
             //     bytes: [ihl * 4(bytes)] + 2 * 4(bytes) + 32 * 4 + 0
-            length: length as u16,
+            length,
             id: header_id,
             flags: 0,
             fragment_offset: 0,
@@ -354,13 +493,12 @@ impl HeaderIPV4 {
 
     fn compute_checksum(&mut self) {
         let mut sum: u32 = 0;
-        sum += (self.version as u32) << 12
-            | (self.ihl as u32) << 8
-            | (self.tos as u32) + (self.length as u32);
+        sum += ((self.version as u32) << 12 | (self.ihl as u32) << 8 | self.tos as u32)
+            + self.length as u32;
+
+        sum += self.id as u32 + ((self.flags as u32) << 13 | self.fragment_offset as u32);
+        sum += (self.ttl as u32) << 8 | self.protocol as u32; // header checksum counts as 0
 
-        sum += self.id as u32 + ((self.flags as u32) << 13) | (self.fragment_offset as u32);
-        sum += (self.ttl as u32) << 8 | (self.protocol as u32) + 0; // 0 term is header checksum
-                                                                    //
         let source_term = ((self.source[0] as u32) << 8 | (self.source[1] as u32))
             + ((self.source[2] as u32) << 8 | (self.source[3] as u32));
         sum += source_term;
@@ -369,11 +507,10 @@ impl HeaderIPV4 {
             + ((self.destination[2] as u32) << 8 | (self.destination[3] as u32));
         sum += destination_term;
 
-        let carry_term = (sum >> 16) as u16;
-        let truncated_sum = (sum & 0xFFFF) as u16;
-        let pre_negation_cs = truncated_sum + carry_term;
-        let checksum = 0xFFFF - pre_negation_cs;
-        self.checksum = checksum;
+        while (sum >> 16) > 0 {
+            sum = (sum & 0xFFFF) + (sum >> 16);
+        }
+        self.checksum = !(sum as u16);
     }
 }
 
@@ -403,6 +540,20 @@ impl HeaderIPV6 {
 }
 
 impl ICMPHeader {
+    pub fn deserialize(data: &[u8]) -> Result<ICMPHeader, ICMPError> {
+        if data.len() < 8 {
+            return Err(ICMPError::new("Packet too short. Invalid".to_string()));
+        }
+
+        Ok(ICMPHeader {
+            msg_type: data[0],
+            code: data[1],
+            checksum: u16::from_be_bytes([data[2], data[3]]),
+            id: u16::from_be_bytes([data[4], data[5]]),
+            seq_num: u16::from_be_bytes([data[6], data[7]]),
+        })
+    }
+
     pub fn new_echo_request_header(msg_type: u8, id: u16, seq_num: u16) -> ICMPHeader {
         ICMPHeader {
             msg_type,
@@ -439,17 +590,183 @@ impl ICMPHeader {
 
 impl ICMPPayload {
     pub fn new_random_payload() -> ICMPPayload {
-        let mut payload_data = [0u8; 32];
+        ICMPPayload::random(DEFAULT_PAYLOAD_SIZE)
+    }
+
+    pub fn random(size: usize) -> ICMPPayload {
+        let mut payload_data = vec![0u8; size];
         use rand::Rng;
         let mut rng = rand::thread_rng();
-        rng.fill(&mut payload_data);
+        rng.fill(&mut payload_data[..]);
         ICMPPayload { data: payload_data }
     }
 }
 
+/// The 16-bit word that turns a message whose checksum is `current` into one
+/// whose checksum is `target`, when added to it in place of a zero word.
+fn checksum_filler(current: u16, target: u16) -> u16 {
+    // the checksum is the complement of the one's complement sum, so the word
+    // has to add !target - !current to that sum
+    let sum = (!target) as u32 + current as u32;
+    ((sum & 0xFFFF) + (sum >> 16)) as u16
+}
+
+/// The RFC 1071 internet checksum of `data`.
+pub fn internet_checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = 0;
+    for chunk in data.chunks(2) {
+        sum += if chunk.len() == 2 {
+            (chunk[0] as u32) << 8 | chunk[1] as u32
+        } else {
+            (chunk[0] as u32) << 8
+        };
+    }
+    while (sum >> 16) > 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+pub fn is_icmpv4_error(msg_type: u8) -> bool {
+    matches!(
+        msg_type,
+        ICMPV4_DESTINATION_UNREACHABLE | ICMPV4_TIME_EXCEEDED | ICMPV4_PARAMETER_PROBLEM
+    )
+}
+
+pub fn is_icmpv6_error(msg_type: u8) -> bool {
+    matches!(
+        msg_type,
+        ICMPV6_DESTINATION_UNREACHABLE
+            | ICMPV6_PACKET_TOO_BIG
+            | ICMPV6_TIME_EXCEEDED
+            | ICMPV6_PARAMETER_PROBLEM
+    )
+}
+
+/// Returns the id and sequence number of the echo request quoted by an ICMPv4
+/// error message. `data` starts at the ICMP header of the error.
+pub fn quoted_echo_v4(data: &[u8]) -> Option<(u16, u16)> {
+    // 8 byte error header, then the offending IP header and at least 8 bytes of its payload
+    let quoted = data.get(8..)?;
+    let ihl = (*quoted.first()? & 0x0F) as usize * 4;
+    let protocol = *quoted.get(9)?;
+    let echo = quoted.get(ihl..ihl + 8)?;
+    if protocol != 1 || echo[0] != ICMPV4_ECHO_REQUEST {
+        return None;
+    }
+    Some((
+        u16::from_be_bytes([echo[4], echo[5]]),
+        u16::from_be_bytes([echo[6], echo[7]]),
+    ))
+}
+
+/// Returns the id and sequence number of the echo request quoted by an ICMPv6
+/// error message. `data` starts at the ICMPv6 header of the error.
+pub fn quoted_echo_v6(data: &[u8]) -> Option<(u16, u16)> {
+    // 8 byte error header, then the 40 byte IPv6 header and the start of its payload
+    let quoted = data.get(8..)?;
+    let next_header = *quoted.get(6)?;
+    let echo = quoted.get(40..48)?;
+    if next_header != 58 || echo[0] != ICMPV6_ECHO_REQUEST {
+        return None;
+    }
+    Some((
+        u16::from_be_bytes([echo[4], echo[5]]),
+        u16::from_be_bytes([echo[6], echo[7]]),
+    ))
+}
+
+/// The UDP datagram an ICMP error quotes: where it was going, its ports, and
+/// as much of its payload as the router sent back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuotedUdp<'a> {
+    pub destination: IpAddr,
+    pub source_port: u16,
+    pub destination_port: u16,
+    pub payload: &'a [u8],
+}
+
+/// Returns the UDP datagram quoted by an ICMPv4 error message. `data` starts at
+/// the ICMP header of the error.
+pub fn quoted_udp_v4(data: &[u8]) -> Option<QuotedUdp<'_>> {
+    let quoted = data.get(8..)?;
+    let ihl = (*quoted.first()? & 0x0F) as usize * 4;
+    let destination = <[u8; 4]>::try_from(quoted.get(16..20)?).ok()?;
+    let udp = quoted.get(ihl..ihl + 8)?;
+    if *quoted.get(9)? != 17 {
+        return None;
+    }
+    Some(QuotedUdp {
+        destination: IpAddr::from(destination),
+        source_port: u16::from_be_bytes([udp[0], udp[1]]),
+        destination_port: u16::from_be_bytes([udp[2], udp[3]]),
+        payload: &quoted[ihl + 8..],
+    })
+}
+
+/// Returns the UDP datagram quoted by an ICMPv6 error message. `data` starts at
+/// the ICMPv6 header of the error.
+pub fn quoted_udp_v6(data: &[u8]) -> Option<QuotedUdp<'_>> {
+    let quoted = data.get(8..)?;
+    let destination = <[u8; 16]>::try_from(quoted.get(24..40)?).ok()?;
+    let udp = quoted.get(40..48)?;
+    if *quoted.get(6)? != 17 {
+        return None;
+    }
+    Some(QuotedUdp {
+        destination: IpAddr::from(destination),
+        source_port: u16::from_be_bytes([udp[0], udp[1]]),
+        destination_port: u16::from_be_bytes([udp[2], udp[3]]),
+        payload: &quoted[48..],
+    })
+}
+
+pub fn describe_icmpv4_error(msg_type: u8, code: u8) -> String {
+    match (msg_type, code) {
+        (ICMPV4_DESTINATION_UNREACHABLE, 0) => "Destination Net Unreachable".to_string(),
+        (ICMPV4_DESTINATION_UNREACHABLE, 1) => "Destination Host Unreachable".to_string(),
+        (ICMPV4_DESTINATION_UNREACHABLE, 2) => "Destination Protocol Unreachable".to_string(),
+        (ICMPV4_DESTINATION_UNREACHABLE, 3) => "Destination Port Unreachable".to_string(),
+        (ICMPV4_DESTINATION_UNREACHABLE, 4) => "Fragmentation Needed".to_string(),
+        (ICMPV4_DESTINATION_UNREACHABLE, 13) => {
+            "Communication Administratively Prohibited".to_string()
+        }
+        (ICMPV4_DESTINATION_UNREACHABLE, _) => format!("Destination Unreachable, code {}", code),
+        (ICMPV4_TIME_EXCEEDED, 0) => "Time to live exceeded".to_string(),
+        (ICMPV4_TIME_EXCEEDED, _) => "Fragment reassembly time exceeded".to_string(),
+        (ICMPV4_PARAMETER_PROBLEM, _) => format!("Parameter problem, code {}", code),
+        _ => format!("ICMP type {} code {}", msg_type, code),
+    }
+}
+
+pub fn describe_icmpv6_error(msg_type: u8, code: u8) -> String {
+    match (msg_type, code) {
+        (ICMPV6_DESTINATION_UNREACHABLE, 0) => "Destination unreachable: No route".to_string(),
+        (ICMPV6_DESTINATION_UNREACHABLE, 1) => {
+            "Destination unreachable: Administratively prohibited".to_string()
+        }
+        (ICMPV6_DESTINATION_UNREACHABLE, 3) => {
+            "Destination unreachable: Address unreachable".to_string()
+        }
+        (ICMPV6_DESTINATION_UNREACHABLE, 4) => {
+            "Destination unreachable: Port unreachable".to_string()
+        }
+        (ICMPV6_DESTINATION_UNREACHABLE, _) => {
+            format!("Destination unreachable, code {}", code)
+        }
+        (ICMPV6_PACKET_TOO_BIG, _) => "Packet too big".to_string(),
+        (ICMPV6_TIME_EXCEEDED, 0) => "Time exceeded: Hop limit".to_string(),
+        (ICMPV6_TIME_EXCEEDED, _) => "Time exceeded: Defrag failure".to_string(),
+        (ICMPV6_PARAMETER_PROBLEM, _) => format!("Parameter problem, code {}", code),
+        _ => format!("ICMPv6 type {} code {}", msg_type, code),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::net::Ipv4Addr;
 
     #[test]
     fn it_computes_icmp_checksum() {
@@ -482,6 +799,78 @@ mod tests {
         assert_eq!(header.checksum, 0xa6ec);
     }
 
+    #[test]
+    fn it_keeps_the_ip_header_checksum_valid_with_a_tos() {
+        let source = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let destination = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let packet = IPV4Packet::new_echo_request(false, source, destination, 0x1234, 64, false, 1)
+            .with_tos(0xb8);
+
+        let serialized = packet.serialize();
+        assert_eq!(serialized[1], 0xb8);
+        assert_eq!(internet_checksum(&serialized[..20]), 0);
+    }
+
+    #[test]
+    fn it_resizes_the_payload_and_sets_dont_fragment() {
+        let source = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let destination = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let packet = IPV4Packet::new_echo_request(false, source, destination, 0x1234, 64, true, 1)
+            .with_payload_size(1000)
+            .with_dont_fragment(true);
+
+        let serialized = packet.serialize();
+        assert_eq!(serialized.len(), 1028);
+        assert_eq!(u16::from_be_bytes([serialized[2], serialized[3]]), 1028);
+        assert_eq!(serialized[6] & 0x40, 0x40);
+        assert_eq!(internet_checksum(&serialized[..20]), 0);
+        assert_eq!(internet_checksum(&serialized[20..]), 0);
+    }
+
+    #[test]
+    fn it_holds_the_checksum_steady_across_sequence_numbers() {
+        let source = IpAddr::V6("2001:db8::1".parse().unwrap());
+        let destination = IpAddr::V6("2001:db8::2".parse().unwrap());
+        for seq in [0, 1, 0xBEEF] {
+            let v4 = IPV4Packet::new_echo_request(
+                true,
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                7,
+                64,
+                false,
+                seq,
+            )
+            .with_checksum(Some(0x1234))
+            .serialize();
+            assert_eq!(v4.len(), 10);
+            assert_eq!(&v4[2..4], &[0x12, 0x34]);
+            assert_eq!(internet_checksum(&v4), 0);
+
+            let v6 = IPV6Packet::new_echo_request(true, source, destination, 7, 64, true, seq)
+                .with_checksum(Some(0x1234), source, destination)
+                .serialize();
+            assert_eq!(&v6[2..4], &[0x12, 0x34]);
+            let mut summed = Vec::new();
+            summed.extend_from_slice(
+                &"2001:db8::1"
+                    .parse::<std::net::Ipv6Addr>()
+                    .unwrap()
+                    .octets(),
+            );
+            summed.extend_from_slice(
+                &"2001:db8::2"
+                    .parse::<std::net::Ipv6Addr>()
+                    .unwrap()
+                    .octets(),
+            );
+            summed.extend_from_slice(&(v6.len() as u32).to_be_bytes());
+            summed.extend_from_slice(&[0, 0, 0, 58]);
+            summed.extend_from_slice(&v6);
+            assert_eq!(internet_checksum(&summed), 0);
+        }
+    }
+
     #[test]
     fn it_serializes_icp4_packet() {
         let source = IpAddr::V4(Ipv4Addr::new(192, 168, 146, 131));
@@ -1,11 +1,135 @@
 use crate::error::IPError;
 use get_if_addrs::get_if_addrs;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, ToSocketAddrs};
+use socket2::SockAddr;
+use std::{
+    ffi::{CStr, CString},
+    fmt::{self, Debug},
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6, ToSocketAddrs, UdpSocket},
+};
 
+/// Where `-I` pins probes: a local address to send from, or an interface to
+/// send through.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BindTarget {
+    Address(IpAddr),
+    Interface(String),
+}
+
+impl fmt::Display for BindTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BindTarget::Address(address) => write!(f, "{}", address),
+            BindTarget::Interface(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// Parses an `-I` value, which must be one of this machine's addresses or the
+/// name of one of its interfaces.
+pub fn parse_bind_target(value: &str) -> Result<BindTarget, IPError> {
+    let interfaces = get_if_addrs()
+        .map_err(|e| IPError::new(format!("Couldn't list network interfaces: {}", e)))?;
+    let target = match value.parse::<IpAddr>() {
+        Ok(address) if interfaces.iter().any(|interface| interface.ip() == address) => {
+            return Ok(BindTarget::Address(address))
+        }
+        Ok(_) => "address",
+        Err(_) if interfaces.iter().any(|interface| interface.name == value) => {
+            return Ok(BindTarget::Interface(value.to_string()))
+        }
+        Err(_) => "interface",
+    };
+    Err(IPError::new(format!(
+        "'{}' is not a local {}. Available interfaces: {}",
+        value,
+        target,
+        describe_interfaces(&interfaces)
+    )))
+}
+
+/// The address on interface `name` to send to `destination` from.
+pub fn interface_address(name: &str, destination: IpAddr) -> Result<IpAddr, IPError> {
+    let interfaces = get_if_addrs()
+        .map_err(|e| IPError::new(format!("Couldn't list network interfaces: {}", e)))?;
+    let mut candidates = interfaces
+        .iter()
+        .filter(|interface| interface.name == name)
+        .map(|interface| interface.ip())
+        .filter(|address| address.is_ipv4() == destination.is_ipv4())
+        .collect::<Vec<_>>();
+    // link-local addresses only reach the local link, and link-local
+    // destinations only answer them
+    let wants_link_local = match destination {
+        IpAddr::V4(destination) => destination.is_link_local(),
+        IpAddr::V6(destination) => is_link_local(&destination),
+    };
+    candidates.sort_by_key(|address| {
+        wants_link_local
+            != match address {
+                IpAddr::V4(address) => address.is_link_local(),
+                IpAddr::V6(address) => is_link_local(address),
+            }
+    });
+    candidates.first().copied().ok_or_else(|| {
+        IPError::new(format!(
+            "Interface {} has no {} address. Available interfaces: {}",
+            name,
+            if destination.is_ipv4() {
+                "IPv4"
+            } else {
+                "IPv6"
+            },
+            describe_interfaces(&interfaces)
+        ))
+    })
+}
+
+/// "eth0 (192.0.2.2, fe80::1), lo (127.0.0.1, ::1)"
+fn describe_interfaces(interfaces: &[get_if_addrs::Interface]) -> String {
+    let mut names: Vec<(&str, Vec<String>)> = Vec::new();
+    for interface in interfaces {
+        let address = interface.ip().to_string();
+        match names.iter_mut().find(|(name, _)| *name == interface.name) {
+            Some((_, addresses)) => addresses.push(address),
+            None => names.push((&interface.name, vec![address])),
+        }
+    }
+    names
+        .iter()
+        .map(|(name, addresses)| format!("{} ({})", name, addresses.join(", ")))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// The address the kernel would send to `destination` from, according to its
+/// routing table.
+pub fn route_source(destination: IpAddr) -> Option<IpAddr> {
+    let socket = UdpSocket::bind((unspecified(destination), 0)).ok()?;
+    // connecting a UDP socket only looks up the route, nothing is sent
+    socket.connect((destination, 9)).ok()?;
+    let source = socket.local_addr().ok()?.ip();
+    (!source.is_unspecified()).then_some(source)
+}
+
+/// The name of the interface `address` belongs to.
+pub fn interface_of(address: IpAddr) -> Option<String> {
+    get_if_addrs()
+        .ok()?
+        .into_iter()
+        .find(|interface| interface.ip() == address)
+        .map(|interface| interface.name)
+}
+
+/// The source for `destination`: the one its route picks, or else the first
+/// usable address.
 pub fn get_machine_ipv4(destination: Ipv4Addr) -> Option<Ipv4Addr> {
     if destination.is_loopback() {
         return Some(Ipv4Addr::LOCALHOST);
     }
+    if let Some(IpAddr::V4(source)) = route_source(IpAddr::V4(destination)) {
+        return Some(source);
+    }
     get_if_addrs().ok().and_then(|if_addrs| {
         if_addrs
             .into_iter()
@@ -28,6 +152,9 @@ pub fn get_machine_ipv6(destination: Ipv6Addr) -> Option<Ipv6Addr> {
     if destination.is_loopback() {
         return Some(Ipv6Addr::LOCALHOST);
     }
+    if let Some(IpAddr::V6(source)) = route_source(IpAddr::V6(destination)) {
+        return Some(source);
+    }
     get_if_addrs().ok().and_then(|if_addrs| {
         if_addrs
             .into_iter()
@@ -50,24 +177,278 @@ fn is_suitable_ipv6(addr: &Ipv6Addr) -> bool {
     !addr.is_loopback() && !is_link_local(addr) && !addr.is_unspecified()
 }
 
-fn is_link_local(addr: &Ipv6Addr) -> bool {
+pub fn is_link_local(addr: &Ipv6Addr) -> bool {
     addr.segments()[0] & 0xffc0 == 0xfe80
 }
 
+/// The MTU of the route to `destination`, including anything the kernel has
+/// learned about the path since.
+#[cfg(target_os = "linux")]
+pub fn route_mtu(destination: IpAddr) -> Option<usize> {
+    use std::os::fd::AsRawFd;
+
+    let socket = UdpSocket::bind((unspecified(destination), 0)).ok()?;
+    socket.connect((destination, 9)).ok()?;
+    let (level, name) = match destination {
+        IpAddr::V4(_) => (libc::IPPROTO_IP, libc::IP_MTU),
+        IpAddr::V6(_) => (libc::IPPROTO_IPV6, libc::IPV6_MTU),
+    };
+    let mut mtu: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &mut mtu as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    (result == 0 && mtu > 0).then_some(mtu as usize)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn route_mtu(_destination: IpAddr) -> Option<usize> {
+    None
+}
+
+/// The source for a destination scoped to interface `scope_id`, which is the
+/// link-local address of that interface for link-local destinations.
+pub fn scoped_source(destination: Ipv6Addr, scope_id: u32) -> Option<Ipv6Addr> {
+    let socket = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).ok()?;
+    socket
+        .connect(SocketAddrV6::new(destination, 9, 0, scope_id))
+        .ok()?;
+    match socket.local_addr().ok()?.ip() {
+        IpAddr::V6(source) if !source.is_unspecified() => Some(source),
+        _ => None,
+    }
+}
+
+/// The index of interface `name`, or `None` if there's no such interface.
+pub fn interface_index(name: &str) -> Option<u32> {
+    let name = CString::new(name).ok()?;
+    let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    (index != 0).then_some(index)
+}
+
+/// The name of the interface with index `index`.
+pub fn interface_name(index: u32) -> Option<String> {
+    let mut name = [0 as libc::c_char; libc::IF_NAMESIZE];
+    let result = unsafe { libc::if_indextoname(index, name.as_mut_ptr()) };
+    if result.is_null() {
+        return None;
+    }
+    let name = unsafe { CStr::from_ptr(name.as_ptr()) };
+    Some(name.to_string_lossy().into_owned())
+}
+
+/// How a zone is written after the `%`: the interface name, or the bare index
+/// if the interface is gone.
+pub fn scope_name(scope_id: u32) -> String {
+    interface_name(scope_id).unwrap_or_else(|| scope_id.to_string())
+}
+
+/// Resolves `host` like `resolve_all`, and also accepts IPv6 literals with a
+/// zone such as `fe80::1%eth0` or `fe80::1%2`. Pairs each address with the index
+/// of the interface it's scoped to, or 0 when it has no zone.
+pub fn resolve_scoped_host(
+    resolver: &dyn Resolve,
+    host: &str,
+    family: AddressFamily,
+) -> Result<Vec<(IpAddr, u32)>, IPError> {
+    let Some((address, zone)) = host.split_once('%') else {
+        let addresses = resolve_all(resolver, host, family)?;
+        return Ok(addresses.into_iter().map(|address| (address, 0)).collect());
+    };
+    if family == AddressFamily::V4 {
+        return Err(IPError::new(format!("{} is not an IPv4 address", host)));
+    }
+    let address = address.parse::<Ipv6Addr>().map_err(|_| {
+        IPError::new(format!(
+            "Only IPv6 addresses take a zone, '{}' isn't one",
+            address
+        ))
+    })?;
+    let scope_id = match zone.parse::<u32>() {
+        Ok(index) => index,
+        Err(_) => interface_index(zone).ok_or_else(|| {
+            let interfaces = get_if_addrs().unwrap_or_default();
+            IPError::new(format!(
+                "Unknown zone '{}'. Available interfaces: {}",
+                zone,
+                describe_interfaces(&interfaces)
+            ))
+        })?,
+    };
+    Ok(vec![(IpAddr::V6(address), scope_id)])
+}
+
+/// Which address families a hostname may resolve to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AddressFamily {
+    #[default]
+    Any,
+    V4,
+    V6,
+}
+
+impl AddressFamily {
+    pub fn matches(self, address: &IpAddr) -> bool {
+        match self {
+            AddressFamily::Any => true,
+            AddressFamily::V4 => address.is_ipv4(),
+            AddressFamily::V6 => address.is_ipv6(),
+        }
+    }
+}
+
+/// Looks up the addresses of hostnames and the names of addresses. Blocks, so
+/// call it off the runtime's threads.
+pub trait Resolve: Debug + Send + Sync {
+    /// Every address `host` has, in the order to try them.
+    fn lookup(&self, host: &str) -> io::Result<Vec<IpAddr>>;
+
+    /// The PTR name of `address`, if it has one.
+    fn reverse(&self, address: IpAddr) -> Option<String>;
+}
+
+/// Resolves with the system's `getaddrinfo` and `getnameinfo`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemResolver;
+
+impl Resolve for SystemResolver {
+    fn lookup(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        Ok((host, 0).to_socket_addrs()?.map(|addr| addr.ip()).collect())
+    }
+
+    fn reverse(&self, address: IpAddr) -> Option<String> {
+        let sockaddr = SockAddr::from(SocketAddr::new(address, 0));
+        // NI_MAXHOST
+        let mut host = [0 as libc::c_char; 1025];
+        let result = unsafe {
+            libc::getnameinfo(
+                sockaddr.as_ptr(),
+                sockaddr.len(),
+                host.as_mut_ptr(),
+                host.len() as libc::socklen_t,
+                std::ptr::null_mut(),
+                0,
+                libc::NI_NAMEREQD,
+            )
+        };
+        if result != 0 {
+            return None;
+        }
+        let name = unsafe { CStr::from_ptr(host.as_ptr()) };
+        Some(name.to_string_lossy().into_owned())
+    }
+}
+
+/// Every address of `family` that `host` resolves to, in the resolver's order.
+pub fn resolve_all(
+    resolver: &dyn Resolve,
+    host: &str,
+    family: AddressFamily,
+) -> Result<Vec<IpAddr>, IPError> {
+    let addresses = match host.parse::<IpAddr>() {
+        Ok(address) => vec![address],
+        Err(_) => {
+            let mut addresses = Vec::new();
+            let resolved = resolver
+                .lookup(host)
+                .map_err(|_| IPError::new(format!("Failed to resolve hostname: {}", host)))?;
+            for address in resolved {
+                if !addresses.contains(&address) {
+                    addresses.push(address);
+                }
+            }
+            addresses
+        }
+    };
+    let matching = addresses
+        .into_iter()
+        .filter(|address| family.matches(address))
+        .collect::<Vec<_>>();
+    if matching.is_empty() {
+        return Err(IPError::new(match family {
+            AddressFamily::Any => format!("Failed to resolve hostname: {}", host),
+            AddressFamily::V4 => format!("{} has no IPv4 address", host),
+            AddressFamily::V6 => format!("{} has no IPv6 address", host),
+        }));
+    }
+    Ok(matching)
+}
+
+fn unspecified(destination: IpAddr) -> IpAddr {
+    match destination {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    }
+}
+
 pub fn resolve_host(host: &str) -> Result<IpAddr, IPError> {
-    if let Ok(ip) = host.parse() {
-        return Ok(ip);
+    resolve_all(&SystemResolver, host, AddressFamily::Any).map(|addresses| addresses[0])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_local_addresses_and_lists_interfaces_otherwise() {
+        assert_eq!(
+            parse_bind_target("127.0.0.1").unwrap(),
+            BindTarget::Address(IpAddr::V4(Ipv4Addr::LOCALHOST))
+        );
+        let error = parse_bind_target("no-such-interface0").unwrap_err();
+        assert!(error.msg.contains("is not a local interface"));
+        assert!(error.msg.contains("127.0.0.1"));
     }
 
-    let socket_addrs = (host, 0)
-        .to_socket_addrs()
-        .map_err(|_| IPError::new(format!("Failed to resolve hostname: {}", host)))?;
+    #[test]
+    fn it_picks_the_source_the_route_uses() {
+        let loopback = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        assert_eq!(route_source(loopback), Some(loopback));
+        assert!(interface_of(loopback).is_some());
+    }
+
+    #[test]
+    fn it_parses_zones_on_ipv6_literals() {
+        let link_local = IpAddr::V6("fe80::1".parse().unwrap());
+        assert_eq!(
+            resolve_scoped_host(&SystemResolver, "fe80::1%3", AddressFamily::Any).unwrap(),
+            vec![(link_local, 3)]
+        );
+        let loopback = interface_of(IpAddr::V4(Ipv4Addr::LOCALHOST)).unwrap();
+        let index = interface_index(&loopback).unwrap();
+        assert_eq!(
+            resolve_scoped_host(
+                &SystemResolver,
+                &format!("fe80::1%{}", loopback),
+                AddressFamily::V6
+            )
+            .unwrap(),
+            vec![(link_local, index)]
+        );
+        assert_eq!(interface_name(index), Some(loopback));
+        assert!(resolve_scoped_host(
+            &SystemResolver,
+            "fe80::1%no-such-interface0",
+            AddressFamily::Any
+        )
+        .is_err());
+        assert!(resolve_scoped_host(&SystemResolver, "10.0.0.1%1", AddressFamily::Any).is_err());
+    }
 
-    socket_addrs
-        .filter_map(|addr| match addr.ip() {
-            IpAddr::V4(ipv4) => Some(IpAddr::V4(ipv4)),
-            IpAddr::V6(ipv6) => Some(IpAddr::V6(ipv6)),
-        })
-        .next()
-        .ok_or_else(|| IPError::new(format!("Failed to resolve hostname: {}", host)))
+    #[test]
+    fn it_keeps_only_addresses_of_the_requested_family() {
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        assert_eq!(
+            resolve_all(&SystemResolver, "127.0.0.1", AddressFamily::V4).unwrap(),
+            vec![localhost]
+        );
+        let error = resolve_all(&SystemResolver, "127.0.0.1", AddressFamily::V6).unwrap_err();
+        assert_eq!(error.msg, "127.0.0.1 has no IPv6 address");
+    }
 }
@@ -1,5 +1,23 @@
+pub mod app;
 pub mod cli;
+pub mod dscp;
 pub mod error;
+pub mod fragment;
 pub mod icmp;
 pub mod ip;
+pub mod pinger;
+pub mod pmtu;
+pub mod rdns;
+pub mod session;
+pub mod simulate;
 pub mod socket;
+pub mod stats;
+pub mod sweep;
+pub mod targets;
+pub mod tcp;
+pub mod trace;
+pub mod transport;
+pub mod udp;
+
+pub use pinger::{PingConfig, PingEvent, Pinger};
+pub use stats::PingStatistics;
@@ -0,0 +1,625 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    sync::{mpsc, Notify},
+    time::{sleep_until, Instant},
+};
+use tokio_stream::{wrappers::UnboundedReceiverStream, Stream};
+
+use crate::{
+    error::PingError,
+    fragment,
+    icmp::{self, get_icmp_id},
+    ip,
+    session::Session,
+    transport::{IcmpTransport, Incoming, MtuDiscovery, Received, SendOptions, TimestampSource},
+};
+
+/// Settings for a single ping session.
+#[derive(Debug, Clone)]
+pub struct PingConfig {
+    pub destination: IpAddr,
+    /// Interface index a link-local IPv6 destination is scoped to, or 0.
+    pub scope_id: u32,
+    /// Source address to send from. Picked from the local interfaces when unset.
+    pub source: Option<IpAddr>,
+    /// Number of probes to send, or `None` to ping until stopped.
+    pub count: Option<u16>,
+    pub interval: Duration,
+    pub timeout: Duration,
+    pub ttl: u8,
+    pub hop_limit: u8,
+    /// IPv4 TOS or IPv6 traffic class byte: DSCP in the upper six bits, ECN below.
+    pub tos: u8,
+    /// ICMP identifier. Derived from the process id when unset.
+    pub id: Option<u16>,
+    pub include_payload: bool,
+    /// Payload bytes after the ICMP header, when `include_payload` is set.
+    pub payload_size: usize,
+    /// Fragmentation and path MTU handling. Left to the system default when unset.
+    pub mtu_discovery: Option<MtuDiscovery>,
+    /// Split IPv4 probes into fragments carrying this many bytes of payload each.
+    /// Probes bigger than the route MTU are fragmented regardless, unless they
+    /// forbid it.
+    pub fragment_size: Option<usize>,
+    /// Bytes each fragment repeats from the one before it.
+    pub fragment_overlap: usize,
+    /// Flow identifier to give probes, as a fixed ICMP checksum and IPv6 flow
+    /// label. Left to chance when unset.
+    pub flow: Option<Flow>,
+    /// Send the next probe as soon as a reply arrives, instead of waiting out
+    /// the rest of the interval.
+    pub flood: bool,
+}
+
+impl PingConfig {
+    pub fn new(destination: IpAddr) -> Self {
+        PingConfig {
+            destination,
+            scope_id: 0,
+            source: None,
+            count: None,
+            interval: Duration::from_millis(1000),
+            timeout: Duration::from_millis(1000),
+            ttl: 64,
+            hop_limit: 64,
+            tos: 0,
+            id: None,
+            include_payload: true,
+            payload_size: icmp::DEFAULT_PAYLOAD_SIZE,
+            mtu_discovery: None,
+            fragment_size: None,
+            fragment_overlap: 0,
+            flow: None,
+            flood: false,
+        }
+    }
+}
+
+/// Which flow probes belong to, for load balancers that hash the ICMP checksum
+/// or the IPv6 flow label to pick a path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flow {
+    /// Every probe takes the same path, as in Paris traceroute.
+    Fixed(u16),
+    /// Probe `seq` carries flow `start + seq`, to spread probes over every path.
+    PerProbe { start: u16 },
+}
+
+impl Flow {
+    /// Highest flow identifier; a checksum of 0xFFFF can't be produced.
+    pub const MAX: u16 = 0xFFFE;
+
+    pub fn for_probe(self, seq: u16) -> u16 {
+        match self {
+            Flow::Fixed(flow) => flow,
+            Flow::PerProbe { start } => start.wrapping_add(seq) % (Flow::MAX + 1),
+        }
+    }
+
+    /// The IPv6 flow label for `flow`, which is never 0 so the kernel keeps it.
+    pub fn label(flow: u16) -> u32 {
+        flow as u32 + 1
+    }
+}
+
+/// An echo reply that answered one of our probes.
+#[derive(Debug, Clone)]
+pub struct PingReply {
+    pub seq: u16,
+    pub source: IpAddr,
+    /// Size of the reply, when the transport sees it.
+    pub bytes: Option<usize>,
+    pub rtt: Duration,
+    /// TTL or hop limit the reply arrived with, if the socket reports it.
+    pub ttl: Option<u8>,
+    /// TOS or traffic class the reply arrived with, if the socket reports it.
+    pub tos: Option<u8>,
+    /// How the send and receive times behind `rtt` were measured.
+    pub send_timestamp: TimestampSource,
+    pub receive_timestamp: TimestampSource,
+}
+
+impl PingReply {
+    /// Hops the reply took, assuming the responder started from the nearest
+    /// common initial TTL (64, 128 or 255).
+    pub fn estimated_hops(&self) -> Option<u8> {
+        let ttl = self.ttl?;
+        let initial = [64, 128, 255].into_iter().find(|initial| *initial >= ttl)?;
+        Some(initial - ttl)
+    }
+}
+
+/// Everything a `Pinger` observes while it runs.
+#[derive(Debug)]
+pub enum PingEvent {
+    Sent {
+        seq: u16,
+        destination: IpAddr,
+    },
+    Reply(PingReply),
+    /// A reply for a probe that was already answered.
+    Duplicate(PingReply),
+    Timeout {
+        seq: u16,
+    },
+    /// An ICMP error, such as destination unreachable, quoting one of our probes.
+    IcmpError {
+        seq: u16,
+        source: IpAddr,
+        msg_type: u8,
+        code: u8,
+        /// Next-hop MTU advertised by Fragmentation Needed or Packet Too Big.
+        mtu: Option<u32>,
+        message: String,
+        /// Time from sending the probe to the error arriving.
+        rtt: Duration,
+    },
+    SendFailed {
+        seq: u16,
+        error: std::io::Error,
+    },
+    /// The probe was sent, but what would answer it can no longer be received.
+    ReceiveFailed {
+        seq: u16,
+        error: std::io::Error,
+    },
+}
+
+/// Stops a running `Pinger`.
+#[derive(Debug, Clone)]
+pub struct PingHandle {
+    stop: Arc<StopSignal>,
+}
+
+#[derive(Debug, Default)]
+struct StopSignal {
+    stopped: AtomicBool,
+    finishing: AtomicBool,
+    // probes sent so far, for whoever holds a handle
+    sent: AtomicU32,
+    notify: Notify,
+}
+
+impl PingHandle {
+    /// Stops at once. Probes already in flight are abandoned.
+    pub fn stop(&self) {
+        self.stop.stopped.store(true, Ordering::SeqCst);
+        self.stop.notify.notify_one();
+    }
+
+    /// Stops sending, and stops once the probes in flight are answered or
+    /// time out.
+    pub fn finish(&self) {
+        self.stop.finishing.store(true, Ordering::SeqCst);
+        self.stop.notify.notify_one();
+    }
+
+    /// How many probes the pinger has sent.
+    pub fn sent(&self) -> u32 {
+        self.stop.sent.load(Ordering::SeqCst)
+    }
+
+    pub fn is_running(&self) -> bool {
+        !self.stop.stopped.load(Ordering::SeqCst)
+    }
+}
+
+/// When a probe left, and how that time was measured.
+#[derive(Debug, Clone, Copy)]
+struct Probe {
+    sent_at: Instant,
+    timestamp: TimestampSource,
+}
+
+#[derive(Debug)]
+pub struct Pinger {
+    config: PingConfig,
+    source: IpAddr,
+    icmp_id: u16,
+    // largest datagram the route to the destination takes, for fragmenting ourselves,
+    // lowered by the path MTU routers report
+    route_mtu: Option<usize>,
+    session: Arc<Session>,
+    transport: Arc<dyn IcmpTransport>,
+    stop: Arc<StopSignal>,
+}
+
+impl Pinger {
+    pub fn new(config: PingConfig) -> Result<Pinger, PingError> {
+        Pinger::with_session(config, Arc::new(Session::new()))
+    }
+
+    /// Creates a pinger that sends and receives through a shared `session`.
+    pub fn with_session(config: PingConfig, session: Arc<Session>) -> Result<Pinger, PingError> {
+        if let IpAddr::V6(destination) = config.destination {
+            if ip::is_link_local(&destination) && config.scope_id == 0 {
+                return Err(PingError::new(format!(
+                    "Link-local destination {} needs a zone, such as {}%eth0",
+                    destination, destination
+                )));
+            }
+        }
+        let max_payload_size = match config.destination {
+            IpAddr::V4(_) => icmp::MAX_IPV4_PAYLOAD_SIZE,
+            IpAddr::V6(_) => icmp::MAX_IPV6_PAYLOAD_SIZE,
+        };
+        if config.payload_size > max_payload_size {
+            return Err(PingError::new(format!(
+                "A {} byte payload doesn't fit in a packet to {}, which carries at most {}",
+                config.payload_size, config.destination, max_payload_size
+            )));
+        }
+        let transport = session.transport(config.destination)?;
+        let source = match config.source {
+            Some(source) if source.is_ipv4() != config.destination.is_ipv4() => {
+                return Err(PingError::new(format!(
+                    "Source {} and destination {} are different address families",
+                    source, config.destination
+                )));
+            }
+            Some(source) => source,
+            None => match transport.source_for(config.destination) {
+                Some(source) => source,
+                None => machine_source(config.destination, config.scope_id)?,
+            },
+        };
+        let fragments_ourselves = config.destination.is_ipv4() && transport.header_included();
+        if config.fragment_size.is_some() && !fragments_ourselves {
+            return Err(PingError::new(
+                "Custom fragments need IPv4 and a raw socket that carries our own IP header"
+                    .to_string(),
+            ));
+        }
+        let route_mtu = if fragments_ourselves {
+            transport.mtu_for(config.destination)
+        } else {
+            None
+        };
+        // pingers sharing the session tell their replies apart by identifier, and
+        // datagram sockets answer to the one the kernel picked, not ours
+        let (transport, icmp_id) = session.lease(config.destination, get_icmp_id(config.id))?;
+
+        Ok(Pinger {
+            icmp_id,
+            route_mtu,
+            config,
+            source,
+            session,
+            transport,
+            stop: Arc::new(StopSignal::default()),
+        })
+    }
+
+    pub fn source(&self) -> IpAddr {
+        self.source
+    }
+
+    pub fn destination(&self) -> IpAddr {
+        self.config.destination
+    }
+
+    pub fn handle(&self) -> PingHandle {
+        PingHandle {
+            stop: self.stop.clone(),
+        }
+    }
+
+    /// Starts pinging in the background. The stream ends once every probe has
+    /// been answered or timed out, or the pinger was stopped.
+    pub fn run(self) -> impl Stream<Item = PingEvent> {
+        let (events, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(self.drive(events));
+        UnboundedReceiverStream::new(receiver)
+    }
+
+    async fn drive(mut self, events: mpsc::UnboundedSender<PingEvent>) {
+        let (waiter, mut incoming) = mpsc::unbounded_channel();
+        let count = self.config.count.map(u32::from);
+        // sequence numbers wrap when pinging until stopped
+        let mut next_seq: u32 = 0;
+        let mut next_send = Instant::now();
+        // probes still waiting for a reply, by sequence number
+        let mut in_flight: HashMap<u16, Probe> = HashMap::new();
+        // answered probes stay registered until their timeout to catch duplicates
+        let mut answered: HashMap<u16, Probe> = HashMap::new();
+
+        while !self.stop.stopped.load(Ordering::SeqCst) && !events.is_closed() {
+            let sending = count.is_none_or(|count| next_seq < count)
+                && !self.stop.finishing.load(Ordering::SeqCst);
+            if !sending && in_flight.is_empty() && answered.is_empty() {
+                break;
+            }
+            let next_expiry = in_flight
+                .values()
+                .chain(answered.values())
+                .map(|probe| probe.sent_at)
+                .min()
+                .map(|sent_at| sent_at + self.config.timeout);
+
+            tokio::select! {
+                _ = wait_until(next_send), if sending => {
+                    let seq = next_seq as u16;
+                    next_seq = next_seq.wrapping_add(1);
+                    next_send += self.config.interval;
+                    // a wrapped sequence number still waiting for duplicates is done
+                    if answered.remove(&seq).is_some() {
+                        self.session.unregister((self.icmp_id, seq));
+                    }
+                    self.stop.sent.fetch_add(1, Ordering::SeqCst);
+
+                    self.session
+                        .register(&self.transport, (self.icmp_id, seq), waiter.clone());
+                    let _ = events.send(PingEvent::Sent {
+                        seq,
+                        destination: self.config.destination,
+                    });
+                    let sent_at = Instant::now();
+                    match self.send_echo_request(seq).await {
+                        Ok(_) => {
+                            // replaced by the kernel's send time if it reports one
+                            let probe = Probe {
+                                sent_at,
+                                timestamp: TimestampSource::Userspace,
+                            };
+                            in_flight.insert(seq, probe);
+                        }
+                        Err(error) => {
+                            self.session.unregister((self.icmp_id, seq));
+                            let _ = events.send(PingEvent::SendFailed { seq, error });
+                        }
+                    }
+                }
+                Some(received) = incoming.recv() => match received {
+                    Received::SendTimestamp(sent) => {
+                        if let Some(probe) = in_flight.get_mut(&sent.seq_num) {
+                            probe.sent_at = sent.sent_at;
+                            probe.timestamp = sent.timestamp;
+                        }
+                    }
+                    Received::Failed { seq_num: seq, error, .. } => {
+                        if in_flight.remove(&seq).is_some() {
+                            self.session.unregister((self.icmp_id, seq));
+                            let error = std::io::Error::new(error.kind(), error.to_string());
+                            let _ = events.send(PingEvent::ReceiveFailed { seq, error });
+                        }
+                    }
+                    Received::Message(message) => {
+                        let seq = message.seq_num;
+                        if let Some(mtu) = message.mtu.filter(|_| self.fragments_ourselves()) {
+                            let mtu = mtu as usize;
+                            self.route_mtu = Some(self.route_mtu.map_or(mtu, |known| known.min(mtu)));
+                        }
+                        if let Some(probe) = in_flight.get(&seq).copied() {
+                            if message.is_echo_reply() {
+                                in_flight.remove(&seq);
+                                answered.insert(seq, probe);
+                                if self.config.flood {
+                                    next_send = next_send.min(Instant::now());
+                                }
+                            }
+                            let _ = events.send(message_to_event(message, probe, false));
+                        } else if let Some(probe) = answered.get(&seq).copied() {
+                            if message.is_echo_reply() {
+                                let _ = events.send(message_to_event(message, probe, true));
+                            }
+                        }
+                    }
+                },
+                _ = sleep_until(next_expiry.unwrap_or_else(Instant::now)), if next_expiry.is_some() => {
+                    let now = Instant::now();
+                    let timeout = self.config.timeout;
+                    in_flight.retain(|seq, probe| {
+                        if probe.sent_at + timeout > now {
+                            return true;
+                        }
+                        self.session.unregister((self.icmp_id, *seq));
+                        let _ = events.send(PingEvent::Timeout { seq: *seq });
+                        false
+                    });
+                    answered.retain(|seq, probe| {
+                        if probe.sent_at + timeout > now {
+                            return true;
+                        }
+                        self.session.unregister((self.icmp_id, *seq));
+                        false
+                    });
+                }
+                _ = self.stop.notify.notified() => {}
+            }
+        }
+
+        for seq in in_flight.keys().chain(answered.keys()) {
+            self.session.unregister((self.icmp_id, *seq));
+        }
+    }
+
+    /// Whether IPv4 probes carry a header we build, which the kernel won't fragment.
+    fn fragments_ourselves(&self) -> bool {
+        self.config.destination.is_ipv4() && self.transport.header_included()
+    }
+
+    async fn send_echo_request(&self, seq: u16) -> std::io::Result<usize> {
+        let config = &self.config;
+        let without_ip_header = !self.transport.header_included();
+        let payload_size = if config.include_payload {
+            config.payload_size
+        } else {
+            0
+        };
+        // the kernel sends a header we build as it is, so -M is up to us; 28 bytes
+        // of IPv4 and ICMP headers come on top of the payload
+        let fits = self.route_mtu.is_none_or(|mtu| 28 + payload_size <= mtu);
+        let dont_fragment = match config.mtu_discovery {
+            None | Some(MtuDiscovery::Dont) => false,
+            Some(MtuDiscovery::Want) => fits,
+            Some(MtuDiscovery::Do) if !fits => {
+                return Err(std::io::Error::from_raw_os_error(libc::EMSGSIZE));
+            }
+            Some(MtuDiscovery::Do | MtuDiscovery::Probe) => true,
+        };
+        let flow = config.flow.map(|flow| flow.for_probe(seq));
+        let (packet, ttl) = match config.destination {
+            IpAddr::V4(_) => (
+                icmp::IPV4Packet::new_echo_request(
+                    without_ip_header,
+                    self.source,
+                    config.destination,
+                    self.icmp_id,
+                    config.ttl,
+                    config.include_payload,
+                    seq,
+                )
+                .with_payload_size(payload_size)
+                .with_dont_fragment(dont_fragment)
+                .with_tos(config.tos)
+                .with_checksum(flow)
+                .serialize(),
+                config.ttl,
+            ),
+            IpAddr::V6(_) => (
+                // the kernel always builds the IPv6 header
+                icmp::IPV6Packet::new_echo_request(
+                    true,
+                    self.source,
+                    config.destination,
+                    self.icmp_id,
+                    config.hop_limit,
+                    config.include_payload,
+                    seq,
+                )
+                .with_payload_size(payload_size)
+                .with_checksum(flow, self.source, config.destination)
+                .serialize(),
+                config.hop_limit,
+            ),
+        };
+        let options = SendOptions {
+            ttl,
+            tos: config.tos,
+            mtu_discovery: config.mtu_discovery,
+            scope_id: config.scope_id,
+            flow_label: match (config.destination, flow) {
+                (IpAddr::V6(_), Some(flow)) => Flow::label(flow),
+                _ => 0,
+            },
+            timeout: config.timeout,
+        };
+        let fragment_size = match config.fragment_size {
+            Some(size) => Some(size),
+            // the kernel doesn't fragment datagrams that carry our own header
+            None if config.destination.is_ipv4() && !dont_fragment => self
+                .route_mtu
+                .filter(|mtu| packet.len() > *mtu)
+                .map(|mtu| mtu.saturating_sub(20)),
+            None => None,
+        };
+        let Some(fragment_size) = fragment_size else {
+            return self
+                .transport
+                .send_to(&packet, config.destination, options)
+                .await;
+        };
+        let mut sent = 0;
+        for fragment in fragment::fragment(&packet, fragment_size, config.fragment_overlap) {
+            sent += self
+                .transport
+                .send_to(&fragment, config.destination, options)
+                .await?;
+        }
+        Ok(sent)
+    }
+}
+
+impl Drop for Pinger {
+    fn drop(&mut self) {
+        self.session.release(self.icmp_id);
+    }
+}
+
+/// Like `sleep_until`, but returns at once for a deadline that has passed
+/// rather than at the timer's next millisecond tick, which would cap flood
+/// pinging at about a thousand probes a second.
+async fn wait_until(deadline: Instant) {
+    if deadline > Instant::now() {
+        sleep_until(deadline).await;
+    }
+}
+
+fn machine_source(destination: IpAddr, scope_id: u32) -> Result<IpAddr, PingError> {
+    match destination {
+        IpAddr::V6(destination) if scope_id != 0 => ip::scoped_source(destination, scope_id)
+            .map(IpAddr::V6)
+            .ok_or_else(|| {
+                PingError::new(format!(
+                    "Couldn't find a link-local address on {} to reach {}",
+                    ip::scope_name(scope_id),
+                    destination
+                ))
+            }),
+        IpAddr::V4(destination) => ip::get_machine_ipv4(destination)
+            .map(IpAddr::V4)
+            .ok_or_else(|| {
+                PingError::new(
+                    "Couldn't find a suitable IPv4 address. Please check your network configuration."
+                        .to_string(),
+                )
+            }),
+        IpAddr::V6(destination) => ip::get_machine_ipv6(destination)
+            .map(IpAddr::V6)
+            .ok_or_else(|| {
+                PingError::new(
+                    "Couldn't find a suitable IPv6 address. Please check your network configuration."
+                        .to_string(),
+                )
+            }),
+    }
+}
+
+fn message_to_event(message: Incoming, probe: Probe, duplicate: bool) -> PingEvent {
+    let rtt = message.received_at.saturating_duration_since(probe.sent_at);
+
+    if message.is_echo_reply() {
+        let reply = PingReply {
+            seq: message.seq_num,
+            source: message.source,
+            bytes: message.bytes,
+            rtt,
+            ttl: message.ttl,
+            tos: message.tos,
+            send_timestamp: probe.timestamp,
+            receive_timestamp: message.timestamp,
+        };
+        if duplicate {
+            PingEvent::Duplicate(reply)
+        } else {
+            PingEvent::Reply(reply)
+        }
+    } else {
+        let description = match message.source {
+            IpAddr::V4(_) => icmp::describe_icmpv4_error(message.msg_type, message.code),
+            IpAddr::V6(_) => icmp::describe_icmpv6_error(message.msg_type, message.code),
+        };
+        let description = match message.mtu {
+            Some(mtu) => format!("{} (mtu = {})", description, mtu),
+            None => description,
+        };
+        PingEvent::IcmpError {
+            seq: message.seq_num,
+            source: message.source,
+            msg_type: message.msg_type,
+            code: message.code,
+            mtu: message.mtu,
+            message: description,
+            rtt,
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use std::{net::IpAddr, sync::Arc, time::Duration};
+
+use tokio_stream::StreamExt;
+
+use crate::{
+    error::PingError,
+    icmp,
+    pinger::{PingConfig, PingEvent, Pinger},
+    session::Session,
+    transport::MtuDiscovery,
+};
+
+/// What a single "don't fragment" probe of some payload size found out.
+#[derive(Debug, Clone)]
+pub enum PmtuProbe {
+    /// The probe reached the target.
+    Fits { rtt: Duration },
+    /// A router, or our own interface when `from` is `None`, refused it as too
+    /// big. Routers usually advertise the MTU of their next hop.
+    TooBig {
+        from: Option<IpAddr>,
+        mtu: Option<u32>,
+    },
+    /// Nothing came back, which usually means a router dropped the probe without
+    /// saying why.
+    Lost,
+}
+
+/// Binary-searches the largest echo request that reaches `config.destination`
+/// without fragmentation and returns it as the path MTU, in bytes including the
+/// IP header. Advertised MTUs jump the search straight to the right size.
+///
+/// `on_probe` is told the payload size and outcome of every probe as it completes.
+pub async fn discover(
+    config: PingConfig,
+    session: Arc<Session>,
+    mut on_probe: impl FnMut(usize, &PmtuProbe),
+) -> Result<usize, PingError> {
+    let overhead = match config.destination {
+        IpAddr::V4(_) => icmp::IPV4_ECHO_OVERHEAD,
+        IpAddr::V6(_) => icmp::IPV6_ECHO_OVERHEAD,
+    };
+
+    let smallest = probe_with_retry(&config, &session, 0, &mut on_probe).await?;
+    if !matches!(smallest, PmtuProbe::Fits { .. }) {
+        return Err(PingError::new(format!(
+            "{} doesn't answer even the smallest probe",
+            config.destination
+        )));
+    }
+
+    // largest payload known to fit, and largest not yet ruled out
+    let mut fits = 0;
+    let mut limit = u16::MAX as usize - overhead;
+    let mut hint = None;
+    while fits < limit {
+        let size = match hint.take() {
+            Some(size) if size > fits && size <= limit => size,
+            _ => fits + (limit - fits).div_ceil(2),
+        };
+        match probe_with_retry(&config, &session, size, &mut on_probe).await? {
+            PmtuProbe::Fits { .. } => fits = size,
+            PmtuProbe::TooBig { mtu, .. } => {
+                limit = size - 1;
+                // nothing bigger than the advertised MTU gets past that router,
+                // and the advertised size itself is the likeliest to fit
+                if let Some(mtu) = mtu {
+                    let advertised = (mtu as usize).saturating_sub(overhead);
+                    limit = limit.min(advertised);
+                    hint = Some(advertised);
+                }
+            }
+            PmtuProbe::Lost => limit = size - 1,
+        }
+    }
+    Ok(fits + overhead)
+}
+
+/// Probes once, and again if the first probe went unanswered, so a single lost
+/// packet doesn't shrink the result.
+async fn probe_with_retry(
+    config: &PingConfig,
+    session: &Arc<Session>,
+    size: usize,
+    on_probe: &mut impl FnMut(usize, &PmtuProbe),
+) -> Result<PmtuProbe, PingError> {
+    let mut outcome = probe(config, session, size).await?;
+    if matches!(outcome, PmtuProbe::Lost) {
+        outcome = probe(config, session, size).await?;
+    }
+    on_probe(size, &outcome);
+    Ok(outcome)
+}
+
+async fn probe(
+    config: &PingConfig,
+    session: &Arc<Session>,
+    size: usize,
+) -> Result<PmtuProbe, PingError> {
+    let mut config = config.clone();
+    config.count = Some(1);
+    config.include_payload = true;
+    config.payload_size = size;
+    // ignore what the kernel already learned about the path
+    config.mtu_discovery = Some(MtuDiscovery::Probe);
+
+    let pinger = Pinger::with_session(config, session.clone())?;
+    let handle = pinger.handle();
+    let mut events = Box::pin(pinger.run());
+    let mut outcome = PmtuProbe::Lost;
+    // stop at the first answer rather than waiting out duplicates, but drain the
+    // stream so the probe is unregistered before the next one reuses its sequence
+    while let Some(event) = events.next().await {
+        match event {
+            PingEvent::Reply(reply) => outcome = PmtuProbe::Fits { rtt: reply.rtt },
+            PingEvent::IcmpError {
+                source,
+                mtu,
+                msg_type,
+                code,
+                ..
+            } if mtu.is_some() || is_too_big(msg_type, code) => {
+                outcome = PmtuProbe::TooBig {
+                    from: Some(source),
+                    mtu,
+                }
+            }
+            PingEvent::SendFailed { error, .. } if error.raw_os_error() == Some(libc::EMSGSIZE) => {
+                outcome = PmtuProbe::TooBig {
+                    from: None,
+                    mtu: None,
+                }
+            }
+            _ => continue,
+        }
+        handle.stop();
+    }
+    Ok(outcome)
+}
+
+fn is_too_big(msg_type: u8, code: u8) -> bool {
+    (msg_type == icmp::ICMPV4_DESTINATION_UNREACHABLE && code == 4)
+        || msg_type == icmp::ICMPV6_PACKET_TOO_BIG
+}
@@ -0,0 +1,126 @@
+//! Reverse DNS for the addresses replies and ICMP errors come from.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{
+    task::{self, JoinHandle, JoinSet},
+    time::timeout,
+};
+
+use crate::ip::{Resolve, SystemResolver};
+
+/// How long output waits for a name before showing the bare address.
+pub const LOOKUP_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Looks up PTR names off the runtime's threads and remembers them, including
+/// addresses that have none.
+#[derive(Debug, Clone)]
+pub struct ReverseResolver {
+    resolver: Arc<dyn Resolve>,
+    timeout: Duration,
+    // `None` while a lookup is running and for addresses without a name
+    names: Arc<Mutex<HashMap<IpAddr, Option<String>>>>,
+}
+
+impl ReverseResolver {
+    /// Looks names up with `resolver`, waiting up to `timeout` for each.
+    pub fn new(resolver: Arc<dyn Resolve>, timeout: Duration) -> ReverseResolver {
+        ReverseResolver {
+            resolver,
+            timeout,
+            names: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// The name of `address`, if it has one and it arrives within the timeout.
+    /// Each address is looked up once; an answer that comes too late is still
+    /// remembered for next time.
+    pub async fn lookup(&self, address: IpAddr) -> Option<String> {
+        let lookup = match self.start(address) {
+            Ok(name) => return name,
+            Err(lookup) => lookup,
+        };
+        timeout(self.timeout, lookup).await.ok()?.ok()?
+    }
+
+    /// Looks up every address at once, so describing them afterwards takes as
+    /// long as the slowest lookup rather than all of them.
+    pub async fn prefetch(&self, addresses: impl IntoIterator<Item = IpAddr>) {
+        let mut lookups = JoinSet::new();
+        for address in addresses {
+            let resolver = self.clone();
+            lookups.spawn(async move { resolver.lookup(address).await });
+        }
+        while lookups.join_next().await.is_some() {}
+    }
+
+    /// `host.example (192.0.2.1)`, or just the address if it has no name.
+    pub async fn describe(&self, address: IpAddr) -> String {
+        describe(self.lookup(address).await, address)
+    }
+
+    /// Like `describe`, but never waits: an address whose name hasn't arrived
+    /// yet is shown bare while it's looked up in the background.
+    pub fn describe_now(&self, address: IpAddr) -> String {
+        describe(self.start(address).ok().flatten(), address)
+    }
+
+    /// The name `address` is known by, or the lookup this starts for it.
+    fn start(&self, address: IpAddr) -> Result<Option<String>, JoinHandle<Option<String>>> {
+        {
+            let mut names = self.names.lock().unwrap();
+            if let Some(name) = names.get(&address) {
+                return Ok(name.clone());
+            }
+            names.insert(address, None);
+        }
+        let resolver = self.resolver.clone();
+        let names = self.names.clone();
+        Err(task::spawn_blocking(move || {
+            let name = resolver.reverse(address);
+            names.lock().unwrap().insert(address, name.clone());
+            name
+        }))
+    }
+}
+
+impl Default for ReverseResolver {
+    fn default() -> Self {
+        ReverseResolver::new(Arc::new(SystemResolver), LOOKUP_TIMEOUT)
+    }
+}
+
+fn describe(name: Option<String>, address: IpAddr) -> String {
+    match name {
+        Some(name) => format!("{} ({})", name, address),
+        None => address.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulate::SimulatedNetwork;
+
+    #[tokio::test]
+    async fn it_names_addresses_and_remembers_them() {
+        let network = Arc::new(SimulatedNetwork::new(7));
+        let address: IpAddr = "192.0.2.7".parse().unwrap();
+        network.add_name("host.example", vec![address]);
+        let resolver = ReverseResolver::new(network, LOOKUP_TIMEOUT);
+
+        let name = resolver.lookup(address).await;
+        assert_eq!(name.as_deref(), Some("host.example"));
+        assert_eq!(resolver.names.lock().unwrap().get(&address), Some(&name));
+        assert_eq!(resolver.describe(address).await, "host.example (192.0.2.7)");
+
+        let unnamed = "192.0.2.8".parse().unwrap();
+        assert_eq!(resolver.describe_now(unnamed), "192.0.2.8");
+        assert_eq!(resolver.describe(unnamed).await, "192.0.2.8");
+    }
+}
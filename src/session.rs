@@ -0,0 +1,313 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
+use tokio::{sync::mpsc, task::AbortHandle};
+
+use crate::{
+    error::SocketError,
+    ip::{BindTarget, Resolve, SystemResolver},
+    socket::IcmpSocket,
+    tcp::TcpProbeSocket,
+    transport::{IcmpTransport, ProbeProtocol, Received},
+    udp::UdpProbeSocket,
+};
+
+/// Identifier and sequence number of an echo request.
+pub type ProbeKey = (u16, u16);
+
+/// Tells transports apart without holding on to them.
+type TransportKey = usize;
+
+fn transport_key(transport: &Arc<dyn IcmpTransport>) -> TransportKey {
+    Arc::as_ptr(transport) as *const () as TransportKey
+}
+
+/// Probes waiting for an answer, keyed by the id and sequence number they were sent with.
+#[derive(Debug, Default)]
+struct PendingProbes {
+    // each with the transport it was sent through
+    probes: Mutex<HashMap<ProbeKey, (TransportKey, mpsc::UnboundedSender<Received>)>>,
+    // why a receiver stopped, by its transport; probes registered against it since fail at once
+    failed: Mutex<HashMap<TransportKey, Arc<io::Error>>>,
+}
+
+impl PendingProbes {
+    fn dispatch(&self, received: Received) {
+        let probes = self.probes.lock().unwrap();
+        if let Some((_, waiter)) = probes.get(&received.key()) {
+            let _ = waiter.send(received);
+        }
+    }
+
+    /// Fails every probe sent through `transport`, waiting now or registered later, with `error`.
+    fn fail(&self, transport: TransportKey, error: io::Error) {
+        let error = Arc::new(error);
+        let probes = self.probes.lock().unwrap();
+        self.failed.lock().unwrap().insert(transport, error.clone());
+        for (&(id, seq_num), (_, waiter)) in probes
+            .iter()
+            .filter(|(_, (sent_through, _))| *sent_through == transport)
+        {
+            let _ = waiter.send(Received::Failed {
+                id,
+                seq_num,
+                error: error.clone(),
+            });
+        }
+    }
+}
+
+/// Owns one transport per address family and a receiver task for each, which
+/// hands incoming messages and send timestamps to whichever probe registered
+/// their (id, seq).
+///
+/// A session can be shared by any number of pingers. Its receivers stop when it
+/// is dropped, or when their socket fails for good, which fails the probes sent
+/// through that socket.
+#[derive(Debug)]
+pub struct Session {
+    header_included: bool,
+    bind: Option<BindTarget>,
+    protocol: ProbeProtocol,
+    resolver: Arc<dyn Resolve>,
+    ipv4: Mutex<Option<Arc<dyn IcmpTransport>>>,
+    ipv6: Mutex<Option<Arc<dyn IcmpTransport>>>,
+    // more datagram sockets, opened when pingers need more identifiers than one per family
+    spares: Mutex<Vec<(bool, Arc<dyn IcmpTransport>)>>,
+    // echo identifiers leased to live pingers
+    identifiers: Mutex<HashSet<u16>>,
+    pending: Arc<PendingProbes>,
+    receivers: Mutex<Vec<AbortHandle>>,
+}
+
+impl Session {
+    pub fn new() -> Session {
+        Session {
+            header_included: std::env::consts::OS != "macos",
+            bind: None,
+            protocol: ProbeProtocol::Icmp,
+            resolver: Arc::new(SystemResolver),
+            ipv4: Mutex::new(None),
+            ipv6: Mutex::new(None),
+            spares: Mutex::new(Vec::new()),
+            identifiers: Mutex::new(HashSet::new()),
+            pending: Arc::new(PendingProbes::default()),
+            receivers: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Binds the sockets this session opens to a local address or an interface.
+    pub fn bound_to(mut self, target: BindTarget) -> Session {
+        self.bind = Some(target);
+        self
+    }
+
+    /// Sends probes as `protocol` through the sockets this session opens.
+    pub fn probing(mut self, protocol: ProbeProtocol) -> Session {
+        self.protocol = protocol;
+        self
+    }
+
+    /// What the sockets this session opens send probes as.
+    pub fn protocol(&self) -> ProbeProtocol {
+        self.protocol
+    }
+
+    /// Looks up hostnames with `resolver` instead of the system's.
+    pub fn resolving_with(mut self, resolver: Arc<dyn Resolve>) -> Session {
+        self.resolver = resolver;
+        self
+    }
+
+    /// What hostnames of this session's targets are looked up with.
+    pub fn resolver(&self) -> Arc<dyn Resolve> {
+        self.resolver.clone()
+    }
+
+    /// A session that sends both address families through `transport` instead of
+    /// opening sockets. Must be called from within a tokio runtime.
+    pub fn with_transport(transport: Arc<dyn IcmpTransport>) -> Session {
+        let session = Session::sending_through(transport.clone(), transport.clone());
+        session.start_receiver(transport);
+        session
+    }
+
+    /// A session that sends each address family through a transport of its own
+    /// instead of opening sockets. Must be called from within a tokio runtime.
+    pub fn with_transports(ipv4: Arc<dyn IcmpTransport>, ipv6: Arc<dyn IcmpTransport>) -> Session {
+        let session = Session::sending_through(ipv4.clone(), ipv6.clone());
+        session.start_receiver(ipv4);
+        session.start_receiver(ipv6);
+        session
+    }
+
+    fn sending_through(ipv4: Arc<dyn IcmpTransport>, ipv6: Arc<dyn IcmpTransport>) -> Session {
+        Session {
+            header_included: ipv4.header_included(),
+            bind: None,
+            protocol: ProbeProtocol::Icmp,
+            resolver: Arc::new(SystemResolver),
+            ipv4: Mutex::new(Some(ipv4)),
+            ipv6: Mutex::new(Some(ipv6)),
+            spares: Mutex::new(Vec::new()),
+            identifiers: Mutex::new(HashSet::new()),
+            pending: Arc::new(PendingProbes::default()),
+            receivers: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Returns the transport used to reach `destination`, opening a socket and
+    /// starting its receiver on first use. Must be called from within a tokio runtime.
+    pub fn transport(&self, destination: IpAddr) -> Result<Arc<dyn IcmpTransport>, SocketError> {
+        let slot = match destination {
+            IpAddr::V4(_) => &self.ipv4,
+            IpAddr::V6(_) => &self.ipv6,
+        };
+        let mut slot = slot.lock().unwrap();
+        if let Some(ref transport) = *slot {
+            return Ok(transport.clone());
+        }
+
+        let transport = self.open(destination)?;
+        *slot = Some(transport.clone());
+        Ok(transport)
+    }
+
+    /// Reserves an echo identifier no other pinger of this session is using, and
+    /// returns it with the transport to send it through. `preferred` is used if
+    /// it's free and the transport lets us choose. Give it back with `release`.
+    pub fn lease(
+        &self,
+        destination: IpAddr,
+        preferred: u16,
+    ) -> Result<(Arc<dyn IcmpTransport>, u16), SocketError> {
+        let transport = self.transport(destination)?;
+        let mut leased = self.identifiers.lock().unwrap();
+        let Some(identifier) = transport.identifier() else {
+            let identifier = (0..=u16::MAX)
+                .map(|offset| preferred.wrapping_add(offset))
+                .find(|identifier| !leased.contains(identifier))
+                .ok_or_else(|| SocketError::new("Every ICMP identifier is in use".to_string()))?;
+            leased.insert(identifier);
+            return Ok((transport, identifier));
+        };
+        if leased.insert(identifier) {
+            return Ok((transport, identifier));
+        }
+
+        // the kernel picks one identifier per datagram socket, so another pinger
+        // needs another socket
+        let is_ipv6 = destination.is_ipv6();
+        let mut spares = self.spares.lock().unwrap();
+        for (family, spare) in spares.iter() {
+            match spare.identifier() {
+                Some(identifier) if *family == is_ipv6 && leased.insert(identifier) => {
+                    return Ok((spare.clone(), identifier));
+                }
+                _ => {}
+            }
+        }
+        let spare = self.open(destination)?;
+        let identifier = spare.identifier().unwrap_or(preferred);
+        leased.insert(identifier);
+        spares.push((is_ipv6, spare.clone()));
+        Ok((spare, identifier))
+    }
+
+    /// Returns an identifier taken with `lease`.
+    pub fn release(&self, identifier: u16) {
+        self.identifiers.lock().unwrap().remove(&identifier);
+    }
+
+    fn open(&self, destination: IpAddr) -> Result<Arc<dyn IcmpTransport>, SocketError> {
+        let bind = self.bind.as_ref();
+        let transport: Arc<dyn IcmpTransport> = match (self.protocol, destination) {
+            (ProbeProtocol::Icmp, IpAddr::V4(_)) => {
+                Arc::new(IcmpSocket::open_ipv4(self.header_included, bind)?)
+            }
+            (ProbeProtocol::Icmp, IpAddr::V6(_)) => Arc::new(IcmpSocket::open_ipv6(bind)?),
+            (ProbeProtocol::Udp { port }, destination) => {
+                Arc::new(UdpProbeSocket::open(destination.is_ipv6(), port, bind)?)
+            }
+            (ProbeProtocol::Tcp { port }, destination) => {
+                Arc::new(TcpProbeSocket::open(destination.is_ipv6(), port, bind)?)
+            }
+        };
+        self.start_receiver(transport.clone());
+        Ok(transport)
+    }
+
+    fn start_receiver(&self, transport: Arc<dyn IcmpTransport>) {
+        let receiver = tokio::spawn(receive(transport, self.pending.clone()));
+        self.receivers.lock().unwrap().push(receiver.abort_handle());
+    }
+
+    /// Routes messages and send timestamps for the echo request `key`, sent
+    /// through `transport`, to `waiter` until unregistered.
+    pub fn register(
+        &self,
+        transport: &Arc<dyn IcmpTransport>,
+        key: ProbeKey,
+        waiter: mpsc::UnboundedSender<Received>,
+    ) {
+        let transport = transport_key(transport);
+        let mut probes = self.pending.probes.lock().unwrap();
+        if let Some(error) = self.pending.failed.lock().unwrap().get(&transport) {
+            let _ = waiter.send(Received::Failed {
+                id: key.0,
+                seq_num: key.1,
+                error: error.clone(),
+            });
+        }
+        probes.insert(key, (transport, waiter));
+    }
+
+    pub fn unregister(&self, key: ProbeKey) {
+        self.pending.probes.lock().unwrap().remove(&key);
+    }
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Session::new()
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        for receiver in self.receivers.lock().unwrap().drain(..) {
+            receiver.abort();
+        }
+    }
+}
+
+async fn receive(transport: Arc<dyn IcmpTransport>, pending: Arc<PendingProbes>) {
+    let key = transport_key(&transport);
+    loop {
+        match transport.recv().await {
+            Ok(Some(received)) => pending.dispatch(received),
+            Ok(None) => {}
+            Err(e) if is_transient(&e) => {}
+            Err(e) => {
+                pending.fail(key, e);
+                break;
+            }
+        }
+    }
+}
+
+/// Whether a receive error leaves the socket usable. Datagram sockets with
+/// `IP_RECVERR` set report a queued ICMP error from the next `recv`, for example.
+fn is_transient(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+    ) || matches!(
+        error.raw_os_error(),
+        Some(libc::EHOSTUNREACH | libc::ECONNREFUSED | libc::ENETUNREACH | libc::EMSGSIZE)
+    )
+}
@@ -0,0 +1,521 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::Mutex,
+    time::Duration,
+};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokio::{
+    sync::mpsc,
+    time::{sleep, Instant},
+};
+
+use crate::{
+    fragment::{fragment, is_fragment, Reassembler},
+    icmp::{self, ICMPHeader},
+    ip::Resolve,
+    socket::{parse_icmpv4, parse_icmpv6},
+    transport::{BoxFuture, IcmpTransport, MtuDiscovery, Received, SendOptions},
+};
+
+/// Address the simulated network sends IPv4 probes from.
+pub const SIMULATED_SOURCE_V4: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+/// Address the simulated network sends IPv6 probes from.
+pub const SIMULATED_SOURCE_V6: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+
+/// How long a simulated host takes to answer.
+#[derive(Debug, Clone, Copy)]
+pub enum Latency {
+    Fixed(Duration),
+    Uniform { min: Duration, max: Duration },
+    Normal { mean: Duration, std_dev: Duration },
+}
+
+impl Latency {
+    fn sample(&self, rng: &mut StdRng) -> Duration {
+        match *self {
+            Latency::Fixed(latency) => latency,
+            Latency::Uniform { min, max } if min < max => rng.gen_range(min..=max),
+            Latency::Uniform { min, .. } => min,
+            Latency::Normal { mean, std_dev } => {
+                // Box-Muller transform
+                let u1: f64 = rng.gen_range(f64::EPSILON..1.0);
+                let u2: f64 = rng.gen();
+                let z = (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos();
+                let secs = mean.as_secs_f64() + z * std_dev.as_secs_f64();
+                Duration::from_secs_f64(secs.max(0.0))
+            }
+        }
+    }
+}
+
+/// An ICMP error a simulated host answers with instead of an echo reply.
+#[derive(Debug, Clone, Copy)]
+pub struct SimulatedError {
+    /// The router reporting the error.
+    pub from: IpAddr,
+    pub msg_type: u8,
+    pub code: u8,
+}
+
+/// A link on the way to a simulated host that is narrower than the rest of the path.
+#[derive(Debug, Clone, Copy)]
+pub struct SimulatedMtu {
+    /// The router in front of the link, which reports packets too big for it.
+    pub router: IpAddr,
+    pub mtu: u32,
+}
+
+/// How a simulated host treats echo requests. Probabilities are between 0 and 1.
+#[derive(Debug, Clone)]
+pub struct SimulatedHost {
+    pub latency: Latency,
+    pub loss: f64,
+    pub duplicate: f64,
+    /// Chance a reply is held back long enough to arrive after later ones.
+    pub reorder: f64,
+    /// Chance a reply arrives with a flipped byte.
+    pub corrupt: f64,
+    pub error: Option<SimulatedError>,
+    /// Routers between us and the host, each taking one off the reply's TTL.
+    pub hops: u8,
+    /// Routers on the way to the host, nearest first, with every router a load
+    /// balancer spreads flows over at that hop. The one a probe's TTL runs out
+    /// at answers with Time Exceeded.
+    pub route: Vec<Vec<IpAddr>>,
+    /// TOS byte the path rewrites replies to. Replies keep the request's otherwise.
+    pub remark: Option<u8>,
+    /// The narrowest link on the path, if any. Bigger IPv4 packets are fragmented
+    /// unless they forbid it; bigger IPv6 packets are always refused.
+    pub mtu: Option<SimulatedMtu>,
+    /// Split IPv4 replies into fragments carrying this many payload bytes, which
+    /// arrive last fragment first.
+    pub fragment_replies: Option<usize>,
+}
+
+impl Default for SimulatedHost {
+    fn default() -> Self {
+        SimulatedHost {
+            latency: Latency::Fixed(Duration::from_millis(10)),
+            loss: 0.0,
+            duplicate: 0.0,
+            reorder: 0.0,
+            corrupt: 0.0,
+            error: None,
+            hops: 0,
+            route: Vec::new(),
+            remark: None,
+            mtu: None,
+            fragment_replies: None,
+        }
+    }
+}
+
+/// A datagram on its way to us, with the TTL and TOS it arrives with.
+#[derive(Debug, Clone)]
+struct Delivery {
+    from: IpAddr,
+    datagram: Vec<u8>,
+    ttl: u8,
+    tos: u8,
+}
+
+/// An in-memory network that answers echo requests according to the hosts
+/// added to it, and resolves the names added to it. Given the same seed and
+/// the same probes it behaves the same way every time, and it runs on tokio's
+/// clock, so tests can pause time.
+#[derive(Debug)]
+pub struct SimulatedNetwork {
+    header_included: bool,
+    hosts: Mutex<HashMap<IpAddr, SimulatedHost>>,
+    // what hostnames resolve to, for sessions that resolve with this network; an
+    // address is named after the first of them, alphabetically, it belongs to
+    names: Mutex<HashMap<String, Vec<IpAddr>>>,
+    rng: Mutex<StdRng>,
+    // datagrams on their way to us, and receive errors to hand out between them
+    deliver: mpsc::UnboundedSender<io::Result<Delivery>>,
+    inbox: tokio::sync::Mutex<mpsc::UnboundedReceiver<io::Result<Delivery>>>,
+    // fragments of requests on their way to hosts, and of replies on their way to us
+    requests: Mutex<Reassembler>,
+    replies: Mutex<Reassembler>,
+}
+
+impl SimulatedNetwork {
+    pub fn new(seed: u64) -> SimulatedNetwork {
+        let (deliver, inbox) = mpsc::unbounded_channel();
+        SimulatedNetwork {
+            header_included: true,
+            hosts: Mutex::new(HashMap::new()),
+            names: Mutex::new(HashMap::new()),
+            rng: Mutex::new(StdRng::seed_from_u64(seed)),
+            deliver,
+            inbox: tokio::sync::Mutex::new(inbox),
+            requests: Mutex::new(Reassembler::default()),
+            replies: Mutex::new(Reassembler::default()),
+        }
+    }
+
+    /// Expect IPv4 probes without our IP header, like a macOS or datagram socket.
+    pub fn without_ip_header(mut self) -> SimulatedNetwork {
+        self.header_included = false;
+        self
+    }
+
+    /// Adds or replaces the host answering at `address`.
+    ///
+    /// Panics if a router on the way is of another address family than the
+    /// host, since it couldn't quote our probe in its errors.
+    pub fn add_host(&self, address: IpAddr, host: SimulatedHost) {
+        let routers = host
+            .route
+            .iter()
+            .flatten()
+            .copied()
+            .chain(host.error.map(|error| error.from))
+            .chain(host.mtu.map(|link| link.router));
+        for router in routers {
+            assert_eq!(
+                router.is_ipv4(),
+                address.is_ipv4(),
+                "router {} can't answer for {}",
+                router,
+                address
+            );
+        }
+        self.hosts.lock().unwrap().insert(address, host);
+    }
+
+    pub fn remove_host(&self, address: IpAddr) {
+        self.hosts.lock().unwrap().remove(&address);
+    }
+
+    /// Makes `name` resolve to `addresses`, in that order, replacing what it
+    /// resolved to before.
+    pub fn add_name(&self, name: &str, addresses: Vec<IpAddr>) {
+        self.names
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), addresses);
+    }
+
+    /// Fails the next receive with `error`, after whatever is already on its way.
+    pub fn fail_receive(&self, error: io::Error) {
+        let _ = self.deliver.send(Err(error));
+    }
+
+    fn answer(&self, packet: &[u8], destination: IpAddr, options: SendOptions) {
+        let reassembled;
+        let packet = if destination.is_ipv4() && self.header_included && is_fragment(packet) {
+            let pushed = self.requests.lock().unwrap().push(packet, Instant::now());
+            match pushed {
+                Some(datagram) => {
+                    reassembled = datagram;
+                    &reassembled[..]
+                }
+                None => return,
+            }
+        } else {
+            packet
+        };
+        let request = match destination {
+            IpAddr::V4(_) if self.header_included => {
+                let ihl = (packet.first().copied().unwrap_or(0x45) & 0x0F) as usize * 4;
+                packet.get(ihl..).unwrap_or_default()
+            }
+            _ => packet,
+        };
+        let echo_request = match destination {
+            IpAddr::V4(_) => icmp::ICMPV4_ECHO_REQUEST,
+            IpAddr::V6(_) => icmp::ICMPV6_ECHO_REQUEST,
+        };
+        match ICMPHeader::deserialize(request) {
+            Ok(header) if header.msg_type == echo_request => {}
+            _ => return,
+        }
+        let Some(host) = self.hosts.lock().unwrap().get(&destination).cloned() else {
+            return;
+        };
+
+        let mut rng = self.rng.lock().unwrap();
+        if rng.gen_bool(host.loss.clamp(0.0, 1.0)) {
+            return;
+        }
+
+        let ttl = 64u8.saturating_sub(host.hops);
+        let tos = host.remark.unwrap_or(options.tos);
+        let too_big = host.mtu.filter(|link| {
+            let size = packet.len() + if self.header_included { 0 } else { 20 };
+            let dont_fragment = match destination {
+                IpAddr::V4(_) if self.header_included => {
+                    packet.get(6).is_some_and(|flags| flags & 0x40 != 0)
+                }
+                IpAddr::V4(_) => options
+                    .mtu_discovery
+                    .is_some_and(MtuDiscovery::sets_dont_fragment),
+                IpAddr::V6(_) => true,
+            };
+            match destination {
+                IpAddr::V4(_) => dont_fragment && size > link.mtu as usize,
+                IpAddr::V6(_) => request.len() + 40 > link.mtu as usize,
+            }
+        });
+        let sent_ttl = match destination {
+            IpAddr::V4(_) if self.header_included => packet.get(8).copied().unwrap_or(64),
+            _ => options.ttl,
+        };
+        // load balancers hash the first four bytes of ICMP, which only the
+        // checksum varies in, or the IPv6 flow label
+        let flow = match destination {
+            IpAddr::V4(_) => request.get(2..4).map_or(0, |checksum| {
+                u16::from_be_bytes([checksum[0], checksum[1]]) as usize
+            }),
+            IpAddr::V6(_) => options.flow_label as usize,
+        };
+        // the router that decrements the TTL to zero, if it runs out on the way
+        let expired_at = (sent_ttl as usize)
+            .checked_sub(1)
+            .and_then(|index| host.route.get(index))
+            .filter(|routers| !routers.is_empty())
+            .map(|routers| routers[flow % routers.len()]);
+        let (from, mut datagram) = match (expired_at, too_big, &host.error) {
+            (Some(router), _, _) => {
+                let error = SimulatedError {
+                    from: router,
+                    msg_type: match destination {
+                        IpAddr::V4(_) => icmp::ICMPV4_TIME_EXCEEDED,
+                        IpAddr::V6(_) => icmp::ICMPV6_TIME_EXCEEDED,
+                    },
+                    code: 0,
+                };
+                (router, build_error(&error, destination, request, 0))
+            }
+            (None, Some(link), _) => {
+                let error = SimulatedError {
+                    from: link.router,
+                    msg_type: match destination {
+                        IpAddr::V4(_) => icmp::ICMPV4_DESTINATION_UNREACHABLE,
+                        IpAddr::V6(_) => icmp::ICMPV6_PACKET_TOO_BIG,
+                    },
+                    code: if destination.is_ipv4() { 4 } else { 0 },
+                };
+                (
+                    link.router,
+                    build_error(&error, destination, request, link.mtu),
+                )
+            }
+            (None, None, Some(error)) => (error.from, build_error(error, destination, request, 0)),
+            (None, None, None) => (destination, build_reply(destination, request, ttl, tos)),
+        };
+        if rng.gen_bool(host.corrupt.clamp(0.0, 1.0)) {
+            if destination.is_ipv6() {
+                // the kernel validates ICMPv6 checksums and drops the datagram
+                return;
+            }
+            // flip a byte past the IP header, if there is one
+            if datagram.len() > 20 {
+                let index = rng.gen_range(20..datagram.len());
+                datagram[index] ^= 0xFF;
+            }
+        }
+
+        let mut delay = host.latency.sample(&mut rng);
+        // routers on the way answer sooner, in proportion to how far they are
+        if expired_at.is_some() {
+            delay = delay * sent_ttl as u32 / (host.route.len() as u32 + 1);
+        }
+        if rng.gen_bool(host.reorder.clamp(0.0, 1.0)) {
+            delay += host.latency.sample(&mut rng) * 2 + Duration::from_millis(1);
+        }
+        let mut deliveries = vec![delay];
+        if rng.gen_bool(host.duplicate.clamp(0.0, 1.0)) {
+            deliveries.push(delay + Duration::from_millis(1));
+        }
+        let datagrams = match host.fragment_replies {
+            Some(size) if destination.is_ipv4() => {
+                datagram[4..6].copy_from_slice(&rng.gen::<u16>().to_be_bytes());
+                let mut fragments = fragment(&datagram, size, 0);
+                fragments.reverse();
+                fragments
+            }
+            _ => vec![datagram],
+        };
+
+        for delay in deliveries {
+            let deliver = self.deliver.clone();
+            let deliveries = datagrams
+                .iter()
+                .map(|datagram| Delivery {
+                    from,
+                    datagram: datagram.clone(),
+                    ttl,
+                    tos,
+                })
+                .collect::<Vec<_>>();
+            tokio::spawn(async move {
+                sleep(delay).await;
+                for delivery in deliveries {
+                    let _ = deliver.send(Ok(delivery));
+                }
+            });
+        }
+    }
+}
+
+impl IcmpTransport for SimulatedNetwork {
+    fn header_included(&self) -> bool {
+        self.header_included
+    }
+
+    fn source_for(&self, destination: IpAddr) -> Option<IpAddr> {
+        Some(match destination {
+            IpAddr::V4(_) => IpAddr::V4(SIMULATED_SOURCE_V4),
+            IpAddr::V6(_) => IpAddr::V6(SIMULATED_SOURCE_V6),
+        })
+    }
+
+    fn send_to<'a>(
+        &'a self,
+        packet: &'a [u8],
+        destination: IpAddr,
+        options: SendOptions,
+    ) -> BoxFuture<'a, io::Result<usize>> {
+        Box::pin(async move {
+            self.answer(packet, destination, options);
+            Ok(packet.len())
+        })
+    }
+
+    fn recv(&self) -> BoxFuture<'_, io::Result<Option<Received>>> {
+        Box::pin(async move {
+            let Some(delivery) = self.inbox.lock().await.recv().await else {
+                return Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "simulated network closed",
+                ));
+            };
+            let delivery = delivery?;
+            let source = delivery.from;
+            let mut datagram = delivery.datagram;
+            if source.is_ipv4() && is_fragment(&datagram) {
+                let pushed = self.replies.lock().unwrap().push(&datagram, Instant::now());
+                match pushed {
+                    Some(whole) => datagram = whole,
+                    None => return Ok(None),
+                }
+            }
+            let incoming = match source {
+                IpAddr::V4(_) => parse_icmpv4(&datagram, source),
+                IpAddr::V6(_) => parse_icmpv6(&datagram, source),
+            };
+            Ok(incoming.map(|mut incoming| {
+                // what a real socket reads from ancillary data
+                incoming.ttl = incoming.ttl.or(Some(delivery.ttl));
+                incoming.tos = incoming.tos.or(Some(delivery.tos));
+                Received::Message(incoming)
+            }))
+        })
+    }
+}
+
+impl Resolve for SimulatedNetwork {
+    fn lookup(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        self.names
+            .lock()
+            .unwrap()
+            .get(host)
+            .cloned()
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{} has no addresses", host),
+                )
+            })
+    }
+
+    fn reverse(&self, address: IpAddr) -> Option<String> {
+        let names = self.names.lock().unwrap();
+        names
+            .iter()
+            .filter(|(_, addresses)| addresses.contains(&address))
+            .map(|(name, _)| name.clone())
+            .min()
+    }
+}
+
+/// The echo reply `responder` sends back for `request`, as a raw socket would
+/// receive it.
+fn build_reply(responder: IpAddr, request: &[u8], ttl: u8, tos: u8) -> Vec<u8> {
+    let mut message = request.to_vec();
+    match responder {
+        IpAddr::V4(responder) => {
+            message[0] = icmp::ICMPV4_ECHO_REPLY;
+            set_checksum(&mut message);
+            with_ipv4_header(responder, SIMULATED_SOURCE_V4, ttl, tos, message)
+        }
+        IpAddr::V6(_) => {
+            message[0] = icmp::ICMPV6_ECHO_REPLY;
+            message
+        }
+    }
+}
+
+/// An ICMP error from `error.from` quoting `request`, sent to `destination`.
+/// `mtu` goes in the header of Fragmentation Needed and Packet Too Big errors.
+fn build_error(error: &SimulatedError, destination: IpAddr, request: &[u8], mtu: u32) -> Vec<u8> {
+    let mut message = vec![error.msg_type, error.code, 0, 0, 0, 0, 0, 0];
+    match (error.from, destination) {
+        (IpAddr::V4(from), IpAddr::V4(destination)) => {
+            message[6..8].copy_from_slice(&(mtu as u16).to_be_bytes());
+            message.extend(with_ipv4_header(
+                SIMULATED_SOURCE_V4,
+                destination,
+                64,
+                0,
+                request[..8.min(request.len())].to_vec(),
+            ));
+            set_checksum(&mut message);
+            with_ipv4_header(from, SIMULATED_SOURCE_V4, 64, 0, message)
+        }
+        (_, IpAddr::V6(destination)) => {
+            message[4..8].copy_from_slice(&mtu.to_be_bytes());
+            let mut quoted = vec![0x60, 0, 0, 0];
+            quoted.extend_from_slice(&(request.len() as u16).to_be_bytes());
+            quoted.extend_from_slice(&[58, 64]);
+            quoted.extend_from_slice(&SIMULATED_SOURCE_V6.octets());
+            quoted.extend_from_slice(&destination.octets());
+            message.extend(quoted);
+            message.extend_from_slice(request);
+            message
+        }
+        (IpAddr::V6(_), IpAddr::V4(_)) => {
+            unreachable!("add_host refuses IPv6 routers for IPv4 hosts")
+        }
+    }
+}
+
+fn with_ipv4_header(
+    source: Ipv4Addr,
+    destination: Ipv4Addr,
+    ttl: u8,
+    tos: u8,
+    payload: Vec<u8>,
+) -> Vec<u8> {
+    let length = (20 + payload.len()) as u16;
+    let mut datagram = vec![0x45, tos];
+    datagram.extend_from_slice(&length.to_be_bytes());
+    datagram.extend_from_slice(&[0, 0, 0, 0, ttl, 1, 0, 0]);
+    datagram.extend_from_slice(&source.octets());
+    datagram.extend_from_slice(&destination.octets());
+    let checksum = icmp::internet_checksum(&datagram);
+    datagram[10..12].copy_from_slice(&checksum.to_be_bytes());
+    datagram.extend(payload);
+    datagram
+}
+
+fn set_checksum(message: &mut [u8]) {
+    message[2..4].copy_from_slice(&[0, 0]);
+    let checksum = icmp::internet_checksum(message);
+    message[2..4].copy_from_slice(&checksum.to_be_bytes());
+}
@@ -1,190 +1,982 @@
 use std::{
-    mem::MaybeUninit,
-    net::{IpAddr, SocketAddr},
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
-    },
-    time::{Duration, Instant},
+    collections::HashSet,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6},
+    os::fd::{AsRawFd, RawFd},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{io::unix::AsyncFd, sync::Mutex as AsyncMutex, time::Instant};
 
-use crate::icmp::{IPV4Packet, IPV6Packet};
+use crate::{
+    error::SocketError,
+    fragment::{self, Reassembler},
+    icmp::{self, ICMPHeader, IPV4Packet, IPV6Packet},
+    ip::{self, BindTarget},
+    transport::{
+        BoxFuture, IcmpTransport, Incoming, MtuDiscovery, Received, SendOptions, SendTimestamp,
+        TimestampSource,
+    },
+};
+
+/// An ICMP socket for one address family, shared by every probe of that family.
+///
+/// This is a raw socket when we're allowed to open one, and otherwise an
+/// unprivileged datagram ICMP socket (`net.ipv4.ping_group_range` on Linux). Datagram
+/// sockets never carry our IP header and the kernel replaces the echo identifier
+/// with its own.
+///
+/// The socket is non-blocking and registered with the tokio reactor, so it must be
+/// created from within a tokio runtime.
+#[derive(Debug)]
+pub struct IcmpSocket {
+    socket: AsyncFd<Socket>,
+    is_ipv6: bool,
+    header_included: bool,
+    // echo identifier the kernel assigned to a datagram socket
+    identifier: Option<u16>,
+    // options last set on the socket, for sockets that don't carry our IP header
+    options: Mutex<Option<SendOptions>>,
+    // IPv4 replies that arrive in fragments
+    reassembly: Mutex<Reassembler>,
+    // IPv6 flow labels the kernel has let this socket send with
+    flow_labels: Mutex<HashSet<u32>>,
+    // what datagrams are read into, kept between receives
+    buffer: AsyncMutex<Vec<u8>>,
+}
 
-pub fn send_and_receive_ipv4_packet(
-    packet: IPV4Packet,
-    destination: IpAddr,
-    audio: bool,
-    timeout: u64,
-    running: &Arc<AtomicBool>,
-) -> std::io::Result<()> {
-    match destination {
-        IpAddr::V6(_) => {
-            panic!("must provide ipv4 address as destination");
+impl IcmpSocket {
+    /// Opens a raw ICMP socket, or a datagram ICMP socket if raw sockets are denied.
+    /// The socket is bound to `bind` if given.
+    pub fn open_ipv4(
+        header_included: bool,
+        bind: Option<&BindTarget>,
+    ) -> Result<IcmpSocket, SocketError> {
+        match Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::ICMPV4)) {
+            Ok(socket) => IcmpSocket::from_raw(socket, false, header_included, bind),
+            Err(e) if is_permission_denied(&e) => IcmpSocket::new_datagram(Domain::IPV4, bind)
+                .map_err(|dgram_error| unprivileged_error(e, dgram_error)),
+            Err(e) => Err(SocketError::new(format!(
+                "Failed to open ICMP socket: {}",
+                e
+            ))),
         }
-        _ => {}
     }
 
-    let serialized_packet = packet.serialize();
-    let socket = Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::ICMPV4))?;
-    socket.set_nonblocking(true)?;
-
-    if packet.header.is_some() {
-        socket.set_header_included(true).unwrap();
+    /// Opens a raw ICMPv6 socket, or a datagram ICMPv6 socket if raw sockets are denied.
+    pub fn open_ipv6(bind: Option<&BindTarget>) -> Result<IcmpSocket, SocketError> {
+        match Socket::new(Domain::IPV6, Type::RAW, Some(Protocol::ICMPV6)) {
+            Ok(socket) => IcmpSocket::from_raw(socket, true, false, bind),
+            Err(e) if is_permission_denied(&e) => IcmpSocket::new_datagram(Domain::IPV6, bind)
+                .map_err(|dgram_error| unprivileged_error(e, dgram_error)),
+            Err(e) => Err(SocketError::new(format!(
+                "Failed to open ICMPv6 socket: {}",
+                e
+            ))),
+        }
     }
 
-    let sockaddr = SocketAddr::new(destination, 0);
-    match socket.send_to(&serialized_packet, &sockaddr.into()) {
-        Ok(_) => {}
-        Err(e) => println!("Failed to send packet: {:?}", e), // TODO: handle error
+    fn from_raw(
+        socket: Socket,
+        is_ipv6: bool,
+        header_included: bool,
+        bind: Option<&BindTarget>,
+    ) -> Result<IcmpSocket, SocketError> {
+        if header_included {
+            socket
+                .set_header_included(true)
+                .map_err(|e| SocketError::new(format!("Failed to set IP_HDRINCL: {}", e)))?;
+        }
+        if let Some(target) = bind {
+            bind_socket(&socket, target, is_ipv6)
+                .map_err(|e| SocketError::new(format!("Failed to bind to {}: {}", target, e)))?;
+        }
+        IcmpSocket::register(socket, is_ipv6, header_included, None)
+            .map_err(|e| SocketError::new(format!("Failed to register socket: {}", e)))
     }
 
-    let mut buf: [MaybeUninit<u8>; 1024] = [const { MaybeUninit::uninit() }; 1024];
-    let timeout = Duration::from_millis(timeout);
-    let start = Instant::now();
+    /// Opens an unprivileged datagram ICMP socket and binds it so the kernel
+    /// assigns the echo identifier up front.
+    fn new_datagram(domain: Domain, bind: Option<&BindTarget>) -> io::Result<IcmpSocket> {
+        let is_ipv6 = domain == Domain::IPV6;
+        let (protocol, unspecified) = if is_ipv6 {
+            (Protocol::ICMPV6, IpAddr::V6(Ipv6Addr::UNSPECIFIED))
+        } else {
+            (Protocol::ICMPV4, IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+        };
+        let socket = Socket::new(domain, Type::DGRAM, Some(protocol))?;
+        match bind {
+            Some(target @ BindTarget::Address(address)) if address.is_ipv6() == is_ipv6 => {
+                bind_socket(&socket, target, is_ipv6)?
+            }
+            Some(target) => {
+                bind_socket(&socket, target, is_ipv6)?;
+                socket.bind(&SocketAddr::new(unspecified, 0).into())?
+            }
+            None => socket.bind(&SocketAddr::new(unspecified, 0).into())?,
+        }
+        let identifier = socket
+            .local_addr()?
+            .as_socket()
+            .map(|addr| addr.port())
+            .unwrap_or_default();
 
-    while start.elapsed() < timeout && running.load(Ordering::SeqCst) {
-        match socket.recv_from(&mut buf) {
-            Ok((number_of_bytes, _)) => {
-                let received_data = unsafe {
-                    std::slice::from_raw_parts(buf.as_ptr() as *const u8, number_of_bytes)
-                };
+        // ICMP errors for datagram sockets only arrive on the socket error queue
+        #[cfg(target_os = "linux")]
+        {
+            let (level, name) = if is_ipv6 {
+                (libc::SOL_IPV6, libc::IPV6_RECVERR)
+            } else {
+                (libc::SOL_IP, libc::IP_RECVERR)
+            };
+            set_int_option(socket.as_raw_fd(), level, name, 1)?;
+        }
 
-                let received_packet = IPV4Packet::deserialize(&received_data);
-                if received_packet.is_err() {
-                    println!("Failed to deserialize packet");
-                    break;
-                }
+        IcmpSocket::register(socket, is_ipv6, false, Some(identifier))
+    }
+
+    fn register(
+        socket: Socket,
+        is_ipv6: bool,
+        header_included: bool,
+        identifier: Option<u16>,
+    ) -> io::Result<IcmpSocket> {
+        socket.set_nonblocking(true)?;
+        enable_timestamps(socket.as_raw_fd());
+        enable_header_fields(socket.as_raw_fd(), is_ipv6);
+        Ok(IcmpSocket {
+            socket: AsyncFd::new(socket)?,
+            is_ipv6,
+            header_included,
+            identifier,
+            options: Mutex::new(None),
+            reassembly: Mutex::new(Reassembler::default()),
+            flow_labels: Mutex::new(HashSet::new()),
+            buffer: AsyncMutex::new(receive_buffer()),
+        })
+    }
 
-                let received_packet = received_packet.unwrap();
+    pub fn is_datagram(&self) -> bool {
+        self.identifier.is_some()
+    }
 
-                if received_packet.icmp_header.seq_num == packet.icmp_header.seq_num {
-                    if audio {
-                        println!("\x07"); // Beep
+    /// Sends a serialized packet. `options` are applied through socket options
+    /// when the kernel builds the IP header; otherwise they are expected to be in
+    /// the packet.
+    async fn send_packet(
+        &self,
+        packet: &[u8],
+        destination: IpAddr,
+        options: SendOptions,
+    ) -> io::Result<usize> {
+        let sockaddr = match destination {
+            IpAddr::V6(destination) => SocketAddr::V6(SocketAddrV6::new(
+                destination,
+                0,
+                // sockaddr_in6 keeps the flow label in network byte order
+                options.flow_label.to_be(),
+                options.scope_id,
+            )),
+            IpAddr::V4(_) => SocketAddr::new(destination, 0),
+        }
+        .into();
+        loop {
+            let mut guard = self.socket.writable().await?;
+            let sent = guard.try_io(|socket| {
+                let socket = socket.get_ref();
+                // set and send under one lock so concurrent probes can't swap options
+                let mut current = self.options.lock().unwrap();
+                if !self.header_included && *current != Some(options) {
+                    apply_options(socket, self.is_ipv6, options, *current)?;
+                    *current = Some(options);
+                }
+                if let (IpAddr::V6(destination), true) = (destination, options.flow_label != 0) {
+                    let mut labels = self.flow_labels.lock().unwrap();
+                    if !labels.contains(&options.flow_label) {
+                        hold_flow_label(socket.as_raw_fd(), destination, options.flow_label)?;
+                        labels.insert(options.flow_label);
                     }
-                    println!(
-                        "Received {} bytes from {}: icmp_seq={} time={} ms",
-                        number_of_bytes,
-                        destination,
-                        received_packet.icmp_header.seq_num,
-                        start.elapsed().as_millis()
-                    );
-                    return Ok(());
                 }
+                // an ICMP error for an earlier probe fails the next send until it
+                // is cleared; the receiver still reads it from the error queue
+                if self.identifier.is_some() {
+                    socket.take_error()?;
+                }
+                socket.send_to(packet, &sockaddr)
+            });
+            match sent {
+                Ok(result) => return result,
+                Err(_would_block) => continue,
             }
-            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                // No data available yet, continue to try
-                continue;
+        }
+    }
+
+    async fn recv_incoming(&self) -> io::Result<Option<Received>> {
+        #[cfg(target_os = "linux")]
+        {
+            // drain the error queue first so send timestamps reach their probe
+            // before the reply does
+            match self.read_queued() {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                result => return result,
             }
-            Err(e) => {
-                return Err(e); // Propagate unexpected errors
+            tokio::select! {
+                biased;
+                received = self.recv_queued() => received,
+                received = self.recv_datagram() => received,
             }
         }
+        #[cfg(not(target_os = "linux"))]
+        self.recv_datagram().await
     }
-    if !running.load(Ordering::SeqCst) {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::Interrupted,
-            "Ping interrupted",
-        ));
+
+    async fn recv_datagram(&self) -> io::Result<Option<Received>> {
+        let mut data = self.buffer.lock().await;
+        let message = loop {
+            let mut guard = self.socket.readable().await?;
+            match guard.try_io(|socket| recv_msg(socket.get_ref().as_raw_fd(), &mut data, 0)) {
+                Ok(result) => break result?,
+                Err(_would_block) => continue,
+            }
+        };
+
+        let (received_at, timestamp) = message
+            .timestamp()
+            .unwrap_or_else(|| (Instant::now(), TimestampSource::Userspace));
+        let Some(source) = message.source else {
+            return Ok(None);
+        };
+        let mut received_data = &data[..message.len.min(data.len())];
+        let reassembled;
+        if !self.is_ipv6
+            && received_data.first().map(|b| b >> 4) == Some(4)
+            && fragment::is_fragment(received_data)
+        {
+            let mut reassembly = self.reassembly.lock().unwrap();
+            match reassembly.push(received_data, received_at) {
+                Some(datagram) => {
+                    reassembled = datagram;
+                    received_data = &reassembled;
+                }
+                None => return Ok(None),
+            }
+        }
+        let parsed = if self.is_ipv6 {
+            parse_icmpv6(received_data, source)
+        } else if self.is_datagram() && received_data.first().map(|b| b >> 4) != Some(4) {
+            // Linux strips the IP header on datagram sockets, macOS keeps it
+            parse_icmpv4_message(received_data, source, received_data.len())
+        } else {
+            parse_icmpv4(received_data, source)
+        };
+        let (hop_limit, tos) = (message.hop_limit(), message.tos());
+        Ok(parsed.map(|mut incoming| {
+            incoming.ttl = incoming.ttl.or(hop_limit);
+            incoming.tos = incoming.tos.or(tos);
+            incoming.received_at = received_at;
+            incoming.timestamp = timestamp;
+            Received::Message(incoming)
+        }))
     }
 
-    if start.elapsed() > timeout {
-        Err(std::io::Error::new(
-            std::io::ErrorKind::TimedOut,
-            "Timeout reached, no response received.",
-        ))?;
+    #[cfg(target_os = "linux")]
+    async fn recv_queued(&self) -> io::Result<Option<Received>> {
+        use tokio::io::Interest;
+
+        loop {
+            let mut guard = self.socket.ready(Interest::ERROR).await?;
+            match guard.try_io(|_| self.read_queued()) {
+                Ok(result) => return result,
+                Err(_would_block) => continue,
+            }
+        }
     }
 
+    /// Reads one entry from the socket error queue without waiting. Entries are
+    /// either send timestamps or, on datagram sockets, ICMP errors. Both carry
+    /// the echo request we sent, as the kernel saw it.
+    #[cfg(target_os = "linux")]
+    fn read_queued(&self) -> io::Result<Option<Received>> {
+        let mut data = [0u8; 1024];
+        let message = recv_msg(
+            self.socket.get_ref().as_raw_fd(),
+            &mut data,
+            libc::MSG_ERRQUEUE,
+        )?;
+        let quoted = &data[..message.len.min(data.len())];
+        let Some(error) = message.extended_error() else {
+            return Ok(None);
+        };
+
+        if error.origin == libc::SO_EE_ORIGIN_TIMESTAMPING {
+            let (Some((id, seq_num)), Some((sent_at, timestamp))) = (
+                looped_echo_request(quoted, self.is_ipv6),
+                message.timestamp(),
+            ) else {
+                return Ok(None);
+            };
+            return Ok(Some(Received::SendTimestamp(SendTimestamp {
+                id,
+                seq_num,
+                sent_at,
+                timestamp,
+            })));
+        }
+
+        if (error.origin != libc::SO_EE_ORIGIN_ICMP && error.origin != libc::SO_EE_ORIGIN_ICMP6)
+            || quoted.len() < 8
+        {
+            return Ok(None);
+        }
+        let source = error.offender.unwrap_or(if self.is_ipv6 {
+            IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+        } else {
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+        });
+        Ok(Some(Received::Message(Incoming {
+            source,
+            bytes: Some(quoted.len()),
+            msg_type: error.msg_type,
+            code: error.code,
+            id: u16::from_be_bytes([quoted[4], quoted[5]]),
+            seq_num: u16::from_be_bytes([quoted[6], quoted[7]]),
+            ttl: None,
+            tos: None,
+            mtu: advertised_mtu(error.msg_type, error.code, error.info),
+            received_at: Instant::now(),
+            timestamp: TimestampSource::Userspace,
+        })))
+    }
+}
+
+impl IcmpTransport for IcmpSocket {
+    fn header_included(&self) -> bool {
+        self.header_included
+    }
+
+    /// The echo identifier the kernel puts on every request sent through a
+    /// datagram socket. `None` for raw sockets, which send ours unchanged.
+    fn identifier(&self) -> Option<u16> {
+        self.identifier
+    }
+
+    fn mtu_for(&self, destination: IpAddr) -> Option<usize> {
+        ip::route_mtu(destination)
+    }
+
+    fn send_to<'a>(
+        &'a self,
+        packet: &'a [u8],
+        destination: IpAddr,
+        options: SendOptions,
+    ) -> BoxFuture<'a, io::Result<usize>> {
+        Box::pin(self.send_packet(packet, destination, options))
+    }
+
+    fn recv(&self) -> BoxFuture<'_, io::Result<Option<Received>>> {
+        Box::pin(self.recv_incoming())
+    }
+}
+
+/// Sets the TTL, TOS and MTU discovery mode of `options` on a socket whose IP
+/// header the kernel builds, skipping those that already match `current`.
+pub(crate) fn apply_options(
+    socket: &Socket,
+    is_ipv6: bool,
+    options: SendOptions,
+    current: Option<SendOptions>,
+) -> io::Result<()> {
+    if current.map(|current| current.ttl) != Some(options.ttl) {
+        if is_ipv6 {
+            socket.set_unicast_hops_v6(options.ttl as u32)?;
+        } else {
+            socket.set_ttl(options.ttl as u32)?;
+        }
+    }
+    if current.map(|current| current.tos) != Some(options.tos) {
+        if is_ipv6 {
+            set_int_option(
+                socket.as_raw_fd(),
+                libc::IPPROTO_IPV6,
+                libc::IPV6_TCLASS,
+                options.tos as libc::c_int,
+            )?;
+        } else {
+            socket.set_tos(options.tos as u32)?;
+        }
+    }
+    if current.map(|current| current.mtu_discovery) != Some(options.mtu_discovery) {
+        if let Some(mode) = options.mtu_discovery {
+            set_mtu_discovery(socket.as_raw_fd(), is_ipv6, mode)?;
+        }
+    }
     Ok(())
 }
 
-pub fn send_and_receive_ipv6_packet(
-    packet: IPV6Packet,
-    destination: IpAddr,
-    audio: bool,
-    timeout: u64,
-    running: &Arc<AtomicBool>,
-) -> std::io::Result<()> {
-    match destination {
-        IpAddr::V4(_) => {
-            panic!("must provide ipv6 address as destination");
+pub(crate) fn is_permission_denied(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::PermissionDenied || e.raw_os_error() == Some(libc::EPERM)
+}
+
+fn unprivileged_error(raw_error: io::Error, dgram_error: io::Error) -> SocketError {
+    SocketError::new(format!(
+        "Failed to open ICMP socket: raw sockets need root or CAP_NET_RAW ({}), and datagram ICMP sockets are not permitted either ({}). Check net.ipv4.ping_group_range.",
+        raw_error, dgram_error
+    ))
+}
+
+/// Asks the kernel to timestamp our datagrams as they are sent and received.
+/// Best effort: RTTs fall back to userspace timing without it.
+pub(crate) fn enable_timestamps(fd: RawFd) {
+    #[cfg(target_os = "linux")]
+    {
+        let flags = libc::SOF_TIMESTAMPING_TX_SOFTWARE
+            | libc::SOF_TIMESTAMPING_RX_SOFTWARE
+            | libc::SOF_TIMESTAMPING_SOFTWARE
+            | libc::SOF_TIMESTAMPING_TX_HARDWARE
+            | libc::SOF_TIMESTAMPING_RX_HARDWARE
+            | libc::SOF_TIMESTAMPING_RAW_HARDWARE;
+        if set_int_option(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_TIMESTAMPING,
+            flags as libc::c_int,
+        )
+        .is_err()
+        {
+            let _ = set_int_option(fd, libc::SOL_SOCKET, libc::SO_TIMESTAMPNS, 1);
         }
-        _ => {}
     }
+    #[cfg(not(target_os = "linux"))]
+    let _ = set_int_option(fd, libc::SOL_SOCKET, libc::SO_TIMESTAMP, 1);
+}
+
+/// Asks the kernel for the TTL or hop limit and the TOS or traffic class of each
+/// datagram, for sockets that don't see the IP header. Best effort, like
+/// timestamps.
+pub(crate) fn enable_header_fields(fd: RawFd, is_ipv6: bool) {
+    if is_ipv6 {
+        let _ = set_int_option(fd, libc::IPPROTO_IPV6, libc::IPV6_RECVHOPLIMIT, 1);
+        let _ = set_int_option(fd, libc::IPPROTO_IPV6, libc::IPV6_RECVTCLASS, 1);
+    } else {
+        let _ = set_int_option(fd, libc::IPPROTO_IP, libc::IP_RECVTTL, 1);
+        let _ = set_int_option(fd, libc::IPPROTO_IP, libc::IP_RECVTOS, 1);
+    }
+}
 
-    let serialized_packet = packet.serialize();
+#[cfg(target_os = "linux")]
+fn set_mtu_discovery(fd: RawFd, is_ipv6: bool, mode: MtuDiscovery) -> io::Result<()> {
+    if is_ipv6 {
+        let value = match mode {
+            MtuDiscovery::Do => libc::IPV6_PMTUDISC_DO,
+            MtuDiscovery::Want => libc::IPV6_PMTUDISC_WANT,
+            MtuDiscovery::Dont => libc::IPV6_PMTUDISC_DONT,
+            MtuDiscovery::Probe => libc::IPV6_PMTUDISC_PROBE,
+        };
+        set_int_option(fd, libc::IPPROTO_IPV6, libc::IPV6_MTU_DISCOVER, value)
+    } else {
+        let value = match mode {
+            MtuDiscovery::Do => libc::IP_PMTUDISC_DO,
+            MtuDiscovery::Want => libc::IP_PMTUDISC_WANT,
+            MtuDiscovery::Dont => libc::IP_PMTUDISC_DONT,
+            MtuDiscovery::Probe => libc::IP_PMTUDISC_PROBE,
+        };
+        set_int_option(fd, libc::IPPROTO_IP, libc::IP_MTU_DISCOVER, value)
+    }
+}
 
-    let socket = Socket::new(Domain::IPV6, Type::RAW, Some(Protocol::ICMPV6))?;
-    socket.set_nonblocking(true)?;
-    socket.set_only_v6(true)?;
-    socket.set_recv_tclass_v6(true)?;
+#[cfg(not(target_os = "linux"))]
+fn set_mtu_discovery(_fd: RawFd, _is_ipv6: bool, _mode: MtuDiscovery) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "MTU discovery modes are only supported on Linux",
+    ))
+}
 
-    if packet.header.is_some() {
-        socket.set_header_included(true).unwrap();
+/// Linux only sends flow labels a socket has leased from the flow label manager.
+#[cfg(target_os = "linux")]
+fn hold_flow_label(fd: RawFd, destination: Ipv6Addr, label: u32) -> io::Result<()> {
+    // struct in6_flowlabel_req from linux/in6.h
+    #[repr(C)]
+    struct FlowLabelRequest {
+        destination: libc::in6_addr,
+        label: u32,
+        action: u8,
+        share: u8,
+        flags: u16,
+        expires: u16,
+        linger: u16,
+        pad: u32,
     }
+    const IPV6_FL_A_GET: u8 = 0;
+    const IPV6_FL_S_ANY: u8 = 255;
+    const IPV6_FL_F_CREATE: u16 = 1;
 
-    let sockaddr = SocketAddr::new(destination, 0);
-    match socket.send_to(&serialized_packet, &sockaddr.into()) {
-        Ok(_) => {}
-        Err(e) => println!("Failed to send packet: {:?}", e), // TODO: handle error
+    let request = FlowLabelRequest {
+        destination: libc::in6_addr {
+            s6_addr: destination.octets(),
+        },
+        label: label.to_be(),
+        action: IPV6_FL_A_GET,
+        // spare sockets of the same session may lease it too
+        share: IPV6_FL_S_ANY,
+        flags: IPV6_FL_F_CREATE,
+        expires: 0,
+        linger: 0,
+        pad: 0,
+    };
+    let result = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::IPPROTO_IPV6,
+            libc::IPV6_FLOWLABEL_MGR,
+            &request as *const FlowLabelRequest as *const libc::c_void,
+            std::mem::size_of::<FlowLabelRequest>() as libc::socklen_t,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
     }
+    set_int_option(fd, libc::IPPROTO_IPV6, libc::IPV6_FLOWINFO_SEND, 1)
+}
 
-    let mut buf: [MaybeUninit<u8>; 1024] = [const { MaybeUninit::uninit() }; 1024];
-    let timeout = Duration::from_millis(timeout);
-    let start = Instant::now();
+#[cfg(not(target_os = "linux"))]
+fn hold_flow_label(_fd: RawFd, _destination: Ipv6Addr, _label: u32) -> io::Result<()> {
+    Ok(())
+}
 
-    while start.elapsed() < timeout && running.load(Ordering::SeqCst) {
-        match socket.recv_from(&mut buf) {
-            Ok((number_of_bytes, _)) => {
-                let received_data = unsafe {
-                    std::slice::from_raw_parts(buf.as_ptr() as *const u8, number_of_bytes)
-                };
+/// Binds to the address if it belongs to the socket's family, or sends through
+/// the interface.
+pub(crate) fn bind_socket(socket: &Socket, target: &BindTarget, is_ipv6: bool) -> io::Result<()> {
+    match target {
+        BindTarget::Address(address) if address.is_ipv6() == is_ipv6 => {
+            socket.bind(&SocketAddr::new(*address, 0).into())
+        }
+        BindTarget::Address(_) => Ok(()),
+        #[cfg(target_os = "linux")]
+        BindTarget::Interface(name) => socket.bind_device(Some(name.as_bytes())),
+        #[cfg(not(target_os = "linux"))]
+        BindTarget::Interface(_) => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "binding to an interface is only supported on Linux",
+        )),
+    }
+}
 
-                let received_packet = IPV6Packet::deserialize(&received_data);
-                if received_packet.is_err() {
-                    println!("Failed to deserialize packet");
-                    break;
-                }
+pub(crate) fn set_int_option(
+    fd: RawFd,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    let result = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
 
-                let received_packet = received_packet.unwrap();
+/// A datagram read with recvmsg(2), along with its control messages.
+#[derive(Debug)]
+pub(crate) struct ReceivedMsg {
+    pub len: usize,
+    pub source: Option<IpAddr>,
+    /// Port of the source, for UDP and for the destination of a queued error.
+    pub port: u16,
+    control: Vec<ControlMessage>,
+}
 
-                if received_packet.icmp_header.seq_num == packet.icmp_header.seq_num {
-                    if audio {
-                        println!("\x07"); // Beep
-                    }
-                    println!(
-                        "Received {} bytes from {}: icmp_seq={} time={} ms",
-                        number_of_bytes,
-                        destination,
-                        received_packet.icmp_header.seq_num,
-                        start.elapsed().as_millis()
-                    );
-                    return Ok(());
+#[derive(Debug)]
+struct ControlMessage {
+    level: libc::c_int,
+    kind: libc::c_int,
+    data: Vec<u8>,
+}
+
+/// The details of a `sock_extended_err` from the socket error queue.
+#[cfg(target_os = "linux")]
+#[derive(Debug)]
+pub(crate) struct ExtendedError {
+    pub origin: u8,
+    pub msg_type: u8,
+    pub code: u8,
+    // the advertised MTU for Fragmentation Needed and Packet Too Big
+    pub info: u32,
+    pub offender: Option<IpAddr>,
+}
+
+impl ReceivedMsg {
+    /// The TTL or hop limit the datagram arrived with.
+    pub(crate) fn hop_limit(&self) -> Option<u8> {
+        // macOS reports the TTL as a single byte under the option's own name
+        #[cfg(target_os = "linux")]
+        const TTL_KIND: libc::c_int = libc::IP_TTL;
+        #[cfg(not(target_os = "linux"))]
+        const TTL_KIND: libc::c_int = libc::IP_RECVTTL;
+
+        self.control.iter().find_map(|control| {
+            if control.level == libc::IPPROTO_IPV6 && control.kind == libc::IPV6_HOPLIMIT {
+                read_control::<libc::c_int>(&control.data).map(|hops| hops as u8)
+            } else if control.level == libc::IPPROTO_IP && control.kind == TTL_KIND {
+                match control.data.len() {
+                    1 => Some(control.data[0]),
+                    _ => read_control::<libc::c_int>(&control.data).map(|ttl| ttl as u8),
                 }
+            } else {
+                None
             }
-            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                // No data available yet, continue to try
-                continue;
-            }
-            Err(e) => {
-                return Err(e); // Propagate unexpected errors
+        })
+    }
+
+    /// The TOS or traffic class byte the datagram arrived with.
+    pub(crate) fn tos(&self) -> Option<u8> {
+        self.control.iter().find_map(|control| {
+            if control.level == libc::IPPROTO_IPV6 && control.kind == libc::IPV6_TCLASS {
+                read_control::<libc::c_int>(&control.data).map(|tclass| tclass as u8)
+            } else if control.level == libc::IPPROTO_IP
+                && (control.kind == libc::IP_TOS || control.kind == libc::IP_RECVTOS)
+            {
+                control.data.first().copied()
+            } else {
+                None
             }
+        })
+    }
+
+    /// When the kernel, or the network card, sent or received this datagram.
+    pub(crate) fn timestamp(&self) -> Option<(Instant, TimestampSource)> {
+        self.control
+            .iter()
+            .filter(|control| control.level == libc::SOL_SOCKET)
+            .find_map(|control| {
+                #[cfg(target_os = "linux")]
+                if control.kind == libc::SCM_TIMESTAMPING {
+                    // software, deprecated, and raw hardware time
+                    let times: [libc::timespec; 3] = read_control(&control.data)?;
+                    return match (timespec_duration(&times[2]), timespec_duration(&times[0])) {
+                        (Some(hardware), _) => {
+                            Some((clock_instant(hardware), TimestampSource::Hardware))
+                        }
+                        (None, Some(software)) => {
+                            Some((clock_instant(software), TimestampSource::Kernel))
+                        }
+                        (None, None) => None,
+                    };
+                }
+                #[cfg(target_os = "linux")]
+                if control.kind == libc::SCM_TIMESTAMPNS {
+                    let time: libc::timespec = read_control(&control.data)?;
+                    return Some((
+                        clock_instant(timespec_duration(&time)?),
+                        TimestampSource::Kernel,
+                    ));
+                }
+                #[cfg(not(target_os = "linux"))]
+                if control.kind == libc::SCM_TIMESTAMP {
+                    let time: libc::timeval = read_control(&control.data)?;
+                    let since_epoch = Duration::new(time.tv_sec as u64, time.tv_usec as u32 * 1000);
+                    return Some((clock_instant(since_epoch), TimestampSource::Kernel));
+                }
+                None
+            })
+    }
+
+    #[cfg(target_os = "linux")]
+    pub(crate) fn extended_error(&self) -> Option<ExtendedError> {
+        let control = self.control.iter().find(|control| {
+            (control.level == libc::SOL_IP && control.kind == libc::IP_RECVERR)
+                || (control.level == libc::SOL_IPV6 && control.kind == libc::IPV6_RECVERR)
+        })?;
+        let header_len = std::mem::size_of::<libc::sock_extended_err>();
+        let error: libc::sock_extended_err = read_control(&control.data)?;
+        Some(ExtendedError {
+            origin: error.ee_origin,
+            info: error.ee_info,
+            msg_type: error.ee_type,
+            code: error.ee_code,
+            offender: parse_sockaddr(&control.data[header_len..]).map(|offender| offender.ip()),
+        })
+    }
+}
+
+fn read_control<T: Copy>(data: &[u8]) -> Option<T> {
+    if data.len() < std::mem::size_of::<T>() {
+        return None;
+    }
+    Some(unsafe { std::ptr::read_unaligned(data.as_ptr() as *const T) })
+}
+
+#[cfg(target_os = "linux")]
+fn timespec_duration(time: &libc::timespec) -> Option<Duration> {
+    if time.tv_sec == 0 && time.tv_nsec == 0 {
+        return None;
+    }
+    Some(Duration::new(time.tv_sec as u64, time.tv_nsec as u32))
+}
+
+/// Places a realtime clock reading on tokio's monotonic clock.
+fn clock_instant(since_epoch: Duration) -> Instant {
+    let now = Instant::now();
+    let age = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .saturating_sub(since_epoch);
+    now.checked_sub(age).unwrap_or(now)
+}
+
+/// Finds our echo request in a packet the kernel looped back with its send
+/// timestamp. The packet may start at the link, network or ICMP header.
+#[cfg(target_os = "linux")]
+fn looped_echo_request(data: &[u8], is_ipv6: bool) -> Option<(u16, u16)> {
+    let echo_at = |offset: usize, echo_type: u8| {
+        let message = data.get(offset..offset + 8)?;
+        (message[0] == echo_type).then(|| {
+            (
+                u16::from_be_bytes([message[4], message[5]]),
+                u16::from_be_bytes([message[6], message[7]]),
+            )
+        })
+    };
+    let headers = (0..data.len().min(64)).filter_map(|offset| {
+        let first = *data.get(offset)?;
+        if is_ipv6 && first >> 4 == 6 && data.get(offset + 6) == Some(&58) {
+            echo_at(offset + 40, icmp::ICMPV6_ECHO_REQUEST)
+        } else if !is_ipv6
+            && first >> 4 == 4
+            && first & 0x0F >= 5
+            && data.get(offset + 9) == Some(&1)
+            // only the first fragment starts with the echo header
+            && data
+                .get(offset + 6..offset + 8)
+                .is_some_and(|field| u16::from_be_bytes([field[0], field[1]]) & 0x1FFF == 0)
+        {
+            echo_at(
+                offset + (first & 0x0F) as usize * 4,
+                icmp::ICMPV4_ECHO_REQUEST,
+            )
+        } else {
+            None
         }
+    });
+    let echo_type = if is_ipv6 {
+        icmp::ICMPV6_ECHO_REQUEST
+    } else {
+        icmp::ICMPV4_ECHO_REQUEST
+    };
+    headers.chain(echo_at(0, echo_type)).next()
+}
+
+/// A buffer big enough for any datagram, so large replies aren't truncated.
+/// Sockets keep theirs between receives rather than allocate one for each.
+pub(crate) fn receive_buffer() -> Vec<u8> {
+    vec![0u8; u16::MAX as usize]
+}
+
+pub(crate) fn recv_msg(fd: RawFd, data: &mut [u8], flags: libc::c_int) -> io::Result<ReceivedMsg> {
+    let mut control = [0u8; 512];
+    let mut name: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut iov = libc::iovec {
+        iov_base: data.as_mut_ptr() as *mut libc::c_void,
+        iov_len: data.len(),
+    };
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_name = &mut name as *mut libc::sockaddr_storage as *mut libc::c_void;
+    msg.msg_namelen = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = control.len() as _;
+
+    let len = unsafe { libc::recvmsg(fd, &mut msg, flags) };
+    if len < 0 {
+        return Err(io::Error::last_os_error());
     }
-    if !running.load(Ordering::SeqCst) {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::Interrupted,
-            "Ping interrupted",
-        ));
+
+    let mut messages = Vec::new();
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    while !cmsg.is_null() {
+        let (level, kind, cmsg_len) = unsafe {
+            (
+                (*cmsg).cmsg_level,
+                (*cmsg).cmsg_type,
+                (*cmsg).cmsg_len as usize,
+            )
+        };
+        let data_offset = unsafe { libc::CMSG_DATA(cmsg) as usize - cmsg as usize };
+        let data_len = cmsg_len.saturating_sub(data_offset);
+        let data = unsafe { std::slice::from_raw_parts(libc::CMSG_DATA(cmsg), data_len) }.to_vec();
+        messages.push(ControlMessage { level, kind, data });
+        cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
     }
 
-    if start.elapsed() > timeout {
-        Err(std::io::Error::new(
-            std::io::ErrorKind::TimedOut,
-            "Timeout reached, no response received.",
-        ))?;
+    let name = unsafe {
+        std::slice::from_raw_parts(
+            &name as *const libc::sockaddr_storage as *const u8,
+            msg.msg_namelen as usize,
+        )
+    };
+    let source = parse_sockaddr(name);
+    Ok(ReceivedMsg {
+        len: len as usize,
+        source: source.map(|source| source.ip()),
+        port: source.map_or(0, |source| source.port()),
+        control: messages,
+    })
+}
+
+/// Reads the address and port out of a raw `sockaddr_in` or `sockaddr_in6`.
+pub(crate) fn parse_sockaddr(data: &[u8]) -> Option<SocketAddr> {
+    let family = read_control::<libc::sockaddr>(data)?.sa_family as libc::c_int;
+    if family == libc::AF_INET && data.len() >= std::mem::size_of::<libc::sockaddr_in>() {
+        let addr = unsafe { std::ptr::read_unaligned(data.as_ptr() as *const libc::sockaddr_in) };
+        Some(SocketAddr::new(
+            IpAddr::V4(Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr))),
+            u16::from_be(addr.sin_port),
+        ))
+    } else if family == libc::AF_INET6 && data.len() >= std::mem::size_of::<libc::sockaddr_in6>() {
+        let addr = unsafe { std::ptr::read_unaligned(data.as_ptr() as *const libc::sockaddr_in6) };
+        Some(SocketAddr::new(
+            IpAddr::V6(Ipv6Addr::from(addr.sin6_addr.s6_addr)),
+            u16::from_be(addr.sin6_port),
+        ))
+    } else {
+        None
     }
+}
 
-    Ok(())
+/// The MTU a Fragmentation Needed or Packet Too Big error advertises, if it is one.
+/// Old routers send Fragmentation Needed with a zero MTU.
+pub(crate) fn advertised_mtu(msg_type: u8, code: u8, mtu: u32) -> Option<u32> {
+    let too_big = (msg_type == icmp::ICMPV4_DESTINATION_UNREACHABLE && code == 4)
+        || msg_type == icmp::ICMPV6_PACKET_TOO_BIG;
+    (too_big && mtu > 0).then_some(mtu)
+}
+
+/// Parses a datagram from a raw ICMPv4 socket, which includes the IP header.
+pub fn parse_icmpv4(data: &[u8], source: IpAddr) -> Option<Incoming> {
+    let packet = IPV4Packet::deserialize(data).ok()?;
+    let ihl = packet.header.as_ref().map_or(5, |header| header.ihl) as usize * 4;
+    let mut incoming = parse_icmpv4_message(data.get(ihl..)?, source, data.len())?;
+    if let Some(header) = packet.header {
+        incoming.ttl = Some(header.ttl);
+        incoming.tos = Some(header.tos);
+    }
+    Some(incoming)
+}
+
+/// Parses an ICMPv4 message without its IP header. `bytes` is the size of the
+/// datagram it arrived in.
+pub fn parse_icmpv4_message(data: &[u8], source: IpAddr, bytes: usize) -> Option<Incoming> {
+    let icmp_header = ICMPHeader::deserialize(data).ok()?;
+    // raw sockets see ICMP before the kernel validates it
+    if icmp::internet_checksum(data) != 0 {
+        return None;
+    }
+
+    // next-hop MTU in the low half of the unused word of Fragmentation Needed
+    let mtu_field = icmp_header.seq_num as u32;
+    let (id, seq_num) = match icmp_header.msg_type {
+        icmp::ICMPV4_ECHO_REPLY => (icmp_header.id, icmp_header.seq_num),
+        msg_type if icmp::is_icmpv4_error(msg_type) => icmp::quoted_echo_v4(data)?,
+        _ => return None,
+    };
+
+    Some(Incoming {
+        source,
+        bytes: Some(bytes),
+        msg_type: icmp_header.msg_type,
+        code: icmp_header.code,
+        id,
+        seq_num,
+        ttl: None,
+        tos: None,
+        mtu: advertised_mtu(icmp_header.msg_type, icmp_header.code, mtu_field),
+        received_at: Instant::now(),
+        timestamp: TimestampSource::Userspace,
+    })
+}
+
+/// Parses a datagram from a raw ICMPv6 socket, which starts at the ICMPv6 header.
+pub fn parse_icmpv6(data: &[u8], source: IpAddr) -> Option<Incoming> {
+    let packet = IPV6Packet::deserialize(data).ok()?;
+    let icmp_header = &packet.icmp_header;
+
+    // Packet Too Big carries the MTU in its second word
+    let mtu_field = (icmp_header.id as u32) << 16 | icmp_header.seq_num as u32;
+    let (id, seq_num) = match icmp_header.msg_type {
+        icmp::ICMPV6_ECHO_REPLY => (icmp_header.id, icmp_header.seq_num),
+        msg_type if icmp::is_icmpv6_error(msg_type) => icmp::quoted_echo_v6(data)?,
+        _ => return None,
+    };
+
+    Some(Incoming {
+        source,
+        bytes: Some(data.len()),
+        msg_type: icmp_header.msg_type,
+        code: icmp_header.code,
+        id,
+        seq_num,
+        ttl: None,
+        tos: None,
+        mtu: advertised_mtu(icmp_header.msg_type, icmp_header.code, mtu_field),
+        received_at: Instant::now(),
+        timestamp: TimestampSource::Userspace,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn it_matches_icmp_errors_to_the_quoted_probe() {
+        let source = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let mut data = vec![
+            0x45, 0, 0, 56, 0, 0, 0, 0, 64, 1, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2,
+        ];
+        // time exceeded in transit
+        data.extend_from_slice(&[icmp::ICMPV4_TIME_EXCEEDED, 0, 0, 0, 0, 0, 0, 0]);
+        data.extend_from_slice(&[
+            0x45, 0, 0, 28, 0, 0, 0, 0, 1, 1, 0, 0, 10, 0, 0, 2, 8, 8, 8, 8,
+        ]);
+        data.extend_from_slice(&[icmp::ICMPV4_ECHO_REQUEST, 0, 0, 0, 0x12, 0x34, 0, 7]);
+        let checksum = icmp::internet_checksum(&data[20..]);
+        data[22..24].copy_from_slice(&checksum.to_be_bytes());
+
+        let incoming = parse_icmpv4(&data, source).unwrap();
+        assert_eq!(incoming.msg_type, icmp::ICMPV4_TIME_EXCEEDED);
+        assert_eq!((incoming.id, incoming.seq_num), (0x1234, 7));
+        assert_eq!(incoming.ttl, Some(64));
+        assert!(!incoming.is_echo_reply());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn it_finds_the_echo_request_behind_a_link_header() {
+        let mut data = vec![0u8; 14];
+        data.extend_from_slice(&[
+            0x45, 0, 0, 28, 0, 0, 0, 0, 64, 1, 0, 0, 127, 0, 0, 1, 127, 0, 0, 1,
+        ]);
+        data.extend_from_slice(&[icmp::ICMPV4_ECHO_REQUEST, 0, 0, 0, 0x12, 0x34, 0, 7]);
+        assert_eq!(looped_echo_request(&data, false), Some((0x1234, 7)));
+        assert_eq!(looped_echo_request(&data[34..], false), Some((0x1234, 7)));
+        assert_eq!(looped_echo_request(&data, true), None);
+    }
+
+    #[test]
+    fn it_ignores_echo_requests() {
+        let source = IpAddr::V6("::1".parse().unwrap());
+        let data = [icmp::ICMPV6_ECHO_REQUEST, 0, 0, 0, 0x12, 0x34, 0, 7];
+        assert!(parse_icmpv6(&data, source).is_none());
+    }
 }
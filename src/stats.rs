@@ -0,0 +1,126 @@
+use std::time::Duration;
+
+use crate::pinger::PingEvent;
+
+/// Running statistics for a ping session, fed from the events of a `Pinger`.
+#[derive(Debug, Clone, Default)]
+pub struct PingStatistics {
+    pub transmitted: u32,
+    pub received: u32,
+    pub duplicates: u32,
+    pub errors: u32,
+    min_rtt: Option<Duration>,
+    max_rtt: Option<Duration>,
+    last_rtt: Option<Duration>,
+    total_rtt: Duration,
+    total_rtt_squared_ms: f64,
+}
+
+impl PingStatistics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&mut self, event: &PingEvent) {
+        match event {
+            PingEvent::Sent { .. } => self.transmitted += 1,
+            PingEvent::Reply(reply) => self.update_success(reply.rtt),
+            PingEvent::Duplicate(_) => self.duplicates += 1,
+            PingEvent::IcmpError { .. } | PingEvent::ReceiveFailed { .. } => self.errors += 1,
+            PingEvent::Timeout { .. } | PingEvent::SendFailed { .. } => {}
+        }
+    }
+
+    pub fn update_success(&mut self, rtt: Duration) {
+        self.received += 1;
+        self.total_rtt += rtt;
+        let rtt_ms = rtt.as_secs_f64() * 1000.0;
+        self.total_rtt_squared_ms += rtt_ms * rtt_ms;
+        self.min_rtt = Some(self.min_rtt.map_or(rtt, |min| min.min(rtt)));
+        self.max_rtt = Some(self.max_rtt.map_or(rtt, |max| max.max(rtt)));
+        self.last_rtt = Some(rtt);
+    }
+
+    /// Adds the probes counted by `other`, for totals across several sessions.
+    pub fn merge(&mut self, other: &PingStatistics) {
+        self.transmitted += other.transmitted;
+        self.received += other.received;
+        self.duplicates += other.duplicates;
+        self.errors += other.errors;
+        self.min_rtt = self.min_rtt.into_iter().chain(other.min_rtt).min();
+        self.max_rtt = self.max_rtt.into_iter().chain(other.max_rtt).max();
+        self.last_rtt = other.last_rtt.or(self.last_rtt);
+        self.total_rtt += other.total_rtt;
+        self.total_rtt_squared_ms += other.total_rtt_squared_ms;
+    }
+
+    /// Probes that were sent but never answered with an echo reply.
+    pub fn lost(&self) -> u32 {
+        self.transmitted.saturating_sub(self.received)
+    }
+
+    pub fn loss_percent(&self) -> f64 {
+        if self.transmitted == 0 {
+            return 0.0;
+        }
+        self.lost() as f64 * 100.0 / self.transmitted as f64
+    }
+
+    pub fn min_rtt(&self) -> Option<Duration> {
+        self.min_rtt
+    }
+
+    pub fn max_rtt(&self) -> Option<Duration> {
+        self.max_rtt
+    }
+
+    /// The round trip time of the latest reply.
+    pub fn last_rtt(&self) -> Option<Duration> {
+        self.last_rtt
+    }
+
+    pub fn avg_rtt(&self) -> Option<Duration> {
+        if self.received > 0 {
+            Some(self.total_rtt / self.received)
+        } else {
+            None
+        }
+    }
+
+    /// Standard deviation of the round trip times, like ping's `mdev`.
+    pub fn mdev_rtt(&self) -> Option<Duration> {
+        let avg_ms = self.avg_rtt()?.as_secs_f64() * 1000.0;
+        let variance = self.total_rtt_squared_ms / self.received as f64 - avg_ms * avg_ms;
+        Some(Duration::from_secs_f64(variance.max(0.0).sqrt() / 1000.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_computes_rtt_summary() {
+        let mut stats = PingStatistics::new();
+        stats.transmitted = 4;
+        stats.update_success(Duration::from_millis(10));
+        stats.update_success(Duration::from_millis(30));
+
+        assert_eq!(stats.lost(), 2);
+        assert_eq!(stats.loss_percent(), 50.0);
+        assert_eq!(stats.min_rtt(), Some(Duration::from_millis(10)));
+        assert_eq!(stats.max_rtt(), Some(Duration::from_millis(30)));
+        assert_eq!(stats.avg_rtt(), Some(Duration::from_millis(20)));
+        assert_eq!(stats.last_rtt(), Some(Duration::from_millis(30)));
+        assert!((stats.mdev_rtt().unwrap().as_secs_f64() * 1000.0 - 10.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn it_has_no_rtt_without_replies() {
+        let stats = PingStatistics::new();
+        assert_eq!(stats.loss_percent(), 0.0);
+        assert!(stats.avg_rtt().is_none());
+        assert!(stats.last_rtt().is_none());
+        assert!(stats.mdev_rtt().is_none());
+    }
+}
@@ -0,0 +1,303 @@
+//! Host discovery: an echo request to every address of a range, all through one
+//! identifier on the session's shared socket.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, Ipv4Addr},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::{
+    sync::mpsc,
+    time::{sleep_until, Instant},
+};
+
+use crate::{
+    error::PingError,
+    icmp::{self, get_icmp_id},
+    ip,
+    session::Session,
+    transport::{Received, SendOptions},
+};
+
+/// Most addresses a single sweep covers, one for every sequence number.
+pub const MAX_ADDRESSES: usize = 1 << 16;
+
+/// An inclusive run of IPv4 addresses to sweep.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AddressRange {
+    pub first: Ipv4Addr,
+    pub last: Ipv4Addr,
+}
+
+impl AddressRange {
+    pub fn addresses(&self) -> Vec<Ipv4Addr> {
+        (u32::from(self.first)..=u32::from(self.last))
+            .map(Ipv4Addr::from)
+            .collect()
+    }
+}
+
+impl fmt::Display for AddressRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.first, self.last)
+    }
+}
+
+impl FromStr for AddressRange {
+    type Err = String;
+
+    /// Parses `10.0.0.0/24` or `10.0.0.1-10.0.0.20`. CIDR blocks leave out their
+    /// network and broadcast addresses, except for /31 and /32.
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "'{}' is not an IPv4 CIDR block (10.0.0.0/24) or range (10.0.0.1-10.0.0.20)",
+                spec
+            )
+        };
+        let range = if let Some((network, prefix)) = spec.split_once('/') {
+            let network = u32::from(network.parse::<Ipv4Addr>().map_err(|_| invalid())?);
+            let prefix = prefix.parse::<u32>().ok().filter(|prefix| *prefix <= 32);
+            let prefix = prefix.ok_or_else(invalid)?;
+            let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            let (first, last) = (network & mask, network | !mask);
+            let (first, last) = if prefix <= 30 {
+                (first + 1, last - 1)
+            } else {
+                (first, last)
+            };
+            AddressRange {
+                first: first.into(),
+                last: last.into(),
+            }
+        } else if let Some((first, last)) = spec.split_once('-') {
+            let first = first.trim().parse::<Ipv4Addr>().map_err(|_| invalid())?;
+            let last = last.trim().parse::<Ipv4Addr>().map_err(|_| invalid())?;
+            if first > last {
+                return Err(format!("{} comes after {} in '{}'", first, last, spec));
+            }
+            AddressRange { first, last }
+        } else {
+            return Err(invalid());
+        };
+
+        let count = (u32::from(range.last) - u32::from(range.first)) as usize + 1;
+        if count > MAX_ADDRESSES {
+            return Err(format!(
+                "'{}' covers {} addresses, more than the {} a sweep can",
+                spec, count, MAX_ADDRESSES
+            ));
+        }
+        Ok(range)
+    }
+}
+
+/// How fast and how persistently to sweep.
+#[derive(Debug, Clone)]
+pub struct SweepConfig {
+    /// Echo requests per second, across all addresses.
+    pub rate: u32,
+    /// Extra rounds for addresses that didn't answer.
+    pub retries: u32,
+    /// How long to wait for replies after a round's last request.
+    pub timeout: Duration,
+    pub ttl: u8,
+    pub tos: u8,
+    pub payload_size: usize,
+    /// Address to put in our IP header, instead of the one routing picks for each host.
+    pub source: Option<IpAddr>,
+}
+
+impl SweepConfig {
+    pub fn new() -> Self {
+        SweepConfig {
+            rate: 100,
+            retries: 1,
+            timeout: Duration::from_millis(1000),
+            ttl: 64,
+            tos: 0,
+            payload_size: icmp::DEFAULT_PAYLOAD_SIZE,
+            source: None,
+        }
+    }
+}
+
+impl Default for SweepConfig {
+    fn default() -> Self {
+        SweepConfig::new()
+    }
+}
+
+/// What a sweep found out about one address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostState {
+    /// Answered with an echo reply after `rtt`.
+    Alive { rtt: Duration },
+    /// A router answered for it with an ICMP error.
+    Unreachable { from: IpAddr, message: String },
+    /// Never answered.
+    Silent,
+}
+
+/// The outcome of a sweep, in address order.
+#[derive(Debug, Clone)]
+pub struct SweepReport {
+    pub hosts: Vec<(Ipv4Addr, HostState)>,
+    /// Echo requests sent, retries included.
+    pub sent: u32,
+}
+
+/// Sends an echo request to every address in `addresses` at `config.rate`, then
+/// repeats for those that stayed silent, up to `config.retries` more times.
+pub async fn sweep(
+    addresses: &[Ipv4Addr],
+    config: &SweepConfig,
+    session: Arc<Session>,
+) -> Result<SweepReport, PingError> {
+    let mut hosts = addresses
+        .iter()
+        .map(|address| (*address, HostState::Silent))
+        .collect::<Vec<_>>();
+    hosts.sort_by_key(|(address, _)| *address);
+    hosts.dedup_by_key(|(address, _)| *address);
+    let Some(&(first, _)) = hosts.first() else {
+        return Ok(SweepReport { hosts, sent: 0 });
+    };
+
+    let (transport, identifier) = session.lease(IpAddr::V4(first), get_icmp_id(None))?;
+    let without_ip_header = !transport.header_included();
+    let options = SendOptions {
+        ttl: config.ttl,
+        tos: config.tos,
+        mtu_discovery: None,
+        scope_id: 0,
+        flow_label: 0,
+        timeout: config.timeout,
+    };
+    let interval = Duration::from_secs(1) / config.rate.max(1);
+    let (waiter, mut incoming) = mpsc::unbounded_channel();
+    // probes that may still be answered, by sequence number: the host's index and send time
+    let mut probes: HashMap<u16, (usize, Instant)> = HashMap::new();
+    let mut next_seq: u16 = 0;
+    let mut sent = 0;
+    // why the session stopped receiving, which ends the sweep
+    let mut failed = None;
+
+    'rounds: for _ in 0..=config.retries {
+        let round = (0..hosts.len())
+            .filter(|index| hosts[*index].1 == HostState::Silent)
+            .collect::<Vec<_>>();
+        if round.is_empty() {
+            break;
+        }
+        let mut round = round.into_iter();
+        let mut next_send = Instant::now();
+        let mut deadline = None;
+        loop {
+            tokio::select! {
+                _ = sleep_until(next_send), if deadline.is_none() => {
+                    let Some(index) = round.next() else {
+                        deadline = Some(Instant::now() + config.timeout);
+                        continue;
+                    };
+                    next_send += interval;
+                    let destination = IpAddr::V4(hosts[index].0);
+                    // only a header we build ourselves needs the source filled in
+                    let source = if without_ip_header {
+                        IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+                    } else {
+                        config
+                            .source
+                            .or_else(|| transport.source_for(destination))
+                            .or_else(|| ip::get_machine_ipv4(hosts[index].0).map(IpAddr::V4))
+                            .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+                    };
+                    let seq = next_seq;
+                    next_seq = next_seq.wrapping_add(1);
+                    // a wrapped sequence number belongs to a long-finished probe
+                    if probes.remove(&seq).is_some() {
+                        session.unregister((identifier, seq));
+                    }
+                    let packet = icmp::IPV4Packet::new_echo_request(
+                        without_ip_header,
+                        source,
+                        destination,
+                        identifier,
+                        config.ttl,
+                        true,
+                        seq,
+                    )
+                    .with_payload_size(config.payload_size)
+                    .with_tos(config.tos)
+                    .serialize();
+                    session.register(&transport, (identifier, seq), waiter.clone());
+                    probes.insert(seq, (index, Instant::now()));
+                    if transport.send_to(&packet, destination, options).await.is_ok() {
+                        sent += 1;
+                    }
+                }
+                Some(received) = incoming.recv() => {
+                    let message = match received {
+                        Received::Message(message) => message,
+                        Received::Failed { error, .. } => {
+                            failed = Some(error);
+                            break 'rounds;
+                        }
+                        Received::SendTimestamp(_) => continue,
+                    };
+                    let Some(&(index, sent_at)) = probes.get(&message.seq_num) else {
+                        continue;
+                    };
+                    let (address, state) = &mut hosts[index];
+                    if *state != HostState::Silent {
+                        continue;
+                    }
+                    if message.is_echo_reply() {
+                        if message.source == IpAddr::V4(*address) {
+                            let rtt = message.received_at.saturating_duration_since(sent_at);
+                            *state = HostState::Alive { rtt };
+                        }
+                    } else {
+                        *state = HostState::Unreachable {
+                            from: message.source,
+                            message: icmp::describe_icmpv4_error(message.msg_type, message.code),
+                        };
+                    }
+                }
+                _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => break,
+            }
+        }
+    }
+
+    for seq in probes.keys() {
+        session.unregister((identifier, *seq));
+    }
+    session.release(identifier);
+    if let Some(error) = failed {
+        return Err(PingError::new(format!("Failed to receive: {}", error)));
+    }
+    Ok(SweepReport { hosts, sent })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_cidr_blocks_and_ranges() {
+        let block = "192.0.2.0/29".parse::<AddressRange>().unwrap();
+        assert_eq!(block.first, Ipv4Addr::new(192, 0, 2, 1));
+        assert_eq!(block.last, Ipv4Addr::new(192, 0, 2, 6));
+        let single = "192.0.2.7/32".parse::<AddressRange>().unwrap();
+        assert_eq!(single.addresses(), vec![Ipv4Addr::new(192, 0, 2, 7)]);
+        let range = "192.0.2.250-192.0.3.4".parse::<AddressRange>().unwrap();
+        assert_eq!(range.addresses().len(), 11);
+        assert!("192.0.2.9-192.0.2.1".parse::<AddressRange>().is_err());
+        assert!("10.0.0.0/8".parse::<AddressRange>().is_err());
+        assert!("192.0.2.0/33".parse::<AddressRange>().is_err());
+    }
+}
@@ -0,0 +1,151 @@
+//! Hosts to ping, one per line, as read from a targets file or stdin:
+//!
+//! ```text
+//! # core routers
+//! 192.0.2.1
+//! edge.example.com interval=200 size=64 label="edge router"
+//! ```
+
+use std::{io::BufRead, time::Duration};
+
+/// A host and the settings it overrides for itself.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HostEntry {
+    pub name: String,
+    /// Replaces `-i` for this host.
+    pub interval: Option<Duration>,
+    /// Replaces `-s` for this host.
+    pub size: Option<usize>,
+    /// Shown in place of the host name in summaries.
+    pub label: Option<String>,
+}
+
+impl HostEntry {
+    pub fn new(name: &str) -> HostEntry {
+        HostEntry {
+            name: name.to_string(),
+            ..HostEntry::default()
+        }
+    }
+}
+
+/// Parses one line: a host followed by `interval=<ms>`, `size=<bytes>` or
+/// `label=<text>`. Blank lines and comments give `None`.
+pub fn parse_line(line: &str) -> Result<Option<HostEntry>, String> {
+    let mut words = split_words(line)?.into_iter();
+    let Some(name) = words.next() else {
+        return Ok(None);
+    };
+    let mut entry = HostEntry::new(&name);
+    for word in words {
+        let Some((key, value)) = word.split_once('=') else {
+            return Err(format!(
+                "expected key=value after the host, found '{}'",
+                word
+            ));
+        };
+        match key {
+            "interval" => {
+                let millis = value
+                    .parse()
+                    .map_err(|_| format!("interval '{}' is not a number of milliseconds", value))?;
+                entry.interval = Some(Duration::from_millis(millis));
+            }
+            "size" => {
+                let size = value
+                    .parse()
+                    .map_err(|_| format!("size '{}' is not a number of bytes", value))?;
+                entry.size = Some(size);
+            }
+            "label" => entry.label = Some(value.to_string()),
+            _ => {
+                return Err(format!(
+                    "unknown setting '{}', expected interval, size or label",
+                    key
+                ))
+            }
+        }
+    }
+    Ok(Some(entry))
+}
+
+/// Every host in `reader` with its line number, or why its line couldn't be parsed.
+pub fn read_targets(
+    reader: impl BufRead,
+) -> std::io::Result<Vec<(usize, Result<HostEntry, String>)>> {
+    let mut entries = Vec::new();
+    for (index, line) in reader.lines().enumerate() {
+        match parse_line(&line?) {
+            Ok(Some(entry)) => entries.push((index + 1, Ok(entry))),
+            Ok(None) => {}
+            Err(e) => entries.push((index + 1, Err(e))),
+        }
+    }
+    Ok(entries)
+}
+
+/// Splits on whitespace, keeping double-quoted text together and stopping at `#`.
+fn split_words(line: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut quoted = false;
+    for c in line.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                in_word = true;
+            }
+            '#' if !quoted => break,
+            c if c.is_whitespace() && !quoted => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            c => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if quoted {
+        return Err("unterminated quote".to_string());
+    }
+    if in_word {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_hosts_with_overrides_and_skips_comments() {
+        let file = "# routers\n\n192.0.2.1\nedge.example interval=200 size=64 label=\"edge router\" # west\nbad.example ttl=3\n";
+        let entries = read_targets(file.as_bytes()).unwrap();
+
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0], (3, Ok(HostEntry::new("192.0.2.1"))));
+        assert_eq!(
+            entries[1],
+            (
+                4,
+                Ok(HostEntry {
+                    name: "edge.example".to_string(),
+                    interval: Some(Duration::from_millis(200)),
+                    size: Some(64),
+                    label: Some("edge router".to_string()),
+                })
+            )
+        );
+        assert_eq!(entries[2].0, 5);
+        assert!(entries[2]
+            .1
+            .as_ref()
+            .unwrap_err()
+            .contains("unknown setting 'ttl'"));
+    }
+}
@@ -0,0 +1,507 @@
+//! TCP SYN probes, for services behind firewalls that drop ICMP. Pingers still
+//! build echo requests; this transport sends each one as a SYN to a port, and a
+//! SYN-ACK or RST, whether the port is open or closed, is the echo reply.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6},
+    os::fd::AsRawFd,
+    sync::{Arc, Mutex},
+};
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{
+    io::unix::AsyncFd,
+    net::TcpSocket,
+    sync::{mpsc, Mutex as AsyncMutex},
+    time::{self, Instant},
+};
+
+use crate::{
+    error::SocketError,
+    icmp::{self, ICMPHeader},
+    ip::{self, BindTarget},
+    socket,
+    transport::{BoxFuture, IcmpTransport, Incoming, Received, SendOptions, TimestampSource},
+};
+
+const TCP_FLAG_SYN: u8 = 0x02;
+const TCP_FLAG_RST: u8 = 0x04;
+const TCP_FLAG_ACK: u8 = 0x10;
+
+/// Sends SYNs to one port of hosts of one address family.
+///
+/// With a raw socket, which takes root or CAP_NET_RAW and Linux, probes are
+/// half-open: we craft the SYN, carrying the echo identifier and sequence number
+/// in its sequence number, and nothing on our side listens for the answer, so
+/// the kernel resets the connection. Otherwise each probe is a `connect()`.
+#[derive(Debug)]
+pub struct TcpProbeSocket {
+    is_ipv6: bool,
+    port: u16,
+    probing: Probing,
+}
+
+#[derive(Debug)]
+enum Probing {
+    Raw {
+        socket: AsyncFd<Socket>,
+        // a socket bound to the port our SYNs come from, so nothing else takes it
+        _reserved: Socket,
+        local_port: u16,
+        // address SYNs go out from when -I names one, or an interface's
+        source: Option<IpAddr>,
+        // otherwise the address the route to each destination picks
+        routes: Mutex<HashMap<IpAddr, Option<IpAddr>>>,
+        // what segments are read into, kept between receives
+        buffer: AsyncMutex<Vec<u8>>,
+        // options last set on the raw socket
+        options: Mutex<Option<SendOptions>>,
+    },
+    Connect {
+        bind: Option<BindTarget>,
+        // outcomes of connects still finishing in the background
+        answers: mpsc::UnboundedSender<Received>,
+        answered: AsyncMutex<mpsc::UnboundedReceiver<Received>>,
+    },
+}
+
+impl TcpProbeSocket {
+    /// Opens a raw TCP socket if we may, bound to `bind` if given, that sends
+    /// SYNs to `port`. Falls back to connecting.
+    pub fn open(
+        is_ipv6: bool,
+        port: u16,
+        bind: Option<&BindTarget>,
+    ) -> Result<TcpProbeSocket, SocketError> {
+        match open_raw(is_ipv6, bind) {
+            Ok(probing) => Ok(TcpProbeSocket {
+                is_ipv6,
+                port,
+                probing,
+            }),
+            Err(e)
+                if socket::is_permission_denied(&e) || e.kind() == io::ErrorKind::Unsupported =>
+            {
+                Ok(TcpProbeSocket::connecting(is_ipv6, port, bind))
+            }
+            Err(e) => Err(SocketError::new(format!(
+                "Failed to open raw TCP socket: {}",
+                e
+            ))),
+        }
+    }
+
+    /// Probes `port` with a `connect()` for each probe, even where we could
+    /// send half-open SYNs.
+    pub fn connecting(is_ipv6: bool, port: u16, bind: Option<&BindTarget>) -> TcpProbeSocket {
+        let (answers, answered) = mpsc::unbounded_channel();
+        TcpProbeSocket {
+            is_ipv6,
+            port,
+            probing: Probing::Connect {
+                bind: bind.cloned(),
+                answers,
+                answered: AsyncMutex::new(answered),
+            },
+        }
+    }
+
+    /// The address raw SYNs to `destination` go out from, which their checksum covers.
+    fn raw_source(&self, destination: IpAddr) -> Option<IpAddr> {
+        let Probing::Raw {
+            source, ref routes, ..
+        } = self.probing
+        else {
+            return None;
+        };
+        source.or_else(|| {
+            let mut routes = routes.lock().unwrap();
+            *routes
+                .entry(destination)
+                .or_insert_with(|| ip::route_source(destination))
+        })
+    }
+
+    async fn send_probe(
+        &self,
+        packet: &[u8],
+        destination: IpAddr,
+        options: SendOptions,
+    ) -> io::Result<usize> {
+        let request = ICMPHeader::deserialize(packet)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let address = match destination {
+            IpAddr::V6(destination) => SocketAddr::V6(SocketAddrV6::new(
+                destination,
+                self.port,
+                0,
+                options.scope_id,
+            )),
+            IpAddr::V4(_) => SocketAddr::new(destination, self.port),
+        };
+        match self.probing {
+            Probing::Raw {
+                ref socket,
+                local_port,
+                options: ref current,
+                ..
+            } => {
+                let isn = (request.id as u32) << 16 | request.seq_num as u32;
+                let mut segment = syn_segment(local_port, self.port, isn);
+                // the kernel fills in IPv6 checksums for us, see open_raw
+                if let (IpAddr::V4(destination), Some(IpAddr::V4(source))) =
+                    (destination, self.raw_source(destination))
+                {
+                    let checksum = tcp_checksum_v4(source, destination, &segment);
+                    segment[16..18].copy_from_slice(&checksum.to_be_bytes());
+                }
+                let address = SocketAddr::new(address.ip(), 0).into();
+                loop {
+                    let mut guard = socket.writable().await?;
+                    let sent = guard.try_io(|raw| {
+                        let raw = raw.get_ref();
+                        let mut current = current.lock().unwrap();
+                        if *current != Some(options) {
+                            socket::apply_options(raw, self.is_ipv6, options, *current)?;
+                            *current = Some(options);
+                        }
+                        raw.send_to(&segment, &address)
+                    });
+                    match sent {
+                        Ok(result) => return result,
+                        Err(_would_block) => continue,
+                    }
+                }
+            }
+            Probing::Connect {
+                ref bind,
+                ref answers,
+                ..
+            } => {
+                let domain = Domain::for_address(address);
+                let probe = Socket::new(domain, Type::STREAM, Some(Protocol::TCP))?;
+                if let Some(target) = bind {
+                    socket::bind_socket(&probe, target, self.is_ipv6)?;
+                }
+                socket::apply_options(&probe, self.is_ipv6, options, None)?;
+                // give up after one retransmission instead of minutes of them
+                #[cfg(target_os = "linux")]
+                socket::set_int_option(probe.as_raw_fd(), libc::IPPROTO_TCP, libc::TCP_SYNCNT, 1)?;
+                probe.set_nonblocking(true)?;
+                let probe = TcpSocket::from_std_stream(probe.into());
+                let answers = answers.clone();
+                tokio::spawn(async move {
+                    // a connect the kernel is still retrying is a probe already timed out
+                    let Ok(connected) =
+                        time::timeout(options.timeout, probe.connect(address)).await
+                    else {
+                        return;
+                    };
+                    let received_at = Instant::now();
+                    let (msg_type, code) = match connected {
+                        Ok(_) => (echo_reply_type(address.ip()), 0),
+                        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                            (echo_reply_type(address.ip()), 0)
+                        }
+                        // no answer in the time the kernel gave it, which is a loss
+                        Err(e) if is_unanswered(&e) => return,
+                        Err(e) => match unreachable_type(address.ip(), &e) {
+                            Some(unreachable) => unreachable,
+                            None => {
+                                let _ = answers.send(Received::Failed {
+                                    id: request.id,
+                                    seq_num: request.seq_num,
+                                    error: Arc::new(e),
+                                });
+                                return;
+                            }
+                        },
+                    };
+                    let _ = answers.send(Received::Message(Incoming {
+                        source: address.ip(),
+                        bytes: None,
+                        msg_type,
+                        code,
+                        id: request.id,
+                        seq_num: request.seq_num,
+                        ttl: None,
+                        tos: None,
+                        mtu: None,
+                        received_at,
+                        timestamp: TimestampSource::Userspace,
+                    }));
+                });
+                Ok(0)
+            }
+        }
+    }
+
+    async fn recv_answer(&self) -> io::Result<Option<Received>> {
+        match self.probing {
+            Probing::Raw {
+                ref socket,
+                local_port,
+                ..
+            } => self.recv_raw(socket, local_port).await,
+            Probing::Connect { ref answered, .. } => Ok(answered.lock().await.recv().await),
+        }
+    }
+
+    /// Reads a segment from the raw socket, which sees every TCP segment that
+    /// arrives. Only answers from our probe port to our local port are kept.
+    async fn recv_raw(
+        &self,
+        socket: &AsyncFd<Socket>,
+        local_port: u16,
+    ) -> io::Result<Option<Received>> {
+        let Probing::Raw { ref buffer, .. } = self.probing else {
+            return Ok(None);
+        };
+        let mut data = buffer.lock().await;
+        let message = loop {
+            let mut guard = socket.readable().await?;
+            match guard.try_io(|raw| socket::recv_msg(raw.get_ref().as_raw_fd(), &mut data, 0)) {
+                Ok(result) => break result?,
+                Err(_would_block) => continue,
+            }
+        };
+        let Some(source) = message.source else {
+            return Ok(None);
+        };
+        let received = &data[..message.len.min(data.len())];
+        let (segment, ttl, tos) = match source {
+            // raw IPv4 sockets keep the IP header. Checksums aren't checked: with
+            // offloading, local segments reach us before they are filled in
+            IpAddr::V4(_) => {
+                let ihl = (*received.first().unwrap_or(&0) & 0x0F) as usize * 4;
+                let Some(segment) = received.get(ihl..) else {
+                    return Ok(None);
+                };
+                (segment, received.get(8).copied(), received.get(1).copied())
+            }
+            IpAddr::V6(_) => (received, message.hop_limit(), message.tos()),
+        };
+        let Some((id, seq_num)) = parse_answer(segment, self.port, local_port) else {
+            return Ok(None);
+        };
+        let (received_at, timestamp) = message
+            .timestamp()
+            .unwrap_or_else(|| (Instant::now(), TimestampSource::Userspace));
+        Ok(Some(Received::Message(Incoming {
+            source,
+            bytes: Some(message.len),
+            msg_type: echo_reply_type(source),
+            code: 0,
+            id,
+            seq_num,
+            ttl,
+            tos,
+            mtu: None,
+            received_at,
+            timestamp,
+        })))
+    }
+}
+
+impl IcmpTransport for TcpProbeSocket {
+    fn header_included(&self) -> bool {
+        false
+    }
+
+    fn source_for(&self, destination: IpAddr) -> Option<IpAddr> {
+        match self.probing {
+            Probing::Raw { .. } => self.raw_source(destination),
+            Probing::Connect { .. } => None,
+        }
+    }
+
+    fn send_to<'a>(
+        &'a self,
+        packet: &'a [u8],
+        destination: IpAddr,
+        options: SendOptions,
+    ) -> BoxFuture<'a, io::Result<usize>> {
+        Box::pin(self.send_probe(packet, destination, options))
+    }
+
+    fn recv(&self) -> BoxFuture<'_, io::Result<Option<Received>>> {
+        Box::pin(self.recv_answer())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn open_raw(is_ipv6: bool, bind: Option<&BindTarget>) -> io::Result<Probing> {
+    let (domain, unspecified) = if is_ipv6 {
+        (Domain::IPV6, IpAddr::V6(Ipv6Addr::UNSPECIFIED))
+    } else {
+        (Domain::IPV4, IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+    };
+    let raw = Socket::new(domain, Type::RAW, Some(Protocol::TCP))?;
+    let reserved = Socket::new(domain, Type::STREAM, Some(Protocol::TCP))?;
+    let bound = match bind {
+        Some(BindTarget::Address(address)) if address.is_ipv6() == is_ipv6 => Some(*address),
+        _ => None,
+    };
+    let source = match bind {
+        Some(BindTarget::Interface(name)) => ip::interface_address(name, unspecified).ok(),
+        _ => bound,
+    };
+    if let Some(target) = bind {
+        socket::bind_socket(&raw, target, is_ipv6)?;
+    }
+    reserved.bind(&SocketAddr::new(bound.unwrap_or(unspecified), 0).into())?;
+    let local_port = reserved
+        .local_addr()?
+        .as_socket()
+        .map(|addr| addr.port())
+        .unwrap_or_default();
+    if is_ipv6 {
+        // offset of the checksum in the TCP header
+        socket::set_int_option(raw.as_raw_fd(), libc::IPPROTO_IPV6, libc::IPV6_CHECKSUM, 16)?;
+    }
+    raw.set_nonblocking(true)?;
+    socket::enable_timestamps(raw.as_raw_fd());
+    socket::enable_header_fields(raw.as_raw_fd(), is_ipv6);
+    Ok(Probing::Raw {
+        socket: AsyncFd::new(raw)?,
+        _reserved: reserved,
+        local_port,
+        source,
+        routes: Mutex::new(HashMap::new()),
+        buffer: AsyncMutex::new(socket::receive_buffer()),
+        options: Mutex::new(None),
+    })
+}
+
+/// Elsewhere raw TCP sockets never see the answers.
+#[cfg(not(target_os = "linux"))]
+fn open_raw(_is_ipv6: bool, _bind: Option<&BindTarget>) -> io::Result<Probing> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "half-open probes are only supported on Linux",
+    ))
+}
+
+/// Whether a connect failed because nothing answered it.
+fn is_unanswered(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+    )
+}
+
+/// The Destination Unreachable a failed connect stands for, when the kernel
+/// failed it because of one.
+fn unreachable_type(destination: IpAddr, error: &io::Error) -> Option<(u8, u8)> {
+    let unreachable = match (destination, error.raw_os_error()?) {
+        (IpAddr::V4(_), libc::ENETUNREACH) => (icmp::ICMPV4_DESTINATION_UNREACHABLE, 0),
+        (IpAddr::V4(_), libc::EHOSTUNREACH) => (icmp::ICMPV4_DESTINATION_UNREACHABLE, 1),
+        (IpAddr::V6(_), libc::ENETUNREACH) => (icmp::ICMPV6_DESTINATION_UNREACHABLE, 0),
+        (IpAddr::V6(_), libc::EHOSTUNREACH) => (icmp::ICMPV6_DESTINATION_UNREACHABLE, 3),
+        _ => return None,
+    };
+    Some(unreachable)
+}
+
+fn echo_reply_type(source: IpAddr) -> u8 {
+    match source {
+        IpAddr::V4(_) => icmp::ICMPV4_ECHO_REPLY,
+        IpAddr::V6(_) => icmp::ICMPV6_ECHO_REPLY,
+    }
+}
+
+/// A SYN with an MSS option and sequence number `isn`, its checksum left at 0.
+pub fn syn_segment(source_port: u16, destination_port: u16, isn: u32) -> Vec<u8> {
+    let mut segment = Vec::with_capacity(24);
+    segment.extend_from_slice(&source_port.to_be_bytes());
+    segment.extend_from_slice(&destination_port.to_be_bytes());
+    segment.extend_from_slice(&isn.to_be_bytes());
+    segment.extend_from_slice(&0u32.to_be_bytes());
+    // six words of header, then the flags
+    segment.extend_from_slice(&[6 << 4, TCP_FLAG_SYN]);
+    segment.extend_from_slice(&64240u16.to_be_bytes());
+    segment.extend_from_slice(&[0, 0, 0, 0]);
+    // maximum segment size 1460
+    segment.extend_from_slice(&[2, 4, 0x05, 0xB4]);
+    segment
+}
+
+/// The checksum of `segment` over the IPv4 pseudo-header.
+fn tcp_checksum_v4(source: Ipv4Addr, destination: Ipv4Addr, segment: &[u8]) -> u16 {
+    let mut data = Vec::with_capacity(12 + segment.len());
+    data.extend_from_slice(&source.octets());
+    data.extend_from_slice(&destination.octets());
+    data.extend_from_slice(&[0, 6]);
+    data.extend_from_slice(&(segment.len() as u16).to_be_bytes());
+    data.extend_from_slice(segment);
+    icmp::internet_checksum(&data)
+}
+
+/// The identifier and sequence number of the probe a SYN-ACK or RST answers,
+/// from its acknowledgment number, for segments from `remote_port` to
+/// `local_port`.
+pub fn parse_answer(segment: &[u8], remote_port: u16, local_port: u16) -> Option<(u16, u16)> {
+    let header = segment.get(..20)?;
+    let source_port = u16::from_be_bytes([header[0], header[1]]);
+    let destination_port = u16::from_be_bytes([header[2], header[3]]);
+    let flags = header[13];
+    if source_port != remote_port
+        || destination_port != local_port
+        || flags & TCP_FLAG_ACK == 0
+        || flags & (TCP_FLAG_SYN | TCP_FLAG_RST) == 0
+    {
+        return None;
+    }
+    let isn = u32::from_be_bytes([header[8], header[9], header[10], header[11]]).wrapping_sub(1);
+    Some(((isn >> 16) as u16, isn as u16))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_matches_syn_acks_and_resets_to_the_syn() {
+        let source = Ipv4Addr::new(10, 0, 0, 1);
+        let destination = Ipv4Addr::new(10, 0, 0, 2);
+        let mut syn = syn_segment(40000, 443, 0x1234_0007);
+        let checksum = tcp_checksum_v4(source, destination, &syn);
+        syn[16..18].copy_from_slice(&checksum.to_be_bytes());
+        assert_eq!(tcp_checksum_v4(source, destination, &syn), 0);
+
+        // the answer swaps the ports and acknowledges our sequence number
+        let mut answer = syn_segment(443, 40000, 0xCAFE);
+        answer[8..12].copy_from_slice(&0x1234_0008u32.to_be_bytes());
+        answer[13] = TCP_FLAG_SYN | TCP_FLAG_ACK;
+        assert_eq!(parse_answer(&answer, 443, 40000), Some((0x1234, 7)));
+        answer[13] = TCP_FLAG_RST | TCP_FLAG_ACK;
+        assert_eq!(parse_answer(&answer, 443, 40000), Some((0x1234, 7)));
+        assert_eq!(parse_answer(&answer, 80, 40000), None);
+        answer[13] = TCP_FLAG_SYN;
+        assert_eq!(parse_answer(&answer, 443, 40000), None);
+    }
+
+    #[test]
+    fn it_reports_unreachable_connects_as_destination_unreachable() {
+        let v4 = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let v6 = "2001:db8::2".parse().unwrap();
+        let host = io::Error::from_raw_os_error(libc::EHOSTUNREACH);
+        let network = io::Error::from_raw_os_error(libc::ENETUNREACH);
+        assert_eq!(
+            unreachable_type(v4, &host),
+            Some((icmp::ICMPV4_DESTINATION_UNREACHABLE, 1))
+        );
+        assert_eq!(
+            unreachable_type(v6, &network),
+            Some((icmp::ICMPV6_DESTINATION_UNREACHABLE, 0))
+        );
+        let timed_out = io::Error::from_raw_os_error(libc::ETIMEDOUT);
+        assert!(is_unanswered(&timed_out));
+        assert!(is_unanswered(&io::Error::from_raw_os_error(libc::EAGAIN)));
+        assert!(!is_unanswered(&host));
+        assert_eq!(unreachable_type(v4, &timed_out), None);
+        assert_eq!(unreachable_type(v4, &io::Error::other("closed")), None);
+    }
+}
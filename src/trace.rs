@@ -0,0 +1,299 @@
+use std::{net::IpAddr, ops::RangeInclusive, sync::Arc, time::Duration};
+
+use tokio::task::JoinSet;
+use tokio_stream::StreamExt;
+
+use crate::{
+    error::PingError,
+    icmp,
+    pinger::{Flow, PingConfig, PingEvent, Pinger},
+    session::Session,
+    stats::PingStatistics,
+};
+
+/// What one probe with a limited TTL ran into.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HopProbe {
+    /// A router on the way said the probe's TTL ran out.
+    TimeExceeded {
+        from: IpAddr,
+        rtt: Duration,
+    },
+    /// The destination answered.
+    Reply {
+        from: IpAddr,
+        rtt: Duration,
+    },
+    /// Some other ICMP error, such as Destination Unreachable, which ends the trace.
+    Unreachable {
+        from: IpAddr,
+        rtt: Duration,
+        message: String,
+    },
+    Lost,
+}
+
+impl HopProbe {
+    /// The address that answered, if any did.
+    pub fn from(&self) -> Option<IpAddr> {
+        match self {
+            HopProbe::TimeExceeded { from, .. }
+            | HopProbe::Reply { from, .. }
+            | HopProbe::Unreachable { from, .. } => Some(*from),
+            HopProbe::Lost => None,
+        }
+    }
+
+    pub fn rtt(&self) -> Option<Duration> {
+        match self {
+            HopProbe::TimeExceeded { rtt, .. }
+            | HopProbe::Reply { rtt, .. }
+            | HopProbe::Unreachable { rtt, .. } => Some(*rtt),
+            HopProbe::Lost => None,
+        }
+    }
+}
+
+/// The probes sent with one TTL, in the order they were sent.
+#[derive(Debug, Clone)]
+pub struct Hop {
+    pub ttl: u8,
+    pub probes: Vec<HopProbe>,
+}
+
+impl Hop {
+    /// Whether the trace ends here: the destination answered, or something
+    /// reported it can't be reached.
+    pub fn is_last(&self) -> bool {
+        self.probes
+            .iter()
+            .any(|probe| matches!(probe, HopProbe::Reply { .. } | HopProbe::Unreachable { .. }))
+    }
+}
+
+/// Everything seen at one TTL over many rounds of probes.
+#[derive(Debug, Clone)]
+pub struct HopStats {
+    pub ttl: u8,
+    /// Addresses that answered, in the order they first did.
+    pub addresses: Vec<IpAddr>,
+    pub stats: PingStatistics,
+}
+
+impl HopStats {
+    pub fn new(ttl: u8) -> HopStats {
+        HopStats {
+            ttl,
+            addresses: Vec::new(),
+            stats: PingStatistics::new(),
+        }
+    }
+
+    /// Counts the probes of `hop`, which must have been sent with this TTL.
+    pub fn record(&mut self, hop: &Hop) {
+        for probe in &hop.probes {
+            self.stats.transmitted += 1;
+            if let Some(rtt) = probe.rtt() {
+                self.stats.update_success(rtt);
+            }
+            if let Some(from) = probe.from().filter(|from| !self.addresses.contains(from)) {
+                self.addresses.push(from);
+            }
+        }
+    }
+}
+
+/// How far and how thoroughly to trace.
+#[derive(Debug, Clone)]
+pub struct TraceConfig {
+    /// Destination and per-probe settings. Its TTL, hop limit, count and
+    /// interval are replaced for every hop.
+    pub probe: PingConfig,
+    pub first_ttl: u8,
+    pub max_hops: u8,
+    /// Probes sent with each TTL.
+    pub queries: u16,
+}
+
+impl TraceConfig {
+    pub fn new(destination: IpAddr) -> Self {
+        TraceConfig {
+            probe: PingConfig::new(destination),
+            first_ttl: 1,
+            max_hops: 30,
+            queries: 3,
+        }
+    }
+}
+
+/// Sends all of a hop's echo requests, with TTL `ttl`, at once and waits for each
+/// to be answered or time out. Time Exceeded errors are matched to probes by the
+/// echo request they quote.
+pub async fn probe_hop(
+    config: &TraceConfig,
+    ttl: u8,
+    session: &Arc<Session>,
+) -> Result<Hop, PingError> {
+    let mut probe = config.probe.clone();
+    probe.ttl = ttl;
+    probe.hop_limit = ttl;
+    probe.count = Some(config.queries);
+    probe.interval = Duration::ZERO;
+
+    let destination = probe.destination;
+    let pinger = Pinger::with_session(probe, session.clone())?;
+    let handle = pinger.handle();
+    let mut events = Box::pin(pinger.run());
+    let mut probes: Vec<Option<HopProbe>> = vec![None; config.queries as usize];
+    // a probe counts as answered by whatever came first; the stream is drained
+    // so every probe is unregistered before the next hop reuses its sequence
+    while let Some(event) = events.next().await {
+        let (seq, outcome) = match event {
+            PingEvent::Reply(reply) => (
+                reply.seq,
+                HopProbe::Reply {
+                    from: reply.source,
+                    rtt: reply.rtt,
+                },
+            ),
+            PingEvent::IcmpError {
+                seq,
+                source,
+                msg_type,
+                rtt,
+                message,
+                ..
+            } => {
+                let outcome = if is_time_exceeded(destination, msg_type) {
+                    HopProbe::TimeExceeded { from: source, rtt }
+                } else {
+                    HopProbe::Unreachable {
+                        from: source,
+                        rtt,
+                        message,
+                    }
+                };
+                (seq, outcome)
+            }
+            PingEvent::Timeout { seq } => (seq, HopProbe::Lost),
+            PingEvent::SendFailed { error, .. } => {
+                handle.stop();
+                return Err(PingError::new(format!(
+                    "Couldn't send a probe with TTL {}: {}",
+                    ttl, error
+                )));
+            }
+            PingEvent::ReceiveFailed { error, .. } => {
+                handle.stop();
+                return Err(PingError::new(format!(
+                    "Couldn't receive answers to probes with TTL {}: {}",
+                    ttl, error
+                )));
+            }
+            PingEvent::Sent { .. } | PingEvent::Duplicate(_) => continue,
+        };
+        if let Some(slot @ None) = probes.get_mut(seq as usize) {
+            *slot = Some(outcome);
+        }
+        if probes.iter().all(Option::is_some) {
+            handle.stop();
+        }
+    }
+    Ok(Hop {
+        ttl,
+        probes: probes
+            .into_iter()
+            .map(|probe| probe.unwrap_or(HopProbe::Lost))
+            .collect(),
+    })
+}
+
+fn is_time_exceeded(destination: IpAddr, msg_type: u8) -> bool {
+    match destination {
+        IpAddr::V4(_) => msg_type == icmp::ICMPV4_TIME_EXCEEDED,
+        IpAddr::V6(_) => msg_type == icmp::ICMPV6_TIME_EXCEEDED,
+    }
+}
+
+/// Probes every TTL in `ttls` at the same time, each with its own pinger, and
+/// returns the hops in TTL order. With a fixed flow the TTLs are probed one
+/// after another instead.
+pub async fn probe_hops(
+    config: &TraceConfig,
+    ttls: RangeInclusive<u8>,
+    session: &Arc<Session>,
+) -> Result<Vec<Hop>, PingError> {
+    // Paris probes keep one identifier too, which concurrent pingers can't share
+    if matches!(config.probe.flow, Some(Flow::Fixed(_))) {
+        let mut hops = Vec::new();
+        for ttl in ttls {
+            hops.push(probe_hop(config, ttl, session).await?);
+        }
+        return Ok(hops);
+    }
+    let mut probing = JoinSet::new();
+    for ttl in ttls {
+        let config = config.clone();
+        let session = session.clone();
+        probing.spawn(async move { probe_hop(&config, ttl, &session).await });
+    }
+    let mut hops = Vec::new();
+    while let Some(hop) = probing.join_next().await {
+        hops.push(hop.map_err(|e| PingError::new(e.to_string()))??);
+    }
+    hops.sort_by_key(|hop| hop.ttl);
+    Ok(hops)
+}
+
+/// Most next hops looked for at one TTL.
+pub const MAX_NEXT_HOPS: usize = 16;
+
+/// Probes, each on its own flow, needed to rule out one more next hop than the
+/// `found` seen so far with 95% confidence, as in the Multipath Detection
+/// Algorithm: the chance that `found + 1` evenly balanced next hops all hid
+/// behind `found` of them is at most 5%.
+pub fn mda_probes(found: usize) -> usize {
+    let found = found.max(1) as f64;
+    let missed = (0.05 / (found + 1.0)).ln() / (found / (found + 1.0)).ln();
+    missed.ceil() as usize
+}
+
+/// Finds every next hop load balancers spread flows over at `ttl`, by sending
+/// probes with a different flow each until `mda_probes` says no more are likely.
+/// The hop's probes are in flow order, starting at flow 0, so the same flow
+/// leads through the same routers at every TTL.
+pub async fn enumerate_hop(
+    config: &TraceConfig,
+    ttl: u8,
+    session: &Arc<Session>,
+) -> Result<Hop, PingError> {
+    let mut probes: Vec<HopProbe> = Vec::new();
+    loop {
+        let mut found: Vec<IpAddr> = probes.iter().filter_map(HopProbe::from).collect();
+        found.sort();
+        found.dedup();
+        let needed = mda_probes(found.len());
+        if probes.len() >= needed || found.len() >= MAX_NEXT_HOPS {
+            break;
+        }
+        let mut batch = config.clone();
+        batch.queries = (needed - probes.len()) as u16;
+        batch.probe.flow = Some(Flow::PerProbe {
+            start: probes.len() as u16,
+        });
+        probes.extend(probe_hop(&batch, ttl, session).await?.probes);
+    }
+    Ok(Hop { ttl, probes })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_needs_the_published_mda_probe_counts() {
+        let counts = (1..=5).map(mda_probes).collect::<Vec<_>>();
+        assert_eq!(counts, vec![6, 11, 16, 21, 27]);
+        assert_eq!(mda_probes(0), 6);
+    }
+}
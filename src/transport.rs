@@ -0,0 +1,211 @@
+use std::{
+    fmt::{self, Debug},
+    future::Future,
+    io,
+    net::IpAddr,
+    pin::Pin,
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+use crate::icmp;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Where a send or receive time was taken, from least to most accurate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TimestampSource {
+    /// Read with `Instant::now()` around the socket call.
+    Userspace,
+    /// Stamped by the kernel network stack.
+    Kernel,
+    /// Stamped by the network card.
+    Hardware,
+}
+
+impl fmt::Display for TimestampSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TimestampSource::Userspace => "userspace",
+            TimestampSource::Kernel => "kernel",
+            TimestampSource::Hardware => "hardware",
+        })
+    }
+}
+
+/// What probes go out as. Pingers always build echo requests; transports for
+/// other protocols send each one as a probe of their own.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ProbeProtocol {
+    #[default]
+    Icmp,
+    /// UDP datagrams to `port`, or to high ports counting up from `udp::BASE_PORT`.
+    Udp { port: Option<u16> },
+    /// TCP SYNs to `port`.
+    Tcp { port: u16 },
+}
+
+impl fmt::Display for ProbeProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProbeProtocol::Icmp => f.write_str("ICMP echo"),
+            ProbeProtocol::Udp { port: None } => f.write_str("UDP to high ports"),
+            ProbeProtocol::Udp { port: Some(port) } => write!(f, "UDP to port {}", port),
+            ProbeProtocol::Tcp { port } => write!(f, "TCP SYN to port {}", port),
+        }
+    }
+}
+
+/// An ICMP message that answers, or reports a problem with, one of our echo requests.
+#[derive(Debug, Clone)]
+pub struct Incoming {
+    pub source: IpAddr,
+    /// Size of the message, when the transport sees it.
+    pub bytes: Option<usize>,
+    pub msg_type: u8,
+    pub code: u8,
+    /// Identifier and sequence number of the echo request this message refers to.
+    pub id: u16,
+    pub seq_num: u16,
+    /// TTL or hop limit the message arrived with, when the transport can see it.
+    pub ttl: Option<u8>,
+    /// TOS or traffic class byte the message arrived with, when the transport can see it.
+    pub tos: Option<u8>,
+    /// Next-hop MTU advertised by a Fragmentation Needed or Packet Too Big error.
+    pub mtu: Option<u32>,
+    pub received_at: Instant,
+    pub timestamp: TimestampSource,
+}
+
+impl Incoming {
+    pub fn is_echo_reply(&self) -> bool {
+        match self.source {
+            IpAddr::V4(_) => self.msg_type == icmp::ICMPV4_ECHO_REPLY,
+            IpAddr::V6(_) => self.msg_type == icmp::ICMPV6_ECHO_REPLY,
+        }
+    }
+}
+
+/// How probes treat fragmentation and the path MTU, like ping's `-M`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MtuDiscovery {
+    /// Set "don't fragment" and never send more than the known path MTU.
+    Do,
+    /// Set "don't fragment" unless the packet exceeds the known path MTU.
+    Want,
+    /// Let routers, or for IPv6 the kernel, fragment.
+    Dont,
+    /// Set "don't fragment" and ignore the known path MTU.
+    Probe,
+}
+
+impl MtuDiscovery {
+    pub fn sets_dont_fragment(self) -> bool {
+        self != MtuDiscovery::Dont
+    }
+}
+
+impl FromStr for MtuDiscovery {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "do" => Ok(MtuDiscovery::Do),
+            "want" => Ok(MtuDiscovery::Want),
+            "dont" => Ok(MtuDiscovery::Dont),
+            "probe" => Ok(MtuDiscovery::Probe),
+            _ => Err(format!("'{}' is not one of do, want, dont or probe", value)),
+        }
+    }
+}
+
+/// Per-packet IP settings for an echo request, and how long it waits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendOptions {
+    /// TTL or hop limit.
+    pub ttl: u8,
+    /// IPv4 TOS or IPv6 traffic class byte.
+    pub tos: u8,
+    /// Left to the system default when unset.
+    pub mtu_discovery: Option<MtuDiscovery>,
+    /// Interface index a link-local IPv6 destination is scoped to, or 0.
+    pub scope_id: u32,
+    /// IPv6 flow label, or 0 to let the kernel choose.
+    pub flow_label: u32,
+    /// How long the probe waits for an answer, for transports that wait on
+    /// one themselves.
+    pub timeout: Duration,
+}
+
+/// The time an echo request actually left, reported by the kernel after it was sent.
+#[derive(Debug, Clone)]
+pub struct SendTimestamp {
+    pub id: u16,
+    pub seq_num: u16,
+    pub sent_at: Instant,
+    pub timestamp: TimestampSource,
+}
+
+/// Something a transport read that concerns one of our echo requests.
+#[derive(Debug, Clone)]
+pub enum Received {
+    Message(Incoming),
+    SendTimestamp(SendTimestamp),
+    /// The probe can't be answered, because its transport failed.
+    Failed {
+        id: u16,
+        seq_num: u16,
+        error: Arc<io::Error>,
+    },
+}
+
+impl Received {
+    /// Identifier and sequence number of the echo request this is about.
+    pub fn key(&self) -> (u16, u16) {
+        match self {
+            Received::Message(incoming) => (incoming.id, incoming.seq_num),
+            Received::SendTimestamp(sent) => (sent.id, sent.seq_num),
+            Received::Failed { id, seq_num, .. } => (*id, *seq_num),
+        }
+    }
+}
+
+/// Carries echo requests to the network and brings back what answers them.
+///
+/// `IcmpSocket` is the real implementation; `SimulatedNetwork` answers from memory.
+pub trait IcmpTransport: Debug + Send + Sync {
+    /// Whether packets sent through this transport must start with our own IP header.
+    fn header_included(&self) -> bool;
+
+    /// The echo identifier this transport puts on every request, overriding ours.
+    fn identifier(&self) -> Option<u16> {
+        None
+    }
+
+    /// The local address this transport would send to `destination` from, if it knows.
+    fn source_for(&self, _destination: IpAddr) -> Option<IpAddr> {
+        None
+    }
+
+    /// The MTU of the route to `destination`, if it is known. Transports that
+    /// carry our IP header can't rely on the kernel to fragment bigger packets.
+    fn mtu_for(&self, _destination: IpAddr) -> Option<usize> {
+        None
+    }
+
+    /// Sends a serialized packet. `options` must be honored by transports that
+    /// build the IP header themselves.
+    fn send_to<'a>(
+        &'a self,
+        packet: &'a [u8],
+        destination: IpAddr,
+        options: SendOptions,
+    ) -> BoxFuture<'a, io::Result<usize>>;
+
+    /// Waits for the next message or send timestamp about an echo request.
+    /// Returns `None` for datagrams that don't concern one.
+    fn recv(&self) -> BoxFuture<'_, io::Result<Option<Received>>>;
+}
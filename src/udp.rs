@@ -0,0 +1,509 @@
+//! UDP probes, for networks that filter ICMP echo. Pingers still build echo
+//! requests; this transport sends each one as a UDP datagram to a port that is
+//! unlikely to be open, and hands back the ICMP errors that answer it, matched
+//! to the probe by the UDP header they quote. A port that turns out to be open
+//! answers with a datagram of its own instead.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6},
+    os::fd::AsRawFd,
+    sync::Mutex,
+};
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{io::unix::AsyncFd, sync::Mutex as AsyncMutex, time::Instant};
+
+use crate::{
+    error::SocketError,
+    icmp::{self, ICMPHeader},
+    ip::BindTarget,
+    socket::{self, ReceivedMsg},
+    transport::{BoxFuture, IcmpTransport, Incoming, Received, SendOptions, TimestampSource},
+};
+
+/// First destination port of probes sent to high ports, as in traceroute.
+pub const BASE_PORT: u16 = 33434;
+
+/// A UDP socket for one address family that carries echo requests as UDP
+/// datagrams. The sequence number goes in the first two bytes of the payload,
+/// and in the destination port when probing high ports; the local port stands
+/// in for the echo identifier.
+///
+/// ICMP errors are read from a raw ICMP socket when we may open one, and
+/// otherwise from the UDP socket's error queue, which only Linux has.
+#[derive(Debug)]
+pub struct UdpProbeSocket {
+    socket: AsyncFd<Socket>,
+    // raw ICMP socket that sees every error, ours among them
+    errors: Option<AsyncFd<Socket>>,
+    is_ipv6: bool,
+    // destination port of every probe, or None for high ports
+    port: Option<u16>,
+    identifier: u16,
+    // options last set on the socket
+    options: Mutex<Option<SendOptions>>,
+    // the probe last sent to each destination port, and when, for errors that
+    // quote too little of a probe to carry its sequence number and for datagrams
+    // an open port sends back
+    last_sent: Mutex<HashMap<u16, (u16, Instant)>>,
+    // what the raw socket's datagrams are read into, kept between receives
+    buffer: AsyncMutex<Vec<u8>>,
+}
+
+impl UdpProbeSocket {
+    /// Opens a UDP socket, bound to `bind` if given, that sends probes to
+    /// `port`, or to high ports when `None`.
+    pub fn open(
+        is_ipv6: bool,
+        port: Option<u16>,
+        bind: Option<&BindTarget>,
+    ) -> Result<UdpProbeSocket, SocketError> {
+        UdpProbeSocket::new(is_ipv6, port, bind)
+            .map_err(|e| SocketError::new(format!("Failed to open UDP socket: {}", e)))
+    }
+
+    fn new(
+        is_ipv6: bool,
+        port: Option<u16>,
+        bind: Option<&BindTarget>,
+    ) -> io::Result<UdpProbeSocket> {
+        let (domain, icmp_protocol, unspecified) = if is_ipv6 {
+            (
+                Domain::IPV6,
+                Protocol::ICMPV6,
+                IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            )
+        } else {
+            (
+                Domain::IPV4,
+                Protocol::ICMPV4,
+                IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            )
+        };
+        let udp = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
+        match bind {
+            Some(target @ BindTarget::Address(address)) if address.is_ipv6() == is_ipv6 => {
+                socket::bind_socket(&udp, target, is_ipv6)?
+            }
+            Some(target) => {
+                socket::bind_socket(&udp, target, is_ipv6)?;
+                udp.bind(&SocketAddr::new(unspecified, 0).into())?
+            }
+            None => udp.bind(&SocketAddr::new(unspecified, 0).into())?,
+        }
+        let identifier = udp
+            .local_addr()?
+            .as_socket()
+            .map(|addr| addr.port())
+            .unwrap_or_default();
+
+        let errors = match Socket::new(domain, Type::RAW, Some(icmp_protocol)) {
+            Ok(errors) => {
+                errors.set_nonblocking(true)?;
+                socket::enable_timestamps(errors.as_raw_fd());
+                socket::enable_header_fields(errors.as_raw_fd(), is_ipv6);
+                Some(AsyncFd::new(errors)?)
+            }
+            Err(e) if socket::is_permission_denied(&e) => None,
+            Err(e) => return Err(e),
+        };
+        if errors.is_none() {
+            // without a raw socket, errors only arrive on the socket error queue
+            #[cfg(target_os = "linux")]
+            {
+                let (level, name) = if is_ipv6 {
+                    (libc::SOL_IPV6, libc::IPV6_RECVERR)
+                } else {
+                    (libc::SOL_IP, libc::IP_RECVERR)
+                };
+                socket::set_int_option(udp.as_raw_fd(), level, name, 1)?;
+            }
+            #[cfg(not(target_os = "linux"))]
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "reading the ICMP errors that answer UDP probes needs root or CAP_NET_RAW",
+            ));
+        }
+        udp.set_nonblocking(true)?;
+
+        Ok(UdpProbeSocket {
+            socket: AsyncFd::new(udp)?,
+            errors,
+            is_ipv6,
+            port,
+            identifier,
+            options: Mutex::new(None),
+            last_sent: Mutex::new(HashMap::new()),
+            buffer: AsyncMutex::new(socket::receive_buffer()),
+        })
+    }
+
+    /// Sends the echo request `packet` as a UDP datagram with the same payload,
+    /// its first two bytes replaced by the sequence number.
+    async fn send_probe(
+        &self,
+        packet: &[u8],
+        destination: IpAddr,
+        options: SendOptions,
+    ) -> io::Result<usize> {
+        let request = ICMPHeader::deserialize(packet)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let seq = request.seq_num;
+        let mut payload = packet[8..].to_vec();
+        if payload.len() < 2 {
+            payload.resize(2, 0);
+        }
+        payload[..2].copy_from_slice(&seq.to_be_bytes());
+        let port = self
+            .port
+            .unwrap_or(BASE_PORT + seq % (u16::MAX - BASE_PORT + 1));
+        let sockaddr = match destination {
+            IpAddr::V6(destination) => {
+                SocketAddr::V6(SocketAddrV6::new(destination, port, 0, options.scope_id))
+            }
+            IpAddr::V4(_) => SocketAddr::new(destination, port),
+        }
+        .into();
+        loop {
+            let mut guard = self.socket.writable().await?;
+            let sent = guard.try_io(|udp| {
+                let udp = udp.get_ref();
+                let mut current = self.options.lock().unwrap();
+                if *current != Some(options) {
+                    socket::apply_options(udp, self.is_ipv6, options, *current)?;
+                    *current = Some(options);
+                }
+                // a queued ICMP error fails the next send until it is cleared
+                if self.errors.is_none() {
+                    udp.take_error()?;
+                }
+                let sent_at = Instant::now();
+                self.last_sent.lock().unwrap().insert(port, (seq, sent_at));
+                udp.send_to(&payload, &sockaddr)
+            });
+            match sent {
+                Ok(result) => return result,
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    async fn recv_error(&self) -> io::Result<Option<Received>> {
+        let error = async {
+            match self.errors {
+                Some(ref errors) => self.recv_raw(errors).await,
+                #[cfg(target_os = "linux")]
+                None => self.recv_queued().await,
+                #[cfg(not(target_os = "linux"))]
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            received = error => received,
+            received = self.recv_datagram() => received,
+        }
+    }
+
+    /// Reads a datagram a target sent back to our port. One from the port a
+    /// probe went to answers the last probe sent there, as a Port Unreachable
+    /// would if the port were closed.
+    async fn recv_datagram(&self) -> io::Result<Option<Received>> {
+        // only the length matters, which MSG_TRUNC has Linux report in full
+        #[cfg(target_os = "linux")]
+        const FLAGS: libc::c_int = libc::MSG_TRUNC;
+        #[cfg(not(target_os = "linux"))]
+        const FLAGS: libc::c_int = 0;
+
+        let mut data = [0u8; 1024];
+        let message = loop {
+            let mut guard = self.socket.readable().await?;
+            let received =
+                guard.try_io(|udp| socket::recv_msg(udp.get_ref().as_raw_fd(), &mut data, FLAGS));
+            match received {
+                Ok(Ok(message)) => break message,
+                // a pending ICMP error, which is read from the error queue
+                Ok(Err(_)) => return Ok(None),
+                Err(_would_block) => continue,
+            }
+        };
+        let Some(source) = message.source else {
+            return Ok(None);
+        };
+        let received_at = Instant::now();
+        let Some(seq_num) = self.sequence(message.port, &[], received_at) else {
+            return Ok(None);
+        };
+        Ok(Some(Received::Message(Incoming {
+            source,
+            bytes: Some(message.len),
+            msg_type: match source {
+                IpAddr::V4(_) => icmp::ICMPV4_ECHO_REPLY,
+                IpAddr::V6(_) => icmp::ICMPV6_ECHO_REPLY,
+            },
+            code: 0,
+            id: self.identifier,
+            seq_num,
+            ttl: None,
+            tos: None,
+            mtu: None,
+            received_at,
+            timestamp: TimestampSource::Userspace,
+        })))
+    }
+
+    /// Reads an ICMP error from the raw socket, which also sees errors for other
+    /// sockets; those are left for them.
+    async fn recv_raw(&self, errors: &AsyncFd<Socket>) -> io::Result<Option<Received>> {
+        let mut data = self.buffer.lock().await;
+        let message = loop {
+            let mut guard = errors.readable().await?;
+            match guard
+                .try_io(|errors| socket::recv_msg(errors.get_ref().as_raw_fd(), &mut data, 0))
+            {
+                Ok(result) => break result?,
+                Err(_would_block) => continue,
+            }
+        };
+        let Some(source) = message.source else {
+            return Ok(None);
+        };
+        let mut received = &data[..message.len.min(data.len())];
+        let mut ttl = message.hop_limit();
+        let mut tos = message.tos();
+        // raw IPv4 sockets keep the IP header, on every platform
+        if !self.is_ipv6 && received.first().map(|b| b >> 4) == Some(4) {
+            let ihl = (received[0] & 0x0F) as usize * 4;
+            ttl = received.get(8).copied();
+            tos = received.get(1).copied();
+            received = received.get(ihl..).unwrap_or_default();
+            // raw sockets see ICMP before the kernel validates it
+            if icmp::internet_checksum(received) != 0 {
+                return Ok(None);
+            }
+        }
+        let (received_at, timestamp) = received_time(&message);
+        let Some(mut incoming) = parse_udp_error(received, source, |port, payload| {
+            self.sequence(port, payload, received_at)
+        }) else {
+            return Ok(None);
+        };
+        if incoming.id != self.identifier {
+            return Ok(None);
+        }
+        incoming.bytes = Some(message.len);
+        incoming.ttl = ttl;
+        incoming.tos = tos;
+        incoming.received_at = received_at;
+        incoming.timestamp = timestamp;
+        Ok(Some(Received::Message(incoming)))
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn recv_queued(&self) -> io::Result<Option<Received>> {
+        use tokio::io::Interest;
+
+        loop {
+            let mut guard = self.socket.ready(Interest::ERROR).await?;
+            match guard.try_io(|_| self.read_queued()) {
+                Ok(result) => return result,
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    /// Reads an ICMP error from the error queue. The kernel gives the datagram's
+    /// destination and port and, as the message, whatever of its payload the
+    /// error quoted.
+    #[cfg(target_os = "linux")]
+    fn read_queued(&self) -> io::Result<Option<Received>> {
+        let mut data = [0u8; 1024];
+        let message = socket::recv_msg(
+            self.socket.get_ref().as_raw_fd(),
+            &mut data,
+            libc::MSG_ERRQUEUE,
+        )?;
+        let (Some(error), Some(destination)) = (message.extended_error(), message.source) else {
+            return Ok(None);
+        };
+        if error.origin != libc::SO_EE_ORIGIN_ICMP && error.origin != libc::SO_EE_ORIGIN_ICMP6 {
+            return Ok(None);
+        }
+        let payload = &data[..message.len.min(data.len())];
+        let (received_at, timestamp) = received_time(&message);
+        let Some(seq_num) = self.sequence(message.port, payload, received_at) else {
+            return Ok(None);
+        };
+        let source = error.offender.unwrap_or(match destination {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        });
+        Ok(Some(Received::Message(Incoming {
+            source,
+            bytes: Some(payload.len()),
+            msg_type: answer_type(error.msg_type, error.code, source, destination),
+            code: error.code,
+            id: self.identifier,
+            seq_num,
+            ttl: None,
+            tos: None,
+            mtu: socket::advertised_mtu(error.msg_type, error.code, error.info),
+            received_at,
+            timestamp,
+        })))
+    }
+
+    /// The sequence number of the probe to `destination_port` that an error
+    /// quoting `payload` answers: from the payload if enough of it is quoted,
+    /// or else the last probe sent to that port. An error received before that
+    /// probe left answers an earlier one, which went to the same high port
+    /// before sequence numbers wrapped past their range, or is one we can't
+    /// place among probes to a fixed port.
+    fn sequence(&self, destination_port: u16, payload: &[u8], received_at: Instant) -> Option<u16> {
+        if let Some(seq) = payload.get(..2) {
+            return Some(u16::from_be_bytes([seq[0], seq[1]]));
+        }
+        let last_sent = self.last_sent.lock().unwrap();
+        let &(seq, sent_at) = last_sent.get(&destination_port)?;
+        (received_at >= sent_at).then_some(seq)
+    }
+}
+
+impl IcmpTransport for UdpProbeSocket {
+    fn header_included(&self) -> bool {
+        false
+    }
+
+    /// The local port, which every probe sent through this socket comes from.
+    fn identifier(&self) -> Option<u16> {
+        Some(self.identifier)
+    }
+
+    fn send_to<'a>(
+        &'a self,
+        packet: &'a [u8],
+        destination: IpAddr,
+        options: SendOptions,
+    ) -> BoxFuture<'a, io::Result<usize>> {
+        Box::pin(self.send_probe(packet, destination, options))
+    }
+
+    fn recv(&self) -> BoxFuture<'_, io::Result<Option<Received>>> {
+        Box::pin(self.recv_error())
+    }
+}
+
+/// Parses an ICMP or ICMPv6 error, starting at its ICMP header, that quotes a
+/// UDP probe. The identifier is the probe's source port; `sequence` finds the
+/// sequence number from the probe's destination port and quoted payload.
+pub fn parse_udp_error(
+    data: &[u8],
+    source: IpAddr,
+    sequence: impl FnOnce(u16, &[u8]) -> Option<u16>,
+) -> Option<Incoming> {
+    let header = ICMPHeader::deserialize(data).ok()?;
+    let (quoted, mtu_field) = match source {
+        IpAddr::V4(_) if icmp::is_icmpv4_error(header.msg_type) => {
+            (icmp::quoted_udp_v4(data)?, header.seq_num as u32)
+        }
+        IpAddr::V6(_) if icmp::is_icmpv6_error(header.msg_type) => (
+            icmp::quoted_udp_v6(data)?,
+            (header.id as u32) << 16 | header.seq_num as u32,
+        ),
+        _ => return None,
+    };
+    Some(Incoming {
+        source,
+        bytes: Some(data.len()),
+        msg_type: answer_type(header.msg_type, header.code, source, quoted.destination),
+        code: header.code,
+        id: quoted.source_port,
+        seq_num: sequence(quoted.destination_port, quoted.payload)?,
+        ttl: None,
+        tos: None,
+        mtu: socket::advertised_mtu(header.msg_type, header.code, mtu_field),
+        received_at: Instant::now(),
+        timestamp: TimestampSource::Userspace,
+    })
+}
+
+/// A Port Unreachable from the destination itself is how it answers a UDP
+/// probe, so it is passed on as the echo reply it stands in for.
+fn answer_type(msg_type: u8, code: u8, from: IpAddr, destination: IpAddr) -> u8 {
+    match (from, msg_type, code) {
+        (IpAddr::V4(_), icmp::ICMPV4_DESTINATION_UNREACHABLE, 3) if from == destination => {
+            icmp::ICMPV4_ECHO_REPLY
+        }
+        (IpAddr::V6(_), icmp::ICMPV6_DESTINATION_UNREACHABLE, 4) if from == destination => {
+            icmp::ICMPV6_ECHO_REPLY
+        }
+        _ => msg_type,
+    }
+}
+
+fn received_time(message: &ReceivedMsg) -> (Instant, TimestampSource) {
+    message
+        .timestamp()
+        .unwrap_or_else(|| (Instant::now(), TimestampSource::Userspace))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn port_unreachable(udp: [u8; 8], payload: &[u8]) -> Vec<u8> {
+        let mut data = vec![icmp::ICMPV4_DESTINATION_UNREACHABLE, 3, 0, 0, 0, 0, 0, 0];
+        data.extend_from_slice(&[
+            0x45, 0, 0, 40, 0, 0, 0, 0, 1, 17, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2,
+        ]);
+        data.extend_from_slice(&udp);
+        data.extend_from_slice(payload);
+        data
+    }
+
+    #[test]
+    fn it_matches_errors_to_the_quoted_udp_probe() {
+        let destination = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let router = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 9));
+        // from port 0x1234 to 33434 + 7, quoting the sequence number in the payload
+        let quoted = [0x12, 0x34, 0x82, 0xA1, 0, 20, 0, 0];
+        let data = port_unreachable(quoted, &[0, 7, 0xAA, 0xAA]);
+
+        let from_payload = |_, payload: &[u8]| Some(u16::from_be_bytes([payload[0], payload[1]]));
+
+        let reply = parse_udp_error(&data, destination, from_payload).unwrap();
+        assert_eq!((reply.id, reply.seq_num), (0x1234, 7));
+        assert!(reply.is_echo_reply());
+        let error = parse_udp_error(&data, router, from_payload).unwrap();
+        assert_eq!(error.msg_type, icmp::ICMPV4_DESTINATION_UNREACHABLE);
+        let short = port_unreachable(quoted, &[]);
+        let by_port = parse_udp_error(&short, router, |port, _| Some(port - BASE_PORT));
+        assert_eq!(by_port.unwrap().seq_num, 7);
+    }
+
+    #[tokio::test]
+    async fn it_matches_errors_quoting_no_payload_to_the_last_probe_on_their_port() {
+        let probes = UdpProbeSocket::open(false, None, None).unwrap();
+        let port = BASE_PORT + 7;
+        let before = Instant::now();
+        // the second probe to this port, after sequence numbers passed the range of high ports
+        let wrapped = 7 + (u16::MAX - BASE_PORT + 1);
+        let sent_at = before + std::time::Duration::from_millis(5);
+        probes
+            .last_sent
+            .lock()
+            .unwrap()
+            .insert(port, (wrapped, sent_at));
+
+        assert_eq!(probes.sequence(port, &[], sent_at), Some(wrapped));
+        assert_eq!(probes.sequence(port, &[], before), None);
+        assert_eq!(probes.sequence(port + 1, &[], sent_at), None);
+        assert_eq!(probes.sequence(port, &[0, 9], before), Some(9));
+
+        let fixed = UdpProbeSocket::open(false, Some(53), None).unwrap();
+        assert_eq!(fixed.sequence(53, &[], sent_at), None);
+        fixed.last_sent.lock().unwrap().insert(53, (4, sent_at));
+        assert_eq!(fixed.sequence(53, &[], sent_at), Some(4));
+    }
+}
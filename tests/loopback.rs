@@ -0,0 +1,142 @@
+//! Probes over real sockets to this machine, for transports the simulated
+//! network doesn't model.
+
+use std::{
+    net::{TcpListener, UdpSocket},
+    sync::Arc,
+};
+
+use clap::Parser;
+use ring::{app, cli::CliArgs, session::Session, stats::PingStatistics, tcp::TcpProbeSocket};
+
+async fn run_cli(args: &[&str]) -> (PingStatistics, String) {
+    let args = CliArgs::parse_from(std::iter::once("ring").chain(args.iter().copied()));
+    let session = Session::new().probing(args.protocol());
+    run_session(args, session).await
+}
+
+async fn run_session(args: CliArgs, session: Session) -> (PingStatistics, String) {
+    let session = Arc::new(session);
+    let mut out = Vec::new();
+    let stats = app::run(args, session, &mut out).await.unwrap();
+    (stats, String::from_utf8(out).unwrap())
+}
+
+/// A TCP port on loopback that nothing listens on.
+fn closed_tcp_port() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().port()
+}
+
+/// A UDP port on loopback that nothing listens on.
+fn closed_udp_port() -> u16 {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.local_addr().unwrap().port()
+}
+
+#[tokio::test]
+async fn it_times_udp_probes_by_the_port_unreachable_they_draw() {
+    let port = format!("--udp={}", closed_udp_port());
+    let (stats, out) =
+        run_cli(&["-n", "-c", "3", "-i", "20", "-t", "200", &port, "127.0.0.1"]).await;
+
+    assert_eq!(stats.transmitted, 3);
+    assert_eq!(stats.received, 3);
+    assert!(stats.max_rtt().unwrap().as_millis() < 100);
+    assert!(out.starts_with(&format!(
+        "Ringing 127.0.0.1 from 127.0.0.1 (lo) with UDP to port {}\n",
+        &port[6..]
+    )));
+    assert!(out.contains("from 127.0.0.1: icmp_seq=2"));
+
+    let (stats, _) = run_cli(&[
+        "-n",
+        "-c",
+        "3",
+        "-i",
+        "20",
+        "-t",
+        "200",
+        "--udp",
+        "127.0.0.1",
+    ])
+    .await;
+    assert_eq!(stats.received, 3);
+}
+
+#[tokio::test]
+async fn it_times_udp_probes_to_an_open_port_by_the_datagram_it_sends_back() {
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let port = format!("--udp={}", server.local_addr().unwrap().port());
+    std::thread::spawn(move || {
+        let mut buffer = [0u8; 512];
+        while let Ok((_, from)) = server.recv_from(&mut buffer) {
+            let _ = server.send_to(b"answer", from);
+        }
+    });
+    let (stats, out) =
+        run_cli(&["-n", "-c", "3", "-i", "20", "-t", "200", &port, "127.0.0.1"]).await;
+
+    assert_eq!(stats.received, 3);
+    assert!(out.contains("Received 6 bytes from 127.0.0.1: icmp_seq=2"));
+}
+
+#[tokio::test]
+async fn it_counts_connects_accepted_and_refused_as_answers() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    for port in [listener.local_addr().unwrap().port(), closed_tcp_port()] {
+        let port = port.to_string();
+        let args = CliArgs::parse_from([
+            "ring",
+            "-n",
+            "-c",
+            "3",
+            "-i",
+            "20",
+            "-t",
+            "500",
+            "--tcp",
+            &port,
+            "127.0.0.1",
+        ]);
+        let socket = TcpProbeSocket::connecting(false, port.parse().unwrap(), None);
+        let session = Session::with_transport(Arc::new(socket)).probing(args.protocol());
+        let (stats, out) = run_session(args, session).await;
+
+        assert_eq!(stats.transmitted, 3);
+        assert_eq!(stats.received, 3);
+        assert_eq!(stats.errors, 0);
+        assert!(stats.max_rtt().unwrap().as_millis() < 400);
+        assert!(out.contains("Answer from 127.0.0.1: icmp_seq=2"));
+        assert!(!out.contains("bytes from"));
+    }
+}
+
+#[tokio::test]
+#[ignore = "sends raw SYNs, which needs root or CAP_NET_RAW"]
+async fn it_times_syns_by_the_segment_that_answers() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    for port in [listener.local_addr().unwrap().port(), closed_tcp_port()] {
+        let port = port.to_string();
+        let (stats, out) = run_cli(&[
+            "-n",
+            "-c",
+            "3",
+            "-i",
+            "20",
+            "-t",
+            "500",
+            "--tcp",
+            &port,
+            "127.0.0.1",
+        ])
+        .await;
+
+        assert_eq!(stats.transmitted, 3);
+        assert_eq!(stats.received, 3);
+        assert!(stats.max_rtt().unwrap().as_millis() < 400);
+        // the SYN-ACK or RST itself, which connect() never shows us
+        assert!(out.contains(" bytes from 127.0.0.1: icmp_seq=2"));
+        assert!(!out.contains("Answer from"));
+    }
+}
@@ -0,0 +1,809 @@
+use std::{io, net::IpAddr, sync::Arc, time::Duration};
+
+use clap::Parser;
+use ring::{
+    app,
+    cli::CliArgs,
+    icmp,
+    ip::Resolve,
+    pinger::{PingConfig, PingEvent, PingReply, Pinger},
+    session::Session,
+    simulate::{Latency, SimulatedError, SimulatedHost, SimulatedMtu, SimulatedNetwork},
+    stats::PingStatistics,
+};
+use tokio_stream::StreamExt;
+
+const TARGET: &str = "198.51.100.7";
+
+fn session_with(host: SimulatedHost) -> Arc<Session> {
+    let network = SimulatedNetwork::new(7);
+    network.add_host(TARGET.parse().unwrap(), host);
+    network.add_host("2001:db8::7".parse().unwrap(), SimulatedHost::default());
+    Arc::new(Session::with_transport(Arc::new(network)))
+}
+
+async fn run_cli(args: &[&str], session: Arc<Session>) -> (PingStatistics, String) {
+    let args = CliArgs::parse_from(std::iter::once("ring").chain(args.iter().copied()));
+    let mut out = Vec::new();
+    let stats = app::run(args, session, &mut out).await.unwrap();
+    (stats, String::from_utf8(out).unwrap())
+}
+
+#[tokio::test(start_paused = true)]
+async fn it_pings_a_healthy_host() {
+    let (stats, out) = run_cli(&["-c", "3", TARGET], session_with(SimulatedHost::default())).await;
+
+    assert_eq!(stats.transmitted, 3);
+    assert_eq!(stats.received, 3);
+    assert_eq!(stats.avg_rtt(), Some(Duration::from_millis(10)));
+    assert!(out.starts_with("Ringing 198.51.100.7 from 192.0.2.1\n"));
+    assert!(out.contains("Received 60 bytes from 198.51.100.7: icmp_seq=2 ttl=64 time=10 ms\n"));
+    assert!(out.contains("Timestamps: userspace send, userspace receive\n"));
+    assert!(out.ends_with("Success: 3 Failure: 0 - Avg Success Time: 10ms\n"));
+}
+
+#[tokio::test(start_paused = true)]
+async fn it_names_replies_once_the_name_arrives_without_holding_them_up() {
+    let network = Arc::new(SimulatedNetwork::new(7));
+    network.add_host(TARGET.parse().unwrap(), SimulatedHost::default());
+    network.add_name("target.example", vec![TARGET.parse().unwrap()]);
+    let session = Arc::new(Session::with_transport(network.clone()).resolving_with(network));
+    let (_, out) = run_cli(&["-c", "2", TARGET], session).await;
+
+    assert!(out.contains("Received 60 bytes from 198.51.100.7: icmp_seq=0 "));
+    assert!(out.contains("Received 60 bytes from target.example (198.51.100.7): icmp_seq=1 "));
+}
+
+#[tokio::test(start_paused = true)]
+async fn it_sends_from_the_address_given_with_i() {
+    let (stats, out) = run_cli(
+        &["-c", "1", "-I", "127.0.0.1", TARGET],
+        session_with(SimulatedHost::default()),
+    )
+    .await;
+
+    assert_eq!(stats.received, 1);
+    assert!(out.starts_with("Ringing 198.51.100.7 from 127.0.0.1 (lo"));
+}
+
+#[tokio::test(start_paused = true)]
+async fn it_pings_over_ipv6() {
+    let (stats, out) = run_cli(
+        &["-c", "2", "2001:db8::7"],
+        session_with(SimulatedHost::default()),
+    )
+    .await;
+
+    assert_eq!(stats.received, 2);
+    assert!(out.contains("Received 40 bytes from 2001:db8::7: icmp_seq=1 ttl=64 time=10 ms\n"));
+}
+
+#[tokio::test(start_paused = true)]
+async fn it_pings_scoped_link_local_addresses() {
+    let network = SimulatedNetwork::new(7);
+    network.add_host("fe80::7".parse().unwrap(), SimulatedHost::default());
+    let session = Arc::new(Session::with_transport(Arc::new(network)));
+    let (stats, out) = run_cli(&["-c", "1", "fe80::7%1"], session.clone()).await;
+
+    assert_eq!(stats.received, 1);
+    assert!(out.starts_with("Ringing fe80::7%"));
+
+    let args = CliArgs::parse_from(["ring", "fe80::7"]);
+    let error = app::run(args, session, &mut Vec::new()).await.unwrap_err();
+    assert!(error.to_string().contains("needs a zone"));
+}
+
+#[tokio::test(start_paused = true)]
+async fn it_pings_several_hosts_at_once() {
+    let (stats, out) = run_cli(
+        &["-n", "-c", "2", TARGET, "2001:db8::7", "198.51.100.9"],
+        session_with(SimulatedHost::default()),
+    )
+    .await;
+
+    assert_eq!(stats.transmitted, 6);
+    assert_eq!(stats.received, 4);
+    assert!(out.contains("Received 40 bytes from 2001:db8::7: icmp_seq=1 ttl=64 time=10 ms\n"));
+    assert!(out.contains("--- 198.51.100.7 ---\nEstimated hops: 0\nTimestamps: userspace send, userspace receive\nSuccess: 2 Failure: 0"));
+    assert!(out.contains("--- 198.51.100.9 ---\nSuccess: 0 Failure: 2"));
+    assert!(
+        out.ends_with("--- all 3 targets ---\nSuccess: 4 Failure: 2 - Avg Success Time: 10ms\n")
+    );
+}
+
+/// A session on a network where `dual.example` has an IPv6 and an IPv4 address,
+/// in that order, and only the IPv6 one answers.
+fn dual_stack_session() -> Arc<Session> {
+    let network = Arc::new(SimulatedNetwork::new(7));
+    network.add_host("2001:db8::7".parse().unwrap(), SimulatedHost::default());
+    network.add_host(
+        TARGET.parse().unwrap(),
+        SimulatedHost {
+            loss: 1.0,
+            ..SimulatedHost::default()
+        },
+    );
+    network.add_name(
+        "dual.example",
+        vec!["2001:db8::7".parse().unwrap(), TARGET.parse().unwrap()],
+    );
+    network.add_name("v6.example", vec!["2001:db8::7".parse().unwrap()]);
+    Arc::new(Session::with_transport(network.clone()).resolving_with(network))
+}
+
+#[tokio::test(start_paused = true)]
+async fn it_pings_the_address_of_the_requested_family() {
+    let (_, out) = run_cli(&["-n", "-c", "1", "dual.example"], dual_stack_session()).await;
+    assert!(out.starts_with("Ringing 2001:db8::7 from"));
+
+    let (stats, out) = run_cli(
+        &["-n", "-c", "1", "-4", "dual.example"],
+        dual_stack_session(),
+    )
+    .await;
+    assert!(out.starts_with("Ringing 198.51.100.7 from"));
+    assert_eq!(stats.received, 0);
+
+    let (stats, out) = run_cli(
+        &["-n", "-c", "1", "-6", "dual.example"],
+        dual_stack_session(),
+    )
+    .await;
+    assert!(out.starts_with("Ringing 2001:db8::7 from"));
+    assert_eq!(stats.received, 1);
+
+    let args = CliArgs::parse_from(["ring", "-4", "v6.example"]);
+    let error = app::run(args, dual_stack_session(), &mut Vec::new())
+        .await
+        .unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Couldn't resolve host: v6.example has no IPv4 address"
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn it_pings_every_address_with_statistics_for_each() {
+    let (stats, out) = run_cli(
+        &["-n", "-c", "2", "--all-addresses", "dual.example"],
+        dual_stack_session(),
+    )
+    .await;
+
+    assert_eq!(stats.transmitted, 4);
+    assert_eq!(stats.received, 2);
+    assert!(out.starts_with(
+        "Ringing 2001:db8::7 from 2001:db8::1\nRinging 198.51.100.7 from 192.0.2.1\n"
+    ));
+    assert!(out.contains("--- dual.example (2001:db8::7) ---\nEstimated hops: 0\nTimestamps: userspace send, userspace receive\nSuccess: 2 Failure: 0"));
+    assert!(out.contains("--- dual.example (198.51.100.7) ---\nSuccess: 0 Failure: 2"));
+    assert!(out.contains("--- all 2 targets ---\nSuccess: 2 Failure: 2"));
+}
+
+#[tokio::test(start_paused = true)]
+async fn it_follows_a_name_to_its_new_address_with_reresolve() {
+    let network = Arc::new(SimulatedNetwork::new(7));
+    let (old, new): (IpAddr, IpAddr) = (TARGET.parse().unwrap(), "198.51.100.8".parse().unwrap());
+    network.add_host(old, SimulatedHost::default());
+    network.add_host(new, SimulatedHost::default());
+    network.add_name("moving.example", vec![old]);
+    let session =
+        Arc::new(Session::with_transport(network.clone()).resolving_with(network.clone()));
+
+    let moved = tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(1000)).await;
+        network.add_name("moving.example", vec![new]);
+    });
+    // probes go out every 300 ms, so seven have left when the name is checked at 2 s
+    let (stats, out) = run_cli(
+        &[
+            "-n",
+            "-c",
+            "10",
+            "-i",
+            "300",
+            "--reresolve",
+            "2",
+            "moving.example",
+        ],
+        session,
+    )
+    .await;
+    moved.await.unwrap();
+
+    assert_eq!(stats.transmitted, 10);
+    assert_eq!(stats.received, 10);
+    assert!(out.contains(
+        "icmp_seq=6 ttl=64 time=10 ms\n\
+         moving.example now resolves to 198.51.100.8 instead of 198.51.100.7, switching\n\
+         Ringing 198.51.100.8 from 192.0.2.1\n"
+    ));
+    assert!(out.contains("Received 60 bytes from 198.51.100.8: icmp_seq=0"));
+    assert!(out.contains("--- moving.example (198.51.100.7) ---\nEstimated hops: 0\nTimestamps: userspace send, userspace receive\nSuccess: 7 Failure: 0"));
+    assert!(out.contains("--- moving.example (198.51.100.8) ---\nEstimated hops: 0\nTimestamps: userspace send, userspace receive\nSuccess: 3 Failure: 0"));
+
+    let zero = CliArgs::try_parse_from(["ring", "--reresolve", "0", "moving.example"]);
+    assert!(zero.is_err());
+}
+
+#[tokio::test(start_paused = true)]
+async fn it_reads_hosts_and_their_overrides_from_a_file() {
+    let path = std::env::temp_dir().join(format!("ring-targets-{}.txt", std::process::id()));
+    std::fs::write(
+        &path,
+        "# lab\n198.51.100.7 label=edge interval=500\n\n2001:db8::7 size=8\nfe80::1%nosuchif\n198.51.100.9 ttl=1\n",
+    )
+    .unwrap();
+    let file = path.to_str().unwrap();
+    let (stats, out) = run_cli(
+        &["-n", "-c", "2", "-F", file],
+        session_with(SimulatedHost::default()),
+    )
+    .await;
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(stats.received, 4);
+    assert!(out.contains(&format!("{}:6: unknown setting 'ttl'", file)));
+    assert!(out.contains(&format!("{}:5: couldn't resolve fe80::1%nosuchif", file)));
+    assert!(out.contains("Received 16 bytes from 2001:db8::7: icmp_seq=0"));
+    assert!(out.contains("--- edge (198.51.100.7) ---\n"));
+    assert!(
+        out.ends_with("--- all 2 targets ---\nSuccess: 4 Failure: 0 - Avg Success Time: 10ms\n")
+    );
+}
+
+/// Takes its time to say every name is `TARGET`.
+#[derive(Debug)]
+struct SlowResolver;
+
+impl Resolve for SlowResolver {
+    fn lookup(&self, _host: &str) -> io::Result<Vec<IpAddr>> {
+        std::thread::sleep(Duration::from_millis(200));
+        Ok(vec![TARGET.parse().unwrap()])
+    }
+
+    fn reverse(&self, _address: IpAddr) -> Option<String> {
+        None
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn it_looks_every_host_up_at_once() {
+    let network = Arc::new(SimulatedNetwork::new(7));
+    network.add_host(TARGET.parse().unwrap(), SimulatedHost::default());
+    let session = Session::with_transport(network).resolving_with(Arc::new(SlowResolver));
+    let names = [
+        "a.example",
+        "b.example",
+        "c.example",
+        "d.example",
+        "e.example",
+    ];
+    let started = std::time::Instant::now();
+    let (stats, _) = run_cli(
+        &[&["-n", "-c", "1"][..], &names[..]].concat(),
+        Arc::new(session),
+    )
+    .await;
+
+    assert_eq!(stats.received, 5);
+    assert!(started.elapsed() < Duration::from_millis(800));
+}
+
+#[tokio::test(start_paused = true)]
+async fn it_traces_the_route_to_a_host() {
+    let host = SimulatedHost {
+        latency: Latency::Fixed(Duration::from_millis(30)),
+        route: vec![
+            vec!["203.0.113.1".parse().unwrap()],
+            vec!["203.0.113.2".parse().unwrap()],
+        ],
+        ..SimulatedHost::default()
+    };
+    let (stats, out) = run_cli(&["trace", "-n", TARGET], session_with(host)).await;
+
+    assert_eq!(stats.transmitted, 9);
+    assert_eq!(stats.received, 9);
+    assert_eq!(
+        out,
+        "Tracing route to 198.51.100.7 from 192.0.2.1, 30 hops max\n \
+         1  203.0.113.1  10 ms  10 ms  10 ms\n \
+         2  203.0.113.2  20 ms  20 ms  20 ms\n \
+         3  198.51.100.7  30 ms  30 ms  30 ms\n"
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn it_keeps_paris_probes_on_one_path_and_finds_every_path_with_mda() {
+    let balanced = || SimulatedHost {
+        latency: Latency::Fixed(Duration::from_millis(30)),
+        route: vec![
+            vec!["203.0.113.1".parse().unwrap()],
+            vec![
+                "203.0.113.2".parse().unwrap(),
+                "203.0.113.3".parse().unwrap(),
+            ],
+        ],
+        ..SimulatedHost::default()
+    };
+    let (_, paris) = run_cli(
+        &["trace", "-n", "--paris", "--flow", "1", TARGET],
+        session_with(balanced()),
+    )
+    .await;
+    let (stats, mda) = run_cli(&["trace", "-n", "--mda", TARGET], session_with(balanced())).await;
+
+    assert!(paris.contains("\n 2  203.0.113.3  20 ms  20 ms  20 ms\n"));
+    assert_eq!(stats.transmitted, 23);
+    assert!(mda.ends_with(
+        " 1  203.0.113.1 (6 flows)\n \
+         2  203.0.113.2 (6 flows)  203.0.113.3 (5 flows)\n \
+         3  198.51.100.7 (6 flows)\n"
+    ));
+}
+
+#[tokio::test(start_paused = true)]
+async fn it_keeps_per_hop_statistics_after_the_first_pass() {
+    let host = SimulatedHost {
+        latency: Latency::Fixed(Duration::from_millis(30)),
+        route: vec![vec!["203.0.113.1".parse().unwrap()]],
+        ..SimulatedHost::default()
+    };
+    let (stats, out) = run_cli(
+        &["trace", "-n", "--report", "-c", "2", TARGET],
+        session_with(host),
+    )
+    .await;
+
+    assert_eq!(stats.transmitted, 10);
+    assert_eq!(stats.received, 10);
+    assert!(out.ends_with(
+        "Hop  Address                                   Loss%   Snt    Last     Avg    Best    Wrst   StDev\n  \
+         1  203.0.113.1                                0.0%     5    15.0    15.0    15.0    15.0     0.0\n  \
+         2  198.51.100.7                               0.0%     5    30.0    30.0    30.0    30.0     0.0\n"
+    ));
+}
+
+#[tokio::test(start_paused = true)]
+async fn it_sweeps_a_block_for_live_hosts() {
+    let network = SimulatedNetwork::new(7);
+    network.add_host("198.51.100.5".parse().unwrap(), SimulatedHost::default());
+    let far = SimulatedHost {
+        latency: Latency::Fixed(Duration::from_millis(30)),
+        ..SimulatedHost::default()
+    };
+    network.add_host("198.51.100.2".parse().unwrap(), far);
+    let unreachable = SimulatedHost {
+        error: Some(SimulatedError {
+            from: "203.0.113.1".parse().unwrap(),
+            msg_type: icmp::ICMPV4_DESTINATION_UNREACHABLE,
+            code: 1,
+        }),
+        ..SimulatedHost::default()
+    };
+    network.add_host("198.51.100.3".parse().unwrap(), unreachable);
+    let session = Arc::new(Session::with_transport(Arc::new(network)));
+    let (stats, out) = run_cli(&["-n", "-g", "198.51.100.0/29", "--retries", "2"], session).await;
+
+    assert_eq!(stats.transmitted, 6);
+    assert_eq!(stats.received, 2);
+    assert_eq!(stats.errors, 1);
+    assert!(out.starts_with("Sweeping 6 addresses in 198.51.100.1-198.51.100.6 from 192.0.2.1\n"));
+    assert!(out.contains(
+        "198.51.100.2 is alive: time=30 ms\n\
+         198.51.100.3 is unreachable: Destination Host Unreachable from 203.0.113.1\n\
+         198.51.100.5 is alive: time=10 ms\n"
+    ));
+    assert!(out.ends_with("2 of 6 addresses alive, 1 unreachable, 12 echo requests sent\n"));
+}
+
+#[tokio::test(start_paused = true)]
+async fn it_stops_sweeping_once_receiving_breaks() {
+    let network = Arc::new(SimulatedNetwork::new(7));
+    network.add_host("198.51.100.5".parse().unwrap(), SimulatedHost::default());
+    network.fail_receive(io::Error::other("socket closed"));
+    let session = Arc::new(Session::with_transport(network));
+    let args = CliArgs::parse_from(["ring", "-n", "-g", "198.51.100.0/29"]);
+    let error = app::run(args, session, &mut Vec::new()).await.unwrap_err();
+
+    assert_eq!(error.to_string(), "Failed to receive: socket closed");
+}
+
+#[tokio::test(start_paused = true)]
+async fn it_floods_with_the_next_probe_sent_on_each_reply() {
+    let host = SimulatedHost {
+        latency: Latency::Fixed(Duration::from_millis(2)),
+        ..SimulatedHost::default()
+    };
+    let started = tokio::time::Instant::now();
+    let (stats, out) = run_cli(
+        &["-f", "-c", "5", "-t", "20", TARGET],
+        session_with(host),
+    )
+    .await;
+
+    assert_eq!(stats.received, 5);
+    // five round trips and the wait for duplicates, not five 10 ms intervals
+    assert!(started.elapsed() < Duration::from_millis(40));
+    assert_eq!(
+        out,
+        format!(
+            "Ringing 198.51.100.7 from 192.0.2.1\n{}\n\
+             Estimated hops: 0\nTimestamps: userspace send, userspace receive\n\
+             Success: 5 Failure: 0 - Avg Success Time: 2ms\n",
+            ".\x08 \x08".repeat(5)
+        )
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn it_times_out_lost_probes() {
+    let host = SimulatedHost {
+        loss: 1.0,
+        ..SimulatedHost::default()
+    };
+    let (stats, out) = run_cli(&["-c", "4", TARGET], session_with(host)).await;
+
+    assert_eq!(stats.received, 0);
+    assert_eq!(stats.loss_percent(), 100.0);
+    assert!(out.ends_with("Success: 0 Failure: 4 - Avg Success Time: N/Ams\n"));
+}
+
+#[tokio::test(start_paused = true)]
+async fn it_reports_duplicates() {
+    let host = SimulatedHost {
+        duplicate: 1.0,
+        ..SimulatedHost::default()
+    };
+    let (stats, out) = run_cli(&["-c", "2", TARGET], session_with(host)).await;
+
+    assert_eq!(stats.received, 2);
+    assert_eq!(stats.duplicates, 2);
+    assert!(out.contains("icmp_seq=0 ttl=64 time=11 ms (DUP!)\n"));
+}
+
+#[tokio::test(start_paused = true)]
+async fn it_estimates_hops_and_warns_when_the_ttl_changes() {
+    let network = Arc::new(SimulatedNetwork::new(7));
+    let host = SimulatedHost {
+        hops: 6,
+        ..SimulatedHost::default()
+    };
+    network.add_host(TARGET.parse().unwrap(), host.clone());
+    let session = Arc::new(Session::with_transport(network.clone()));
+
+    let rerouted = tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        let host = SimulatedHost { hops: 9, ..host };
+        network.add_host(TARGET.parse().unwrap(), host);
+    });
+    let (_, out) = run_cli(&["-c", "3", TARGET], session).await;
+    rerouted.await.unwrap();
+
+    assert!(out.contains("icmp_seq=0 ttl=58 time=10 ms\n"));
+    assert!(out.contains("Warning: TTL changed from 58 to 55 at icmp_seq=2"));
+    assert!(out.contains("Estimated hops: 9\n"));
+}
+
+#[tokio::test(start_paused = true)]
+async fn it_reports_remarked_replies() {
+    let host = SimulatedHost {
+        remark: Some(0),
+        ..SimulatedHost::default()
+    };
+    let (_, out) = run_cli(&["-c", "2", "-Q", "EF", TARGET], session_with(host)).await;
+
+    assert!(out.contains("icmp_seq=0 ttl=64 tos=0x00 time=10 ms\n"));
+    assert!(out.contains("Warning: replies carry DSCP CS0 instead of EF"));
+    assert_eq!(out.matches("Warning").count(), 1);
+
+    let (_, out) = run_cli(
+        &["-c", "1", "--tos", "0xb8", "2001:db8::7"],
+        session_with(SimulatedHost::default()),
+    )
+    .await;
+    assert!(out.contains("icmp_seq=0 ttl=64 tos=0xb8 time=10 ms\n"));
+    assert!(!out.contains("Warning"));
+}
+
+#[tokio::test(start_paused = true)]
+async fn it_discovers_the_path_mtu() {
+    let network = SimulatedNetwork::new(7);
+    let link = |mtu| SimulatedMtu {
+        router: "203.0.113.1".parse().unwrap(),
+        mtu,
+    };
+    let host = SimulatedHost {
+        mtu: Some(link(1400)),
+        ..SimulatedHost::default()
+    };
+    network.add_host(TARGET.parse().unwrap(), host);
+    let host = SimulatedHost {
+        mtu: Some(SimulatedMtu {
+            router: "2001:db8::1:1".parse().unwrap(),
+            mtu: 1280,
+        }),
+        ..SimulatedHost::default()
+    };
+    network.add_host("2001:db8::7".parse().unwrap(), host);
+    let session = Arc::new(Session::with_transport(Arc::new(network)));
+
+    let (_, out) = run_cli(&["--pmtu", TARGET], session.clone()).await;
+    assert!(out.contains("too big for 203.0.113.1 (mtu = 1400)\n"));
+    assert!(out.contains("1372 byte payload: reached 198.51.100.7\n"));
+    assert!(out.ends_with("Path MTU to 198.51.100.7: 1400 bytes\n"));
+
+    let (_, out) = run_cli(&["--pmtu", "2001:db8::7"], session).await;
+    assert!(out.ends_with("Path MTU to 2001:db8::7: 1280 bytes\n"));
+}
+
+#[tokio::test(start_paused = true)]
+async fn it_fragments_with_m_want_once_the_path_mtu_is_known() {
+    let host = SimulatedHost {
+        mtu: Some(SimulatedMtu {
+            router: "203.0.113.1".parse().unwrap(),
+            mtu: 1400,
+        }),
+        ..SimulatedHost::default()
+    };
+    let session = session_with(host);
+    let ping = |mode| {
+        let session = session.clone();
+        async move {
+            let args = ["-n", "-c", "3", "-s", "1400", "-M", mode, TARGET];
+            run_cli(&args, session).await
+        }
+    };
+
+    let (stats, out) = ping("want").await;
+    assert!(out.contains("From 203.0.113.1 icmp_seq=0 Fragmentation Needed (mtu = 1400)\n"));
+    assert_eq!(stats.received, 2);
+    assert!(out.contains("Received 1428 bytes from 198.51.100.7: icmp_seq=2"));
+
+    let (stats, out) = ping("do").await;
+    assert_eq!(stats.received, 0);
+    assert!(out.contains("Failed to send packet icmp_seq=2: Message too long"));
+
+    let (stats, _) = ping("probe").await;
+    assert_eq!(stats.received, 0);
+    assert_eq!(stats.errors, 3);
+}
+
+#[tokio::test]
+async fn it_refuses_payloads_too_big_for_the_ip_length() {
+    let session = session_with(SimulatedHost::default());
+    let pinger = |destination: &str, payload_size| {
+        let mut config = PingConfig::new(destination.parse().unwrap());
+        config.payload_size = payload_size;
+        Pinger::with_session(config, session.clone())
+    };
+
+    assert!(pinger(TARGET, icmp::MAX_IPV4_PAYLOAD_SIZE).is_ok());
+    assert!(pinger(TARGET, icmp::MAX_IPV4_PAYLOAD_SIZE + 1).is_err());
+    assert!(pinger("2001:db8::7", icmp::MAX_IPV6_PAYLOAD_SIZE).is_ok());
+    assert!(pinger("2001:db8::7", icmp::MAX_IPV6_PAYLOAD_SIZE + 1).is_err());
+    assert!(CliArgs::try_parse_from(["ring", "-s", "65528", TARGET]).is_err());
+    assert!(CliArgs::try_parse_from(["ring", "--fragment-size", "65516", TARGET]).is_err());
+}
+
+#[tokio::test(start_paused = true)]
+async fn it_sends_and_reassembles_fragments() {
+    let host = SimulatedHost {
+        fragment_replies: Some(256),
+        ..SimulatedHost::default()
+    };
+    let (stats, out) = run_cli(
+        &[
+            "-c",
+            "2",
+            "-s",
+            "1000",
+            "--fragment-size",
+            "100",
+            "--fragment-overlap",
+            "16",
+            TARGET,
+        ],
+        session_with(host),
+    )
+    .await;
+
+    assert_eq!(stats.received, 2);
+    assert!(out.contains("Received 1028 bytes from 198.51.100.7: icmp_seq=1 ttl=64 time=10 ms\n"));
+
+    let mut args = vec!["ring", "--fragment-size", "100", "2001:db8::7"];
+    let error = app::run(
+        CliArgs::parse_from(args.drain(..)),
+        session_with(SimulatedHost::default()),
+        &mut Vec::new(),
+    )
+    .await
+    .unwrap_err();
+    assert!(error.to_string().contains("Custom fragments need IPv4"));
+}
+
+#[tokio::test(start_paused = true)]
+async fn it_drops_corrupted_replies() {
+    let host = SimulatedHost {
+        corrupt: 1.0,
+        ..SimulatedHost::default()
+    };
+    let (stats, _) = run_cli(&["-c", "3", TARGET], session_with(host)).await;
+
+    assert_eq!(stats.received, 0);
+}
+
+#[test]
+#[should_panic(expected = "router 2001:db8::1 can't answer for 198.51.100.7")]
+fn it_refuses_routers_of_another_family() {
+    SimulatedNetwork::new(7).add_host(
+        TARGET.parse().unwrap(),
+        SimulatedHost {
+            error: Some(SimulatedError {
+                from: "2001:db8::1".parse().unwrap(),
+                msg_type: icmp::ICMPV6_DESTINATION_UNREACHABLE,
+                code: 0,
+            }),
+            corrupt: 1.0,
+            ..SimulatedHost::default()
+        },
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn it_reports_icmp_errors() {
+    let host = SimulatedHost {
+        error: Some(SimulatedError {
+            from: "203.0.113.1".parse().unwrap(),
+            msg_type: icmp::ICMPV4_DESTINATION_UNREACHABLE,
+            code: 1,
+        }),
+        ..SimulatedHost::default()
+    };
+    let (stats, out) = run_cli(&["-c", "2", TARGET], session_with(host)).await;
+
+    assert_eq!(stats.errors, 2);
+    assert_eq!(stats.received, 0);
+    assert!(out.contains("From 203.0.113.1 icmp_seq=1 Destination Host Unreachable\n"));
+}
+
+#[tokio::test(start_paused = true)]
+async fn it_matches_reordered_replies_to_their_probes() {
+    let host = SimulatedHost {
+        latency: Latency::Uniform {
+            min: Duration::from_millis(50),
+            max: Duration::from_millis(150),
+        },
+        reorder: 0.5,
+        ..SimulatedHost::default()
+    };
+    let mut config = PingConfig::new(TARGET.parse::<IpAddr>().unwrap());
+    config.count = Some(20);
+    config.interval = Duration::from_millis(20);
+    let pinger = Pinger::with_session(config, session_with(host)).unwrap();
+
+    let mut seqs = Vec::new();
+    let mut events = Box::pin(pinger.run());
+    while let Some(event) = events.next().await {
+        if let PingEvent::Reply(reply) = event {
+            assert!(reply.rtt >= Duration::from_millis(50));
+            seqs.push(reply.seq);
+        }
+    }
+
+    assert_eq!(seqs.len(), 20);
+    assert!(seqs.windows(2).any(|pair| pair[0] > pair[1]));
+    seqs.sort();
+    assert_eq!(seqs, (0..20).collect::<Vec<u16>>());
+}
+
+#[tokio::test(start_paused = true)]
+async fn it_keeps_concurrent_pingers_on_one_session_apart() {
+    let network = SimulatedNetwork::new(7);
+    let healthy: IpAddr = "198.51.100.7".parse().unwrap();
+    let lossy: IpAddr = "198.51.100.8".parse().unwrap();
+    network.add_host(healthy, SimulatedHost::default());
+    network.add_host(
+        lossy,
+        SimulatedHost {
+            loss: 1.0,
+            ..SimulatedHost::default()
+        },
+    );
+    let session = Arc::new(Session::with_transport(Arc::new(network)));
+
+    let mut streams = Vec::new();
+    for destination in [healthy, lossy] {
+        let mut config = PingConfig::new(destination);
+        config.count = Some(3);
+        // the session hands the second pinger another identifier
+        config.id = Some(4242);
+        let pinger = Pinger::with_session(config, session.clone()).unwrap();
+        streams.push(Box::pin(pinger.run()));
+    }
+    let mut stats = Vec::new();
+    for mut events in streams {
+        let mut statistics = PingStatistics::new();
+        while let Some(event) = events.next().await {
+            statistics.update(&event);
+        }
+        stats.push(statistics);
+    }
+
+    assert_eq!(stats[0].received, 3);
+    assert_eq!(stats[1].received, 0);
+    assert_eq!(stats[1].transmitted, 3);
+}
+
+#[tokio::test(start_paused = true)]
+async fn it_keeps_receiving_after_a_transient_error() {
+    let network = Arc::new(SimulatedNetwork::new(7));
+    network.add_host(TARGET.parse().unwrap(), SimulatedHost::default());
+    let session = Arc::new(Session::with_transport(network.clone()));
+    let mut config = PingConfig::new(TARGET.parse().unwrap());
+    config.count = Some(3);
+    let pinger = Pinger::with_session(config, session).unwrap();
+
+    let mut stats = PingStatistics::new();
+    let mut events = Box::pin(pinger.run());
+    while let Some(event) = events.next().await {
+        if let PingEvent::Reply(PingReply { seq: 0, .. }) = event {
+            // what a datagram socket with IP_RECVERR reads after an ICMP error
+            network.fail_receive(io::Error::from_raw_os_error(libc::EHOSTUNREACH));
+        }
+        stats.update(&event);
+    }
+
+    assert_eq!(stats.received, 3);
+    assert_eq!(stats.errors, 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn it_fails_probes_once_receiving_breaks() {
+    let network = Arc::new(SimulatedNetwork::new(7));
+    network.add_host(TARGET.parse().unwrap(), SimulatedHost::default());
+    network.fail_receive(io::Error::other("socket closed"));
+    let session = Arc::new(Session::with_transport(network));
+    let (stats, out) = run_cli(&["-c", "2", TARGET], session).await;
+
+    assert_eq!(stats.received, 0);
+    assert_eq!(stats.errors, 2);
+    assert!(out.contains("Failed to receive icmp_seq=0: socket closed\n"));
+    assert!(out.contains("Failed to receive icmp_seq=1: socket closed\n"));
+}
+
+#[tokio::test(start_paused = true)]
+async fn it_keeps_pinging_one_family_when_the_others_receiver_breaks() {
+    let ipv4 = Arc::new(SimulatedNetwork::new(7));
+    ipv4.add_host(TARGET.parse().unwrap(), SimulatedHost::default());
+    let ipv6 = Arc::new(SimulatedNetwork::new(7));
+    ipv6.add_host("2001:db8::7".parse().unwrap(), SimulatedHost::default());
+    ipv6.fail_receive(io::Error::other("socket closed"));
+    let session = Arc::new(Session::with_transports(ipv4, ipv6));
+    let (stats, out) = run_cli(&["-n", "-c", "2", TARGET, "2001:db8::7"], session).await;
+
+    assert_eq!(stats.received, 2);
+    assert_eq!(stats.errors, 2);
+    assert!(out.contains("Failed to receive icmp_seq=1: socket closed\n"));
+    assert!(out.contains("--- 198.51.100.7 ---\nEstimated hops: 0\nTimestamps: userspace send, userspace receive\nSuccess: 2 Failure: 0"));
+}
+
+#[tokio::test(start_paused = true)]
+async fn it_settles_probes_in_flight_when_finishing() {
+    let mut config = PingConfig::new(TARGET.parse::<IpAddr>().unwrap());
+    config.count = Some(5);
+    let pinger = Pinger::with_session(config, session_with(SimulatedHost::default())).unwrap();
+    let handle = pinger.handle();
+
+    let mut stats = PingStatistics::new();
+    let mut events = Box::pin(pinger.run());
+    while let Some(event) = events.next().await {
+        if let PingEvent::Sent { seq: 1, .. } = event {
+            handle.finish();
+        }
+        stats.update(&event);
+    }
+
+    assert_eq!(stats.transmitted, 2);
+    assert_eq!(stats.received, 2);
+}